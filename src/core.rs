@@ -1,3 +1,4 @@
+pub mod datetime;
 pub mod delay;
 
 pub trait Conversions {
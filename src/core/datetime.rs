@@ -0,0 +1,102 @@
+#![allow(dead_code)]
+
+const SECONDS_PER_DAY: u32 = 86400;
+// Days from 0000-03-01 to 1970-01-01 in the proleptic Gregorian calendar
+const DAYS_TO_UNIX_EPOCH: i32 = 719468;
+
+// Calendar date and 24-hour time, shared by the internal and the external RTCs
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8
+}
+
+impl DateTime {
+    pub fn new(year: u16, month: u8, day: u8, hours: u8, minutes: u8, seconds: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hours,
+            minutes,
+            seconds
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.month >= 1 && self.month <= 12
+            && self.day >= 1 && self.day <= days_in_month(self.year, self.month)
+            && self.hours < 24 && self.minutes < 60 && self.seconds < 60
+    }
+
+    // 1 is Monday and 7 is Sunday, as in the WDU field of the internal RTC
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        ((self.days_since_unix_epoch() + 3).rem_euclid(7) + 1) as u8
+    }
+
+    // Seconds since 1970-01-01 00:00:00, valid up to 2106
+    pub fn unix_timestamp(&self) -> u32 {
+        self.days_since_unix_epoch() as u32 * SECONDS_PER_DAY
+            + self.hours as u32 * 3600 + self.minutes as u32 * 60 + self.seconds as u32
+    }
+
+    pub fn from_unix_timestamp(timestamp: u32) -> DateTime {
+        let days = (timestamp / SECONDS_PER_DAY) as i32 + DAYS_TO_UNIX_EPOCH;
+        let seconds_of_day = timestamp % SECONDS_PER_DAY;
+
+        // Years start in March so the leap day is the last day of the year
+        let era = days / 146097;
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hours: (seconds_of_day / 3600) as u8,
+            minutes: (seconds_of_day / 60 % 60) as u8,
+            seconds: (seconds_of_day % 60) as u8
+        }
+    }
+
+    fn days_since_unix_epoch(&self) -> i32 {
+        let year = self.year as i32 - if self.month <= 2 { 1 } else { 0 };
+        let month = self.month as i32;
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i32 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - DAYS_TO_UNIX_EPOCH
+    }
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 => if is_leap_year(year) { 29 } else { 28 },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+// RTC registers hold the tens in the high nibble and the units in the low nibble
+pub fn bcd_to_binary(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0xF)
+}
+
+pub fn binary_to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
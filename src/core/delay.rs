@@ -1,7 +1,46 @@
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m_rt::exception;
+use crate::system::SYSCLK_FREQ;
 
 pub fn non_exact_time_delay(delay: u32){
     let mut count = 0u32;
     while count < delay {
         count+=1;
     }
-}
\ No newline at end of file
+}
+
+pub fn delay_us(us: u32){
+    cortex_m::asm::delay((SYSCLK_FREQ / 1000000) * us)
+}
+
+pub fn delay_ms(ms: u32){
+    for _ in 0..ms {
+        delay_us(1000);
+    }
+}
+
+// SysTick interrupts every millisecond and counts the ticks behind millis
+pub fn enable_millis_tick(){
+    unsafe {
+        let syst = &*cortex_m::peripheral::SYST::PTR;
+        // SYST_RVR is 24 bits wide, 170000 cycles per millisecond fit
+        syst.rvr.write(SYSCLK_FREQ / 1000 - 1);
+        syst.cvr.write(0);
+        // CLKSOURCE bit 2 selects the processor clock, TICKINT bit 1, ENABLE bit 0 in SYST_CSR
+        syst.csr.write((1 << 2) | (1 << 1) | (1 << 0));
+    }
+}
+
+// Milliseconds since enable_millis_tick, wraps after 49 days so compare with wrapping_sub
+pub fn millis() -> u32 {
+    MILLIS.load(Ordering::Relaxed)
+}
+
+#[exception]
+fn SysTick() {
+    MILLIS.fetch_add(1, Ordering::Relaxed);
+}
+
+static MILLIS: AtomicU32 = AtomicU32::new(0);
@@ -1,5 +1,12 @@
+pub mod bme280;
+pub mod eeprom_at24;
 pub mod gpio;
+pub mod imu_mpu6050;
 pub mod lcd_hd44780;
+pub mod oled_ssd1306;
+pub mod power_ina219;
+pub mod rtc_external;
+pub mod rtc_internal;
 pub mod serial;
 #[allow(non_snake_case)]
 pub mod I2C;
@@ -1,35 +1,102 @@
 #![allow(dead_code)]
 
 use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
 use stm32g4::stm32g431;
-use stm32g4::stm32g431::I2C1;
+use stm32g4::stm32g431::interrupt;
+use cortex_m::peripheral::NVIC;
 use crate::drivers::gpio;
 use crate::drivers::gpio::GPIOPORT;
 use crate::drivers::serial::Serial;
 use crate::core::Conversions;
+use crate::core::delay::delay_us;
+use crate::system::{APB1_FREQ, HSI16_FREQ, SYSCLK_FREQ};
+
+pub mod smbus;
+pub mod pmbus;
+pub mod transfer;
+pub mod hal;
+
+pub use stm32g431xx::drivers::I2C::ten_bit::{self, I2cRegisters};
 
 pub struct I2C {
+    pub instance: I2cInstance,
+    pub pins: I2cPins
+}
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum I2cInstance {
+    I2C1,
+    I2C2,
+    I2C3
 }
 
-const TYPE_I2C1_GPIO: gpio::GpioConfig = gpio::GpioConfig {
-    moder: gpio::MODER::AlternateFunction,
-    otyper: gpio::OTYPER::OpenDrain,
-    ospeedr: gpio::OSPEEDR::VeryHigh,
-    pupdr: gpio::PUPDR::PullUp,
-    alf_func_sel: Some(4)
+pub struct I2cPin {
+    pub gpio: gpio::Gpio,
+    pub alternate_function: u8
+}
+
+pub struct I2cPins {
+    pub scl: I2cPin,
+    pub sda: I2cPin
+}
+
+pub const I2C1_PB8_PB9: I2cPins = I2cPins {
+    scl: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOB, pin_number: 8 }, alternate_function: 4 },
+    sda: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOB, pin_number: 9 }, alternate_function: 4 }
+};
+
+pub const I2C1_PA15_PB7: I2cPins = I2cPins {
+    scl: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOA, pin_number: 15 }, alternate_function: 4 },
+    sda: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOB, pin_number: 7 }, alternate_function: 4 }
+};
+
+pub const I2C2_PA9_PA8: I2cPins = I2cPins {
+    scl: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOA, pin_number: 9 }, alternate_function: 4 },
+    sda: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOA, pin_number: 8 }, alternate_function: 4 }
 };
 
-const PB8_I2C1_SCL: gpio::Gpio = gpio::Gpio {
-    port: GPIOPORT::GPIOB,
-    pin_number: 8,
+pub const I2C2_PC4_PA8: I2cPins = I2cPins {
+    scl: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOC, pin_number: 4 }, alternate_function: 4 },
+    sda: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOA, pin_number: 8 }, alternate_function: 4 }
 };
 
-const PB9_I2C1_SDA: gpio::Gpio = gpio::Gpio {
-    port: GPIOPORT::GPIOB,
-    pin_number: 9,
+pub const I2C3_PC8_PC9: I2cPins = I2cPins {
+    scl: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOC, pin_number: 8 }, alternate_function: 8 },
+    sda: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOC, pin_number: 9 }, alternate_function: 8 }
+};
+
+pub const I2C3_PC8_PC11: I2cPins = I2cPins {
+    scl: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOC, pin_number: 8 }, alternate_function: 8 },
+    sda: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOC, pin_number: 11 }, alternate_function: 8 }
+};
+
+pub const I2C3_PA8_PB5: I2cPins = I2cPins {
+    scl: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOA, pin_number: 8 }, alternate_function: 2 },
+    sda: I2cPin { gpio: gpio::Gpio { port: GPIOPORT::GPIOB, pin_number: 5 }, alternate_function: 8 }
+};
+
+// Used while the bus is being recovered, SCL and SDA are driven by software
+const TYPE_I2C_RECOVERY_GPIO: gpio::GpioConfig = gpio::GpioConfig {
+    moder: gpio::MODER::GeneralPurposeOutput,
+    otyper: gpio::OTYPER::OpenDrain,
+    ospeedr: gpio::OSPEEDR::VeryHigh,
+    pupdr: gpio::PUPDR::PullUp,
+    alf_func_sel: None
 };
 
+const I2C_TIMEOUT_US: u32 = 25000;
+// A slave holding SDA low releases it after at most 9 clock pulses
+const I2C_RECOVERY_CLOCK_PULSES: u32 = 9;
+// Half period of the recovery clock, 100 kHz
+const I2C_RECOVERY_HALF_PERIOD_US: u32 = 5;
+const I2C_MAX_NBYTES: usize = ten_bit::MAX_NBYTES;
+// Addresses outside this range are reserved by the I2C specification
+const I2C_FIRST_SCAN_ADDRESS: u8 = 0x08;
+const I2C_LAST_SCAN_ADDRESS: u8 = 0x77;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum I2cError {
     TimeOut,
     PECErrorInReception,
@@ -37,9 +104,109 @@ pub enum I2cError {
     ArbitrationLost,
     BusError,
     NotAcknowledgedReceived,
+    // NACKF was set before any byte of the transfer was moved, the address itself was refused
+    AddressNotAcknowledged,
+    InvalidConfiguration,
+    InvalidLength,
+    TransferInProgress,
+}
+
+pub enum I2cSpeed {
+    Standard100k,
+    Fast400k,
+    FastPlus1M,
+    Custom(u32)
+}
+
+pub struct I2cConfig {
+    pub speed: I2cSpeed,
+    // SCL and SDA rise and fall times of the bus, they depend on the pull-ups and the bus capacitance
+    pub rise_ns: u32,
+    pub fall_ns: u32,
+    pub analog_filter: bool,
+    // Digital noise filter length in I2C kernel clock cycles (0 to 15)
+    pub digital_filter: u8
+}
+
+struct I2cModeTimings {
+    low_min_ns: u32,
+    high_min_ns: u32,
+    data_setup_min_ns: u32,
+    data_valid_max_ns: u32
+}
+
+const STANDARD_MODE_TIMINGS: I2cModeTimings = I2cModeTimings {
+    low_min_ns: 4700,
+    high_min_ns: 4000,
+    data_setup_min_ns: 250,
+    data_valid_max_ns: 3450
+};
+
+const FAST_MODE_TIMINGS: I2cModeTimings = I2cModeTimings {
+    low_min_ns: 1300,
+    high_min_ns: 600,
+    data_setup_min_ns: 100,
+    data_valid_max_ns: 900
+};
+
+const FAST_MODE_PLUS_TIMINGS: I2cModeTimings = I2cModeTimings {
+    low_min_ns: 500,
+    high_min_ns: 260,
+    data_setup_min_ns: 50,
+    data_valid_max_ns: 450
+};
+
+// Analog filter input delay
+const ANALOG_FILTER_MIN_NS: u32 = 50;
+const ANALOG_FILTER_MAX_NS: u32 = 260;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum I2cAddress {
+    SevenBit(u8),
+    TenBit(u16)
+}
+
+pub enum I2cOwnAddress {
+    SevenBit(u8),
+    TenBit(u16)
+}
+
+// Bits of the own address 2 that are not compared
+pub enum I2cAddressMask {
+    NoMask,
+    Mask1,
+    Mask2,
+    Mask3,
+    Mask4,
+    Mask5,
+    Mask6,
+    // All non reserved addresses are acknowledged
+    Mask7
+}
+
+pub struct I2cSlaveConfig {
+    pub own_address_1: I2cOwnAddress,
+    pub own_address_2: Option<(u8, I2cAddressMask)>,
+    pub clock_stretching: bool,
+    pub general_call: bool,
+    // Register map callbacks, they receive the matched address (ADDCODE) and the register pointer
+    pub on_read: fn(address: u8, register: u8) -> u8,
+    pub on_write: fn(address: u8, register: u8, value: u8)
+}
+
+struct I2cSlaveState {
+    address: u8,
+    register: u8,
+    register_received: bool,
+    on_read: fn(u8, u8) -> u8,
+    on_write: fn(u8, u8, u8),
+    // SMBus device with PEC, data bytes of the command and the ones still expected before the PEC
+    pec_data_length: fn(u8, bool) -> u8,
+    pec_remaining: u8
 }
 
 pub struct MasterConfig {
+    // 7-bit or 10-bit address, not shifted
     pub slave_address_to_send: u16,
     pub address_10_bit_mode: bool,
     pub i2c_10_bit_reading_procedure: Option<I2cHeaderOnlyReadDirection10Bit>
@@ -47,76 +214,488 @@ pub struct MasterConfig {
 
 impl I2C {
 
-    pub fn begin() {
-        Self::enable_peripheral_clock_in_rcc();
-        Self::configure_peripheral();
-        Self::enable_peripheral()
+    pub fn new(instance: I2cInstance, pins: I2cPins) -> I2C {
+        I2C {
+            instance,
+            pins
+        }
     }
 
-    pub fn begin_transmission(config: MasterConfig) {
-        Self::master_initialization_address_phase(config);
+    pub fn begin(&self, config: I2cConfig) -> Result<(), I2cError> {
+        self.enable_peripheral_clock_in_rcc();
+        self.configure_peripheral(config)?;
+        self.enable_peripheral();
+        Ok(())
     }
 
-    pub fn transmit(data: &[u8]){
+    pub fn begin_slave(&self, config: I2cConfig, slave_config: I2cSlaveConfig) -> Result<(), I2cError> {
+        self.enable_peripheral_clock_in_rcc();
+        self.configure_peripheral(config)?;
+        self.configure_own_addresses(&slave_config);
+
+        unsafe {
+            let port = self.instance.registers();
+            // NOSTRETCH bit 17 and GCEN bit 19 in I2C_CR1
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !((1 << 17) | (1 << 19)));
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() | ((!slave_config.clock_stretching).as_u32() << 17) | (slave_config.general_call.as_u32() << 19));
+            // Enable TXIE bit 1, RXIE bit 2, ADDRIE bit 3, NACKIE bit 4, STOPIE bit 5 and ERRIE bit 7 in I2C_CR1
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() | (1 << 1) | (1 << 2) | (1 << 3) | (1 << 4) | (1 << 5) | (1 << 7));
 
-        let number_of_bytes_to_transmit = data.len() as u32;
+            SLAVE_STATES[self.instance.index()].on_read = slave_config.on_read;
+            SLAVE_STATES[self.instance.index()].on_write = slave_config.on_write;
 
-        let port;
+            NVIC::unmask(self.instance.event_interrupt());
+            NVIC::unmask(self.instance.error_interrupt());
+        }
+
+        self.enable_peripheral();
+        Ok(())
+    }
+
+    fn configure_own_addresses(&self, slave_config: &I2cSlaveConfig){
         unsafe {
-             port = &*I2C1::ptr();
+            let port = self.instance.registers();
+
+            // OA1EN bit 15 in I2C_OAR1 must be cleared before changing the address
+            port.oar1.as_ptr().write(0);
+            match slave_config.own_address_1 {
+                // 7-bit address goes into OA1[7:1]
+                I2cOwnAddress::SevenBit(address) => port.oar1.as_ptr().write((address as u32 & 0x7F) << 1),
+                // 10-bit address goes into OA1[9:0] with OA1MODE bit 10
+                I2cOwnAddress::TenBit(address) => port.oar1.as_ptr().write((address as u32 & 0x3FF) | (1 << 10))
+            }
+            port.oar1.as_ptr().write(port.oar1.as_ptr().read() | (1 << 15));
+
+            // OA2EN bit 15 in I2C_OAR2 must be cleared before changing the address
+            port.oar2.as_ptr().write(0);
+            if let Some((address, mask)) = &slave_config.own_address_2 {
+                // OA2[7:1] and OA2MSK bits 10:8
+                port.oar2.as_ptr().write(((*address as u32 & 0x7F) << 1) | (mask.as_u32() << 8) | (1 << 15));
+            }
         }
+    }
+
+    pub fn begin_transmission(&self, config: MasterConfig) {
+        self.master_initialization_address_phase(config);
+    }
 
-        //sets the desired number of bytes and transfer direction
-        Self::master_set_number_of_bytes_and_transfer_direction(number_of_bytes_to_transmit);
+    pub fn transmit(&self, data: &[u8]) -> Result<(), I2cError> {
+        //sets the desired number of bytes and transfer direction, the STOP is sent by end_transmission
+        self.master_set_number_of_bytes_and_transfer_direction(data.len(), I2cTransferDirection::MasterRequestAWrite, false);
 
         //send a start condition
-        Self::start_condition();
+        self.start_condition();
+
+        self.write_data(data, false)?;
+
+        self.wait_for_transfer_complete()
+    }
+
+    // Reads after transmit use a repeated start, a 10-bit address is sent again or only its header depending on the reading procedure
+    pub fn receive(&self, buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.master_set_number_of_bytes_and_transfer_direction(buffer.len(), I2cTransferDirection::MasterRequestARead, false);
+
+        self.start_condition();
+
+        self.read_data(buffer, false)?;
+
+        self.wait_for_transfer_complete()
+    }
+
+    pub fn end_transmission(&self) -> Result<(), I2cError> {
+        self.stop_condition();
+        self.wait_for_stop_condition()
+    }
+
+    pub fn write(&self, address: u8, data: &[u8]) -> Result<(), I2cError> {
+        self.wait_while_busy()?;
+        self.master_request(address, data.len(), I2cTransferDirection::MasterRequestAWrite, true);
+
+        self.write_data(data, true)?;
+
+        self.wait_for_stop_condition()
+    }
+
+    pub fn read(&self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.wait_while_busy()?;
+        self.master_request(address, buffer.len(), I2cTransferDirection::MasterRequestARead, true);
+
+        self.read_data(buffer, true)?;
+
+        self.wait_for_stop_condition()
+    }
+
+    pub fn write_read(&self, address: u8, data: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.wait_while_busy()?;
+
+        // Write phase without AUTOEND, TC is set once every byte is sent
+        self.master_request(address, data.len(), I2cTransferDirection::MasterRequestAWrite, false);
+
+        self.write_data(data, false)?;
+
+        self.wait_for_transfer_complete()?;
+
+        // Read phase starts with a repeated start condition
+        self.master_request(address, buffer.len(), I2cTransferDirection::MasterRequestARead, true);
+
+        self.read_data(buffer, true)?;
+
+        self.wait_for_stop_condition()
+    }
+
+    pub fn write_10bit(&self, address: u16, data: &[u8]) -> Result<(), I2cError> {
+        self.wait_while_busy()?;
+        ten_bit::write(self, address, data)
+    }
+
+    // A read that does not follow a write always sends the complete 10-bit address
+    pub fn read_10bit(&self, address: u16, buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.wait_while_busy()?;
+        ten_bit::read(self, address, buffer)
+    }
 
-        Serial::println(format!("I2C1_CR1: {:b}", port.cr1.read().bits()).as_str());
-        Serial::println(format!("I2C1_CR2: {:b}", port.cr2.read().bits()).as_str());
-        Serial::println(format!("I2C1_ISR: {:b}", port.isr.read().bits()).as_str());
+    pub fn write_read_10bit(&self, address: u16, data: &[u8], buffer: &mut [u8], reading_procedure: I2cHeaderOnlyReadDirection10Bit) -> Result<(), I2cError> {
+        self.wait_while_busy()?;
+        let header_only = matches!(reading_procedure, I2cHeaderOnlyReadDirection10Bit::SevenBitsFirst);
+        ten_bit::write_read(self, address, data, buffer, header_only)
+    }
+
+    pub fn probe(&self, address: u8) -> Result<bool, I2cError> {
+        // Same probing as i2cdetect: EEPROMs and some sensors may corrupt data on a quick write, they are read instead
+        let result = if (0x30..=0x37).contains(&address) || (0x50..=0x5F).contains(&address) {
+            self.read(address, &mut [0u8; 1])
+        } else {
+            self.write(address, &[])
+        };
 
-        while port.isr.read().tc().bit_is_clear(){
-            for data_slice in data{
-                Serial::println("sending FIRST byte");
-                Self::write_into_tr_register(*data_slice);
+        match result {
+            Ok(()) => Ok(true),
+            Err(I2cError::NotAcknowledgedReceived) => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub fn scan(&self) -> Result<Vec<u8>, I2cError> {
+        let mut devices = Vec::new();
+        for address in I2C_FIRST_SCAN_ADDRESS..=I2C_LAST_SCAN_ADDRESS {
+            if self.probe(address)? {
+                devices.push(address);
             }
         }
+        Ok(devices)
+    }
 
+    pub fn scan_and_print(&self) -> Result<Vec<u8>, I2cError> {
+        let devices = self.scan()?;
+        self.print_scan_grid(&devices);
+        Ok(devices)
     }
 
-    pub fn end_transmission(){
-        Self::stop_condition()
+    pub fn print_scan_grid(&self, devices: &[u8]){
+        Serial::println("     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f");
+        for row in (0u8..0x80).step_by(16) {
+            let mut line = format!("{:02x}:", row);
+            for address in row..(row + 16) {
+                let cell = if !(I2C_FIRST_SCAN_ADDRESS..=I2C_LAST_SCAN_ADDRESS).contains(&address) {
+                    String::from("   ")
+                } else if devices.contains(&address) {
+                    format!(" {:02x}", address)
+                } else {
+                    String::from(" --")
+                };
+                line.push_str(&cell);
+            }
+            Serial::println(&line);
+        }
     }
 
-    pub fn check_error_flags(){
+    // Clocks out a slave that holds SDA low after an interrupted transfer and ends with a STOP
+    pub fn recover_bus(&self) -> Result<(), I2cError> {
+        unsafe {
+            let port = self.instance.registers();
+            // Clear PE bit 0 in I2C_CR1, the pins are released by the peripheral
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !(1 << 0));
+        }
+
+        self.pins.scl.gpio.set(true);
+        self.pins.sda.gpio.set(true);
+        self.pins.scl.gpio.configure(TYPE_I2C_RECOVERY_GPIO);
+        self.pins.sda.gpio.configure(TYPE_I2C_RECOVERY_GPIO);
+        delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+
+        let mut result = Ok(());
+
+        for _ in 0..I2C_RECOVERY_CLOCK_PULSES {
+            if self.pins.sda.gpio.get() {
+                break
+            }
+            self.pins.scl.gpio.set(false);
+            delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+            self.pins.scl.gpio.set(true);
+            // The slave may stretch the clock
+            if !self.wait_for_scl_release() {
+                result = Err(I2cError::TimeOut);
+                break
+            }
+            delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+        }
+
+        if result.is_ok() {
+            // STOP condition: SDA rises while SCL is high
+            self.pins.scl.gpio.set(false);
+            delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+            self.pins.sda.gpio.set(false);
+            delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+            self.pins.scl.gpio.set(true);
+            delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+            self.pins.sda.gpio.set(true);
+            delay_us(I2C_RECOVERY_HALF_PERIOD_US);
+
+            if !self.pins.sda.gpio.get() {
+                result = Err(I2cError::BusError);
+            }
+        }
+
+        self.configure_gpio();
+        self.enable_peripheral();
 
+        result
     }
 
-    pub fn write_into_tr_register(data_slice: u8){
+    pub fn check_error_flags(&self) -> Result<(), I2cError> {
         unsafe {
-            let port = &*I2C1::ptr();
+            let port = self.instance.registers();
+            let port_isr = port.isr.read();
+
+            let error = if port_isr.nackf().bit_is_set() {
+                // The STOP is generated automatically after a NACK
+                self.wait_for_flag(5).ok();
+                // Write NACKCF bit 4 and STOPCF bit 5 in I2C_ICR
+                port.icr.as_ptr().write((1 << 4) | (1 << 5));
+                I2cError::NotAcknowledgedReceived
+            } else if port_isr.berr().bit_is_set() {
+                // Write BERRCF bit 8 in I2C_ICR
+                port.icr.as_ptr().write(1 << 8);
+                I2cError::BusError
+            } else if port_isr.arlo().bit_is_set() {
+                // Write ARLOCF bit 9 in I2C_ICR, the interface already released the bus
+                port.icr.as_ptr().write(1 << 9);
+                I2cError::ArbitrationLost
+            } else if port_isr.ovr().bit_is_set() {
+                // Write OVRCF bit 10 in I2C_ICR
+                port.icr.as_ptr().write(1 << 10);
+                I2cError::OverrunUnderrun
+            } else if port_isr.pecerr().bit_is_set() {
+                // Write PECCF bit 11 in I2C_ICR
+                port.icr.as_ptr().write(1 << 11);
+                I2cError::PECErrorInReception
+            } else if port_isr.timeout().bit_is_set() {
+                // Write TIMOUTCF bit 12 in I2C_ICR, a STOP is sent automatically in master mode
+                port.icr.as_ptr().write(1 << 12);
+                I2cError::TimeOut
+            } else {
+                return Ok(())
+            };
 
-            while port.isr.read().txe().bit_is_clear(){}
+            // Flush I2C_TXDR by setting TXE bit 0 in I2C_ISR
+            port.isr.as_ptr().write(port.isr.as_ptr().read() | (1 << 0));
+
+            Err(error)
+        }
+    }
 
+    pub fn write_into_tr_register(&self, data_slice: u8) -> Result<(), I2cError> {
+        // Wait for TXIS bit 1 in I2C_ISR
+        self.wait_for_flag(1)?;
+        unsafe {
+            let port = self.instance.registers();
             port.txdr.as_ptr().write(data_slice as u32);
+        }
+        Ok(())
+    }
+
+    fn write_data(&self, data: &[u8], autoend: bool) -> Result<(), I2cError> {
+        self.write_chunks(data, autoend, 0)
+    }
+
+    // Trailing bytes are counted in NBYTES but handled by the hardware, like the PEC byte
+    fn write_chunks(&self, data: &[u8], autoend: bool, trailing_bytes: usize) -> Result<(), I2cError> {
+        for (index, data_slice) in data.iter().enumerate() {
+            // NBYTES holds up to 255 bytes, the next chunk is loaded when TCR is set
+            if index > 0 && index.is_multiple_of(I2C_MAX_NBYTES) {
+                self.reload_next_chunk(data.len() - index + trailing_bytes, autoend)?;
+            }
+            self.write_into_tr_register(*data_slice)?;
+        }
+        Ok(())
+    }
+
+    fn read_data(&self, buffer: &mut [u8], autoend: bool) -> Result<(), I2cError> {
+        self.read_chunks(buffer, autoend, 0)
+    }
+
+    fn read_chunks(&self, buffer: &mut [u8], autoend: bool, trailing_bytes: usize) -> Result<(), I2cError> {
+        let length = buffer.len();
+        for (index, data_slice) in buffer.iter_mut().enumerate() {
+            if index > 0 && index.is_multiple_of(I2C_MAX_NBYTES) {
+                self.reload_next_chunk(length - index + trailing_bytes, autoend)?;
+            }
+            *data_slice = self.read_from_rx_register()?;
+        }
+        Ok(())
+    }
+
+    fn reload_next_chunk(&self, remaining: usize, autoend: bool) -> Result<(), I2cError> {
+        // Wait for TCR bit 7 in I2C_ISR, SCL is stretched until NBYTES is written
+        self.wait_for_flag(7)?;
+        self.set_number_of_bytes(remaining, autoend);
+        Ok(())
+    }
+
+    fn set_number_of_bytes(&self, remaining: usize, autoend: bool){
+        self.instance.set_number_of_bytes(remaining, autoend)
+    }
 
+    fn read_from_rx_register(&self) -> Result<u8, I2cError> {
+        // Wait for RXNE bit 2 in I2C_ISR
+        self.wait_for_flag(2)?;
+        unsafe {
+            let port = self.instance.registers();
+            Ok(port.rxdr.as_ptr().read() as u8)
         }
     }
 
-    fn master_set_number_of_bytes_and_transfer_direction(number_of_bytes_to_transmit: u32){
+    fn wait_for_transfer_complete(&self) -> Result<(), I2cError> {
+        // Wait for TC bit 6 in I2C_ISR
+        self.wait_for_flag(6)
+    }
+
+    fn wait_for_stop_condition(&self) -> Result<(), I2cError> {
+        // Wait for STOPF bit 5 in I2C_ISR
+        self.wait_for_flag(5)?;
         unsafe {
-            let port = &*I2C1::ptr();
-            //set number of bytes to transmit
-            port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (number_of_bytes_to_transmit << 16));
-            //set transfer direction (request a write)
-            port.cr2.as_ptr().write(port.cr2.as_ptr().read() & !(1 << 10));
+            let port = self.instance.registers();
+            // NACKF bit 4 in I2C_ISR, a transfer without data bytes ends with the STOP sent after the NACK
+            let nack_received = (port.isr.as_ptr().read() & (1 << 4)) > 0;
+            // Write STOPCF bit 5 and NACKCF bit 4 in I2C_ICR
+            port.icr.as_ptr().write((1 << 5) | (1 << 4));
+            if nack_received {
+                return Err(I2cError::NotAcknowledgedReceived)
+            }
+        }
+        Ok(())
+    }
+
+    fn wait_while_busy(&self) -> Result<(), I2cError> {
+        unsafe {
+            let port = self.instance.registers();
+            let mut elapsed_us = 0u32;
+            // Wait for BUSY bit 15 in I2C_ISR to be cleared
+            while (port.isr.as_ptr().read() & (1 << 15)) > 0 {
+                if elapsed_us >= I2C_TIMEOUT_US {
+                    // The bus is held by a slave, the peripheral restarts with BUSY cleared once it is recovered
+                    self.recover_bus()?;
+                    if (port.isr.as_ptr().read() & (1 << 15)) > 0 {
+                        return Err(I2cError::TimeOut)
+                    }
+                    return Ok(())
+                }
+                delay_us(1);
+                elapsed_us += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn wait_for_flag(&self, bit: u32) -> Result<(), I2cError> {
+        unsafe {
+            let port = self.instance.registers();
+            let mut elapsed_us = 0u32;
+            while (port.isr.as_ptr().read() & (1 << bit)) == 0 {
+                // STOPF is the end of the error handling, errors are not checked while waiting for it
+                if bit != 5 {
+                    self.check_error_flags()?;
+                }
+                if elapsed_us >= I2C_TIMEOUT_US {
+                    self.abort_transfer();
+                    self.recover_bus().ok();
+                    return Err(I2cError::TimeOut)
+                }
+                delay_us(1);
+                elapsed_us += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn abort_transfer(&self){
+        unsafe {
+            let port = self.instance.registers();
 
+            // Generate a STOP if the bus is still owned by the interface
+            if (port.isr.as_ptr().read() & (1 << 15)) > 0 {
+                self.stop_condition();
+                let mut elapsed_us = 0u32;
+                while (port.isr.as_ptr().read() & (1 << 5)) == 0 && elapsed_us < I2C_TIMEOUT_US {
+                    delay_us(1);
+                    elapsed_us += 1;
+                }
+            }
+
+            // Write STOPCF bit 5 and NACKCF bit 4 in I2C_ICR
+            port.icr.as_ptr().write((1 << 5) | (1 << 4));
+
+            // A software reset releases SCL and SDA if the STOP could not be sent
+            if (port.isr.as_ptr().read() & (1 << 15)) > 0 {
+                self.software_reset();
+            }
+        }
+    }
+
+    fn wait_for_scl_release(&self) -> bool {
+        let mut elapsed_us = 0u32;
+        while !self.pins.scl.gpio.get() {
+            if elapsed_us >= I2C_TIMEOUT_US {
+                return false
+            }
+            delay_us(1);
+            elapsed_us += 1;
+        }
+        true
+    }
+
+    fn software_reset(&self){
+        unsafe {
+            let port = self.instance.registers();
+            // Clear PE bit 0 in I2C_CR1, it must be kept low during at least 3 APB clock cycles
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !(1 << 0));
+            while (port.cr1.as_ptr().read() & (1 << 0)) > 0 { }
+            cortex_m::asm::delay(3);
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() | (1 << 0));
+        }
+    }
+
+    fn master_request(&self, address: u8, number_of_bytes: usize, direction: I2cTransferDirection, autoend: bool){
+        self.master_request_with_pec(address, number_of_bytes, direction, autoend, false)
+    }
 
+    fn master_request_with_pec(&self, address: u8, number_of_bytes: usize, direction: I2cTransferDirection, autoend: bool, pec: bool){
+        self.instance.master_request(I2cAddress::SevenBit(address), number_of_bytes, direction, autoend, pec)
+    }
+
+    fn master_set_number_of_bytes_and_transfer_direction(&self, number_of_bytes_to_transmit: usize, direction: I2cTransferDirection, autoend: bool){
+        unsafe {
+            let port = self.instance.registers();
+            //clear RD_WRN bit 10
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() & !(1 << 10));
+            //set transfer direction
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (direction.as_u32() << 10));
         }
+        //set number of bytes to transmit and automatic end mode
+        self.set_number_of_bytes(number_of_bytes_to_transmit, autoend);
     }
 
-    fn master_initialization_address_phase(config: MasterConfig){
+    fn master_initialization_address_phase(&self, config: MasterConfig){
         // run this code before a start condition
 
         /*
@@ -131,16 +710,19 @@ impl I2C {
         */
 
         unsafe {
-            let port = &*I2C1::ptr();
+            let port = self.instance.registers();
 
             //clear previous configurations
 
             port.cr2.as_ptr().write(0);
 
-            //set addressing mode.
-            port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (config.address_10_bit_mode.as_u32() << 11));
-            //Set slave address to send
-            port.cr2.as_ptr().write(port.cr2.as_ptr().read() | ((config.slave_address_to_send as u32 & 0x3FF) << 0));
+            //set addressing mode and slave address to send
+            let address = if config.address_10_bit_mode {
+                I2cAddress::TenBit(config.slave_address_to_send)
+            } else {
+                I2cAddress::SevenBit(config.slave_address_to_send as u8)
+            };
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() | address.as_u32());
             //Set 10 bit address reading procedure
             if config.i2c_10_bit_reading_procedure.is_some() {
                 port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (config.i2c_10_bit_reading_procedure.as_ref().unwrap().as_u32() << 12));
@@ -151,70 +733,521 @@ impl I2C {
 
     /* Private methods */
 
-    fn configure_peripheral(){
-        Self::configure_gpio();
+    fn configure_peripheral(&self, config: I2cConfig) -> Result<(), I2cError> {
+        let timing = compute_timing(self.kernel_clock_freq(), &config)?;
+
+        self.configure_gpio();
         unsafe {
-            let port = &*I2C1::ptr();
-            port.timingr.as_ptr().write(0x40B285C2);
+            let port = self.instance.registers();
+            // Filters and timings can only be programmed with PE bit 0 in I2C_CR1 cleared
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !(1 << 0));
+            // Set ANFOFF bit 12 and DNF bits 11:8 in I2C_CR1
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !((1 << 12) | (0xF << 8)));
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() | ((!config.analog_filter).as_u32() << 12) | ((config.digital_filter as u32 & 0xF) << 8));
+            port.timingr.as_ptr().write(timing);
         }
+        self.fast_mode_plus(config.speed.frequency() > 400000);
+        Ok(())
     }
 
-    fn enable_peripheral(){
+    fn kernel_clock_freq(&self) -> u32 {
         unsafe {
-            let port = &*I2C1::ptr();
+            let rcc = &*stm32g431::RCC::ptr();
+            // Read I2CxSEL in RCC_CCIPR
+            match (rcc.ccipr.as_ptr().read() >> self.instance.clock_selection_shift()) & 0x3 {
+                1 => SYSCLK_FREQ,
+                2 => HSI16_FREQ,
+                _ => APB1_FREQ
+            }
+        }
+    }
+
+    fn fast_mode_plus(&self, enable: bool){
+        unsafe {
+            let rcc = &*stm32g431::RCC::ptr();
+            let syscfg = &*stm32g431::SYSCFG::ptr();
+            // Enable SYSCFG clock bit 0 in RCC_APB2ENR
+            rcc.apb2enr.as_ptr().write(rcc.apb2enr.as_ptr().read() | (1 << 0));
+            // I2Cx_FMP in SYSCFG_CFGR1 enables the Fm+ drive on the pins of the instance
+            let fast_mode_plus_bit = self.instance.fast_mode_plus_bit();
+            if enable {
+                syscfg.cfgr1.as_ptr().write(syscfg.cfgr1.as_ptr().read() | (1 << fast_mode_plus_bit));
+            } else {
+                syscfg.cfgr1.as_ptr().write(syscfg.cfgr1.as_ptr().read() & !(1 << fast_mode_plus_bit));
+            }
+        }
+    }
+
+    fn enable_peripheral(&self){
+        unsafe {
+            let port = self.instance.registers();
             // Enable I2C
             port.cr1.as_ptr().write(port.cr1.as_ptr().read() | (1 << 0));
         }
     }
 
-    fn enable_peripheral_clock_in_rcc(){
+    fn enable_peripheral_clock_in_rcc(&self){
         unsafe {
             let rcc = &*stm32g431::RCC::ptr();
-            //Enable I2C1 in rcc clock
-            rcc.apb1enr1.as_ptr().write(rcc.apb1enr1.as_ptr().read() | (1 << 21))
+            //Enable I2Cx in rcc clock
+            rcc.apb1enr1.as_ptr().write(rcc.apb1enr1.as_ptr().read() | (1 << self.instance.rcc_enable_bit()))
         }
     }
 
-    fn start_condition(){
+    fn start_condition(&self){
         unsafe {
-            let port = &*I2C1::ptr();
+            let port = self.instance.registers();
             // Generate start condition
             port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (1 << 13));
         }
     }
 
-    fn stop_condition(){
+    fn stop_condition(&self){
         unsafe {
-            let port = &*I2C1::ptr();
+            let port = self.instance.registers();
             // Generate stop condition
             port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (1 << 14));
         }
     }
 
-    fn configure_gpio(){
-        // PB8 I2C1_SCL AF4
-        // PB9 I2C1_SDA AF4
-        PB8_I2C1_SCL.configure(TYPE_I2C1_GPIO);
-        PB9_I2C1_SDA.configure(TYPE_I2C1_GPIO)
+    fn configure_gpio(&self){
+        self.pins.scl.configure();
+        self.pins.sda.configure()
+    }
+
+}
+
+impl I2cRegisters for I2C {
+    type Error = I2cError;
+
+    fn read_cr2(&self) -> u32 {
+        unsafe {
+            self.instance.registers().cr2.as_ptr().read()
+        }
+    }
+
+    fn write_cr2(&self, value: u32){
+        unsafe {
+            self.instance.registers().cr2.as_ptr().write(value);
+        }
+    }
+
+    fn write_txdr(&self, data: u8){
+        unsafe {
+            self.instance.registers().txdr.as_ptr().write(data as u32);
+        }
+    }
+
+    fn read_rxdr(&self) -> u8 {
+        unsafe {
+            self.instance.registers().rxdr.as_ptr().read() as u8
+        }
+    }
+
+    fn wait_for_flag(&self, bit: u32) -> Result<(), I2cError> {
+        I2C::wait_for_flag(self, bit)
+    }
+
+    fn wait_for_stop_condition(&self) -> Result<(), I2cError> {
+        I2C::wait_for_stop_condition(self)
+    }
+}
+
+impl I2cInstance {
+    fn registers(&self) -> &'static stm32g431::i2c1::RegisterBlock {
+        unsafe {
+            match self {
+                I2cInstance::I2C1 => &*stm32g431::I2C1::ptr(),
+                I2cInstance::I2C2 => &*stm32g431::I2C2::ptr(),
+                I2cInstance::I2C3 => &*stm32g431::I2C3::ptr()
+            }
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            I2cInstance::I2C1 => 0,
+            I2cInstance::I2C2 => 1,
+            I2cInstance::I2C3 => 2
+        }
+    }
+
+    // With PECBYTE the last byte counted in NBYTES is the PEC, sent or checked by the hardware
+    fn master_request(&self, address: I2cAddress, number_of_bytes: usize, direction: I2cTransferDirection, autoend: bool, pec: bool){
+        self.write_request(address, number_of_bytes, direction, autoend, pec, false)
+    }
+
+    // HEAD10R bit 12 in I2C_CR2, only valid for a repeated start after a 10-bit write to the same slave
+    fn master_request_header_only(&self, address: u16, number_of_bytes: usize, autoend: bool){
+        self.write_request(I2cAddress::TenBit(address), number_of_bytes, I2cTransferDirection::MasterRequestARead, autoend, false, true)
+    }
+
+    fn write_request(&self, address: I2cAddress, number_of_bytes: usize, direction: I2cTransferDirection, autoend: bool, pec: bool, header_only: bool){
+        let read = direction.as_u32() == 1;
+        unsafe {
+            let port = self.registers();
+            // PECBYTE bit 26 in I2C_CR2
+            port.cr2.as_ptr().write(ten_bit::transfer_request(address.as_u32(), number_of_bytes, read, autoend, header_only) | (pec.as_u32() << 26));
+        }
+    }
+
+    fn set_number_of_bytes(&self, remaining: usize, autoend: bool){
+        unsafe {
+            let port = self.registers();
+            port.cr2.as_ptr().write(ten_bit::with_number_of_bytes(port.cr2.as_ptr().read(), remaining, autoend));
+        }
+    }
+
+    // I2CxEN in RCC_APB1ENR1
+    fn rcc_enable_bit(&self) -> u32 {
+        match self {
+            I2cInstance::I2C1 => 21,
+            I2cInstance::I2C2 => 22,
+            I2cInstance::I2C3 => 30
+        }
+    }
+
+    // I2CxSEL in RCC_CCIPR
+    fn clock_selection_shift(&self) -> u32 {
+        match self {
+            I2cInstance::I2C1 => 12,
+            I2cInstance::I2C2 => 14,
+            I2cInstance::I2C3 => 16
+        }
+    }
+
+    // I2Cx_FMP in SYSCFG_CFGR1
+    fn fast_mode_plus_bit(&self) -> u32 {
+        match self {
+            I2cInstance::I2C1 => 20,
+            I2cInstance::I2C2 => 21,
+            I2cInstance::I2C3 => 22
+        }
+    }
+
+    fn event_interrupt(&self) -> stm32g431::Interrupt {
+        match self {
+            I2cInstance::I2C1 => stm32g431::Interrupt::I2C1_EV,
+            I2cInstance::I2C2 => stm32g431::Interrupt::I2C2_EV,
+            I2cInstance::I2C3 => stm32g431::Interrupt::I2C3_EV
+        }
+    }
+
+    fn error_interrupt(&self) -> stm32g431::Interrupt {
+        match self {
+            I2cInstance::I2C1 => stm32g431::Interrupt::I2C1_ER,
+            I2cInstance::I2C2 => stm32g431::Interrupt::I2C2_ER,
+            I2cInstance::I2C3 => stm32g431::Interrupt::I2C3_ER
+        }
     }
+}
 
+impl I2cPin {
+    pub fn configure(&self){
+        self.gpio.configure(gpio::GpioConfig {
+            moder: gpio::MODER::AlternateFunction,
+            otyper: gpio::OTYPER::OpenDrain,
+            ospeedr: gpio::OSPEEDR::VeryHigh,
+            pupdr: gpio::PUPDR::PullUp,
+            alf_func_sel: Some(self.alternate_function)
+        })
+    }
 }
 
+impl I2cSpeed {
+    pub fn frequency(&self) -> u32 {
+        match self {
+            I2cSpeed::Standard100k => 100000,
+            I2cSpeed::Fast400k => 400000,
+            I2cSpeed::FastPlus1M => 1000000,
+            I2cSpeed::Custom(hz) => *hz
+        }
+    }
+}
 
+impl I2cConfig {
+    pub fn new(speed: I2cSpeed) -> I2cConfig {
+        I2cConfig {
+            speed,
+            rise_ns: 100,
+            fall_ns: 10,
+            analog_filter: true,
+            digital_filter: 0
+        }
+    }
+}
 
 /*
+ * TIMINGR computation (RM0440 I2C timings)
+ * tPRESC = (PRESC + 1) * tI2CCLK
+ * tSDADEL = SDADEL * tPRESC, must be within:
+ *   tf - tAF(min) - (DNF + 3) * tI2CCLK and tVD;DAT(max) - tr - tAF(max) - (DNF + 4) * tI2CCLK
+ * tSCLDEL = (SCLDEL + 1) * tPRESC >= tr + tSU;DAT(min)
+ * tSCL = tSYNC1 + tSYNC2 + ((SCLL + 1) + (SCLH + 1)) * tPRESC
+ *   with tSYNC = slope + tAF + (DNF + 2) * tI2CCLK for each edge
+ * Times are computed in picoseconds.
+ */
+pub fn compute_timing(i2c_clock: u32, config: &I2cConfig) -> Result<u32, I2cError> {
+    let frequency = config.speed.frequency();
+
+    let mode = if frequency == 0 || i2c_clock == 0 {
+        return Err(I2cError::InvalidConfiguration)
+    } else if frequency <= 100000 {
+        STANDARD_MODE_TIMINGS
+    } else if frequency <= 400000 {
+        FAST_MODE_TIMINGS
+    } else if frequency <= 1000000 {
+        FAST_MODE_PLUS_TIMINGS
+    } else {
+        return Err(I2cError::InvalidConfiguration)
+    };
+
+    if config.digital_filter > 15 {
+        return Err(I2cError::InvalidConfiguration)
+    }
+
+    let ps = |ns: u32| ns as i64 * 1000;
+    let clock_period = 1000000000000i64 / i2c_clock as i64;
+    let scl_period = 1000000000000i64 / frequency as i64;
+    let dnf = config.digital_filter as i64;
+    let (filter_min, filter_max) = if config.analog_filter {
+        (ps(ANALOG_FILTER_MIN_NS), ps(ANALOG_FILTER_MAX_NS))
+    } else {
+        (0, 0)
+    };
+
+    let sdadel_min = ps(config.fall_ns) - filter_min - (dnf + 3) * clock_period;
+    let sdadel_max = ps(mode.data_valid_max_ns) - ps(config.rise_ns) - filter_max - (dnf + 4) * clock_period;
+    let scldel_min = ps(config.rise_ns) + ps(mode.data_setup_min_ns);
+    // tSYNC1 follows the SCL falling edge and adds to the low period, tSYNC2 follows the rising edge
+    let sync_low = ps(config.fall_ns) + filter_min + (dnf + 2) * clock_period;
+    let sync_high = ps(config.rise_ns) + filter_min + (dnf + 2) * clock_period;
+    let sync = sync_low + sync_high;
+
+    if sdadel_max < 0 || scl_period <= sync {
+        return Err(I2cError::InvalidConfiguration)
+    }
+
+    for presc in 0..16i64 {
+        let presc_period = (presc + 1) * clock_period;
+
+        let sdadel = ceil_div(sdadel_min.max(0), presc_period);
+        if sdadel > 15 || sdadel * presc_period > sdadel_max {
+            continue
+        }
+
+        let scldel = (ceil_div(scldel_min, presc_period) - 1).max(0);
+        if scldel > 15 {
+            continue
+        }
+
+        // SCL low and high periods, split in proportion of the minimum values of the mode
+        let ticks = ceil_div(scl_period - sync, presc_period);
+        let low_min = ceil_div((ps(mode.low_min_ns) - sync_low).max(presc_period), presc_period);
+        let high_min = ceil_div((ps(mode.high_min_ns) - sync_high).max(presc_period), presc_period);
+        let low = ceil_div(ticks * mode.low_min_ns as i64, (mode.low_min_ns + mode.high_min_ns) as i64).max(low_min);
+        let high = (ticks - low).max(high_min);
+
+        if low > 256 || high > 256 {
+            continue
+        }
+
+        // Data hold and setup must fit in the SCL low period
+        if sdadel + scldel + 1 >= low {
+            continue
+        }
+
+        return Ok(((presc as u32) << 28)
+            | ((scldel as u32) << 20)
+            | ((sdadel as u32) << 16)
+            | (((high - 1) as u32) << 8)
+            | ((low - 1) as u32))
+    }
+
+    Err(I2cError::InvalidConfiguration)
+}
+
+fn ceil_div(a: i64, b: i64) -> i64 {
+    (a + b - 1) / b
+}
+
+const DEFAULT_SLAVE_STATE: I2cSlaveState = I2cSlaveState {
+    address: 0,
+    register: 0,
+    register_received: false,
+    on_read: default_on_read,
+    on_write: default_on_write,
+    pec_data_length: default_pec_data_length,
+    pec_remaining: 0
+};
+
+static mut SLAVE_STATES: [I2cSlaveState; 3] = [DEFAULT_SLAVE_STATE; 3];
+
+fn default_on_read(_address: u8, _register: u8) -> u8 {
+    0xFF
+}
+
+fn default_on_write(_address: u8, _register: u8, _value: u8){
+    /* ******* */
+}
+
+fn default_pec_data_length(_register: u8, _read: bool) -> u8 {
+    1
+}
+
+// Asynchronous master transfers take precedence over the slave register map
+fn i2c_event(instance: I2cInstance) {
+    if !transfer::transfer_event(instance) {
+        slave_event(instance)
+    }
+}
+
+fn i2c_error(instance: I2cInstance) {
+    if !transfer::transfer_error(instance) {
+        slave_error(instance)
+    }
+}
+
+fn slave_event(instance: I2cInstance) {
+    unsafe {
+        let port = instance.registers();
+        let state = instance.index();
+        let isr = port.isr.as_ptr().read();
+        // SBC bit 16 in I2C_CR1, only set for an SMBus device with PEC
+        let pec = (port.cr1.as_ptr().read() & (1 << 16)) > 0;
+
+        // ADDR bit 3 in I2C_ISR, SCL is stretched until ADDRCF is written
+        if (isr & (1 << 3)) > 0 {
+            // ADDCODE bits 23:17 in I2C_ISR
+            SLAVE_STATES[state].address = ((isr >> 17) & 0x7F) as u8;
+            // DIR bit 16 in I2C_ISR, the master reads from the register pointer set by a previous write
+            if (isr & (1 << 16)) > 0 {
+                // Flush I2C_TXDR by setting TXE bit 0 in I2C_ISR
+                port.isr.as_ptr().write(isr | (1 << 0));
+                if pec {
+                    // The hardware sends the PEC after the data bytes
+                    let length = (SLAVE_STATES[state].pec_data_length)(SLAVE_STATES[state].register, true);
+                    set_slave_number_of_bytes(port, length as u32 + 1, false, true);
+                }
+            } else {
+                SLAVE_STATES[state].register_received = false;
+                if pec {
+                    // Only the command code, its data length is known once it is received
+                    set_slave_number_of_bytes(port, 1, true, false);
+                }
+            }
+            // Write ADDRCF bit 3 in I2C_ICR
+            port.icr.as_ptr().write(1 << 3);
+        }
+
+        // RXNE bit 2 in I2C_ISR, the first byte written is the register pointer
+        if (isr & (1 << 2)) > 0 {
+            let value = port.rxdr.as_ptr().read() as u8;
+            if !SLAVE_STATES[state].register_received {
+                SLAVE_STATES[state].register = value;
+                SLAVE_STATES[state].register_received = true;
+            } else if !pec || SLAVE_STATES[state].pec_remaining > 0 {
+                (SLAVE_STATES[state].on_write)(SLAVE_STATES[state].address, SLAVE_STATES[state].register, value);
+                SLAVE_STATES[state].register = SLAVE_STATES[state].register.wrapping_add(1);
+                SLAVE_STATES[state].pec_remaining = SLAVE_STATES[state].pec_remaining.saturating_sub(1);
+            }
+            // With PEC the last byte is the PEC, it was checked by the hardware
+        }
+
+        // TCR bit 7 in I2C_ISR, the command code was received and SCL is stretched until NBYTES is written
+        if pec && (isr & (1 << 7)) > 0 {
+            let length = (SLAVE_STATES[state].pec_data_length)(SLAVE_STATES[state].register, false);
+            SLAVE_STATES[state].pec_remaining = length;
+            // A PEC mismatch sets PECERR and the PEC byte is not acknowledged
+            set_slave_number_of_bytes(port, length as u32 + 1, false, true);
+        }
+
+        // TXIS bit 1 in I2C_ISR
+        if (isr & (1 << 1)) > 0 {
+            let value = (SLAVE_STATES[state].on_read)(SLAVE_STATES[state].address, SLAVE_STATES[state].register);
+            port.txdr.as_ptr().write(value as u32);
+            SLAVE_STATES[state].register = SLAVE_STATES[state].register.wrapping_add(1);
+        }
+
+        // NACKF bit 4 in I2C_ISR, the master ends a read
+        if (isr & (1 << 4)) > 0 {
+            // The byte loaded in I2C_TXDR after the last one was not sent, with PEC NBYTES stops TXIS after the last data byte
+            if !pec {
+                SLAVE_STATES[state].register = SLAVE_STATES[state].register.wrapping_sub(1);
+            }
+            // Write NACKCF bit 4 in I2C_ICR
+            port.icr.as_ptr().write(1 << 4);
+        }
+
+        // STOPF bit 5 in I2C_ISR
+        if (isr & (1 << 5)) > 0 {
+            // Write STOPCF bit 5 in I2C_ICR
+            port.icr.as_ptr().write(1 << 5);
+            // Flush I2C_TXDR by setting TXE bit 0 in I2C_ISR
+            port.isr.as_ptr().write(port.isr.as_ptr().read() | (1 << 0));
+        }
+    }
+}
+
+// Slave byte control, PECBYTE makes the last of the NBYTES bytes the PEC
+fn set_slave_number_of_bytes(port: &stm32g431::i2c1::RegisterBlock, number_of_bytes: u32, reload: bool, pec: bool){
+    unsafe {
+        // NBYTES bits 23:16, RELOAD bit 24 and PECBYTE bit 26 in I2C_CR2
+        port.cr2.as_ptr().write(port.cr2.as_ptr().read() & !((0xFF << 16) | (1 << 24) | (1 << 26)));
+        port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (number_of_bytes << 16) | (reload.as_u32() << 24) | (pec.as_u32() << 26));
+    }
+}
+
+fn slave_error(instance: I2cInstance) {
+    unsafe {
+        let port = instance.registers();
+        let state = instance.index();
+        // Write BERRCF bit 8, ARLOCF bit 9, OVRCF bit 10, PECCF bit 11 and TIMOUTCF bit 12 in I2C_ICR
+        port.icr.as_ptr().write((1 << 8) | (1 << 9) | (1 << 10) | (1 << 11) | (1 << 12));
+        SLAVE_STATES[state].register_received = false;
+    }
+}
+
+#[interrupt]
+fn I2C1_EV() {
+    i2c_event(I2cInstance::I2C1)
+}
+
+#[interrupt]
+fn I2C1_ER() {
+    i2c_error(I2cInstance::I2C1)
+}
+
+#[interrupt]
+fn I2C2_EV() {
+    i2c_event(I2cInstance::I2C2)
+}
+
+#[interrupt]
+fn I2C2_ER() {
+    i2c_error(I2cInstance::I2C2)
+}
+
+#[interrupt]
+fn I2C3_EV() {
+    i2c_event(I2cInstance::I2C3)
+}
+
+#[interrupt]
+fn I2C3_ER() {
+    i2c_error(I2cInstance::I2C3)
+}
+
 pub enum I2cTransferDirection {
     MasterRequestAWrite,
     MasterRequestARead
 }
- */
 
 pub enum I2cHeaderOnlyReadDirection10Bit {
     CompleteSlaveAddress,
     SevenBitsFirst
 }
 
-/*
 impl Conversions for I2cTransferDirection {
     fn as_u32(&self) -> u32 {
         match self {
@@ -223,7 +1256,6 @@ impl Conversions for I2cTransferDirection {
         }
     }
 }
- */
 
 impl Conversions for I2cHeaderOnlyReadDirection10Bit {
     fn as_u32(&self) -> u32 {
@@ -234,3 +1266,28 @@ impl Conversions for I2cHeaderOnlyReadDirection10Bit {
     }
 }
 
+impl Conversions for I2cAddressMask {
+    fn as_u32(&self) -> u32 {
+        match self {
+            I2cAddressMask::NoMask => 0,
+            I2cAddressMask::Mask1 => 1,
+            I2cAddressMask::Mask2 => 2,
+            I2cAddressMask::Mask3 => 3,
+            I2cAddressMask::Mask4 => 4,
+            I2cAddressMask::Mask5 => 5,
+            I2cAddressMask::Mask6 => 6,
+            I2cAddressMask::Mask7 => 7,
+        }
+    }
+}
+
+impl Conversions for I2cAddress {
+    fn as_u32(&self) -> u32 {
+        match self {
+            // 7-bit address goes into SADD[7:1]
+            I2cAddress::SevenBit(address) => (*address as u32 & 0x7F) << 1,
+            // 10-bit address goes into SADD[9:0] with ADD10 bit 11
+            I2cAddress::TenBit(address) => (*address as u32 & 0x3FF) | (1 << 11)
+        }
+    }
+}
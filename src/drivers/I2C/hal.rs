@@ -0,0 +1,207 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_hal::i2c::{Error, ErrorKind, ErrorType, NoAcknowledgeSource, Operation, SevenBitAddress, TenBitAddress};
+use crate::drivers::I2C::{I2C, I2cAddress, I2cError, I2cTransferDirection, I2C_MAX_NBYTES};
+use crate::drivers::I2C::transfer::{I2cTransfer, I2cTransferFuture, I2cTransferMode};
+
+impl I2C {
+    // Adjacent operations of the same type are merged, a repeated start separates the others and the last one ends with a STOP
+    pub fn run_transaction(&self, address: I2cAddress, operations: &mut [Operation<'_>]) -> Result<(), I2cError> {
+        if operations.is_empty() {
+            return Ok(())
+        }
+
+        self.wait_while_busy()?;
+
+        let mut start = 0;
+        while start < operations.len() {
+            let read = is_read(&operations[start]);
+            let mut end = start + 1;
+            while end < operations.len() && is_read(&operations[end]) == read {
+                end += 1;
+            }
+
+            let last = end == operations.len();
+            let group = &mut operations[start..end];
+            let length: usize = group.iter().map(operation_length).sum();
+            let direction = if read {
+                I2cTransferDirection::MasterRequestARead
+            } else {
+                I2cTransferDirection::MasterRequestAWrite
+            };
+
+            match address {
+                // A read following a write to a 10-bit slave only repeats the header
+                I2cAddress::TenBit(address) if read && start > 0 => self.instance.master_request_header_only(address, length, last),
+                _ => self.instance.master_request(address, length, direction, last, false)
+            }
+
+            let mut index = 0;
+            self.transfer_group(group, length, last, &mut index).map_err(|error| match error {
+                // The first byte is only moved once the address is acknowledged
+                I2cError::NotAcknowledgedReceived if index == 0 => I2cError::AddressNotAcknowledged,
+                _ => error
+            })?;
+
+            start = end;
+        }
+        Ok(())
+    }
+
+    // Single write, read and write-then-read transactions run as one interrupt transfer
+    pub async fn run_transaction_async(&self, address: I2cAddress, operations: &mut [Operation<'_>]) -> Result<(), I2cError> {
+        match operations {
+            [] => Ok(()),
+            [Operation::Write(data)] => self.transfer_async(I2cTransfer::new(address, data, &mut [])).await,
+            [Operation::Read(buffer)] => self.transfer_async(I2cTransfer::new(address, &[], buffer)).await,
+            [Operation::Write(data), Operation::Read(buffer)] => self.transfer_async(I2cTransfer::new(address, data, buffer)).await,
+            _ => self.run_groups_async(address, operations).await
+        }
+    }
+
+    // Every merged group is an interrupt transfer ending at TC, the next one starts with a repeated start and the last one with AUTOEND
+    async fn run_groups_async(&self, address: I2cAddress, operations: &mut [Operation<'_>]) -> Result<(), I2cError> {
+        let mut start = 0;
+        while start < operations.len() {
+            let read = is_read(&operations[start]);
+            let mut end = start + 1;
+            while end < operations.len() && is_read(&operations[end]) == read {
+                end += 1;
+            }
+
+            let keep_bus = end < operations.len();
+            // A read following a write to a 10-bit slave only repeats the header
+            let header_only = matches!(address, I2cAddress::TenBit(_)) && read && start > 0;
+            let group = &mut operations[start..end];
+
+            // The interrupt moves one contiguous buffer, merged operations go through a copy
+            if let [operation] = group {
+                let transfer = match operation {
+                    Operation::Write(data) => I2cTransfer::new(address, data, &mut []),
+                    Operation::Read(buffer) => I2cTransfer::new(address, &[], buffer)
+                };
+                self.transfer_async(transfer.segment(start > 0, header_only, keep_bus)).await?;
+            } else if read {
+                let mut merged = vec![0; group.iter().map(operation_length).sum()];
+                self.transfer_async(I2cTransfer::new(address, &[], &mut merged).segment(start > 0, header_only, keep_bus)).await?;
+                let mut received = &merged[..];
+                for operation in group.iter_mut() {
+                    if let Operation::Read(buffer) = operation {
+                        let (head, tail) = received.split_at(buffer.len());
+                        buffer.copy_from_slice(head);
+                        received = tail;
+                    }
+                }
+            } else {
+                let mut merged = Vec::with_capacity(group.iter().map(operation_length).sum());
+                for operation in group.iter() {
+                    if let Operation::Write(data) = operation {
+                        merged.extend_from_slice(data);
+                    }
+                }
+                self.transfer_async(I2cTransfer::new(address, &merged, &mut []).segment(start > 0, header_only, keep_bus)).await?;
+            }
+
+            start = end;
+        }
+        Ok(())
+    }
+
+    // The buffers of the transfer are borrowed by the caller until the future resolves
+    async fn transfer_async(&self, transfer: I2cTransfer) -> Result<(), I2cError> {
+        unsafe {
+            self.start_transfer(transfer, I2cTransferMode::Interrupt, None)?;
+        }
+        // The transfer is aborted if the future is dropped before the end
+        I2cTransferFuture::new(self.instance, true).await
+    }
+
+    // index counts the bytes moved so far, it tells an address NACK from a data NACK
+    fn transfer_group(&self, group: &mut [Operation<'_>], length: usize, last: bool, index: &mut usize) -> Result<(), I2cError> {
+        for operation in group.iter_mut() {
+            match operation {
+                Operation::Write(data) => {
+                    for &data_slice in data.iter() {
+                        self.reload_at_chunk_end(*index, length, last)?;
+                        self.write_into_tr_register(data_slice)?;
+                        *index += 1;
+                    }
+                }
+                Operation::Read(buffer) => {
+                    for data_slice in buffer.iter_mut() {
+                        self.reload_at_chunk_end(*index, length, last)?;
+                        *data_slice = self.read_from_rx_register()?;
+                        *index += 1;
+                    }
+                }
+            }
+        }
+
+        if last {
+            self.wait_for_stop_condition()
+        } else {
+            self.wait_for_transfer_complete()
+        }
+    }
+
+    fn reload_at_chunk_end(&self, index: usize, length: usize, autoend: bool) -> Result<(), I2cError> {
+        // NBYTES holds up to 255 bytes, the next chunk is loaded when TCR is set
+        if index > 0 && index.is_multiple_of(I2C_MAX_NBYTES) {
+            self.reload_next_chunk(length - index, autoend)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_read(operation: &Operation<'_>) -> bool {
+    matches!(operation, Operation::Read(_))
+}
+
+fn operation_length(operation: &Operation<'_>) -> usize {
+    match operation {
+        Operation::Read(buffer) => buffer.len(),
+        Operation::Write(data) => data.len()
+    }
+}
+
+impl Error for I2cError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            I2cError::BusError => ErrorKind::Bus,
+            I2cError::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            I2cError::OverrunUnderrun => ErrorKind::Overrun,
+            I2cError::AddressNotAcknowledged => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+            // Transactions report a refused address as AddressNotAcknowledged, a later NACK is on a data byte
+            I2cError::NotAcknowledgedReceived => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+            _ => ErrorKind::Other
+        }
+    }
+}
+
+impl ErrorType for I2C {
+    type Error = I2cError;
+}
+
+impl embedded_hal::i2c::I2c<SevenBitAddress> for I2C {
+    fn transaction(&mut self, address: SevenBitAddress, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        self.run_transaction(I2cAddress::SevenBit(address), operations)
+    }
+}
+
+impl embedded_hal::i2c::I2c<TenBitAddress> for I2C {
+    fn transaction(&mut self, address: TenBitAddress, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        self.run_transaction(I2cAddress::TenBit(address), operations)
+    }
+}
+
+impl embedded_hal_async::i2c::I2c<SevenBitAddress> for I2C {
+    async fn transaction(&mut self, address: SevenBitAddress, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        self.run_transaction_async(I2cAddress::SevenBit(address), operations).await
+    }
+}
+
+impl embedded_hal_async::i2c::I2c<TenBitAddress> for I2C {
+    async fn transaction(&mut self, address: TenBitAddress, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        self.run_transaction_async(I2cAddress::TenBit(address), operations).await
+    }
+}
@@ -0,0 +1,249 @@
+#![allow(dead_code)]
+
+use alloc::string::String;
+use crate::core::Conversions;
+use crate::drivers::I2C::I2cError;
+use crate::drivers::I2C::smbus::Smbus;
+
+// LINEAR11 mantissa is an 11-bit two's complement value
+const LINEAR11_MANTISSA_MIN: i32 = -1024;
+const LINEAR11_MANTISSA_MAX: i32 = 1023;
+// LINEAR11 exponent is a 5-bit two's complement value
+const LINEAR11_EXPONENT_MIN: i32 = -16;
+const LINEAR11_EXPONENT_MAX: i32 = 15;
+
+pub enum PmbusError {
+    Bus(I2cError),
+    // VOUT_MODE selects the VID or direct format, only the linear format is supported
+    UnsupportedVoutMode
+}
+
+pub enum PmbusCommand {
+    Page,
+    Operation,
+    OnOffConfig,
+    ClearFaults,
+    Capability,
+    VoutMode,
+    VoutCommand,
+    StatusByte,
+    StatusWord,
+    StatusVout,
+    StatusIout,
+    StatusInput,
+    StatusTemperature,
+    ReadVin,
+    ReadIin,
+    ReadVout,
+    ReadIout,
+    ReadTemperature1,
+    ReadTemperature2,
+    ReadFanSpeed1,
+    ReadPout,
+    ReadPin,
+    PmbusRevision,
+    MfrId,
+    MfrModel,
+    MfrRevision
+}
+
+pub struct PmbusDevice<'a> {
+    pub bus: &'a Smbus,
+    pub address: u8
+}
+
+impl<'a> PmbusDevice<'a> {
+    pub fn new(bus: &'a Smbus, address: u8) -> PmbusDevice<'a> {
+        PmbusDevice {
+            bus,
+            address
+        }
+    }
+
+    pub fn select_page(&self, page: u8) -> Result<(), PmbusError> {
+        self.write_byte(PmbusCommand::Page, page)
+    }
+
+    pub fn clear_faults(&self) -> Result<(), PmbusError> {
+        self.bus.send_byte(self.address, PmbusCommand::ClearFaults.code()).map_err(PmbusError::Bus)
+    }
+
+    pub fn set_operation(&self, operation: u8) -> Result<(), PmbusError> {
+        self.write_byte(PmbusCommand::Operation, operation)
+    }
+
+    pub fn status_word(&self) -> Result<u16, PmbusError> {
+        self.read_word(PmbusCommand::StatusWord)
+    }
+
+    pub fn read_vin(&self) -> Result<f32, PmbusError> {
+        self.read_linear11(PmbusCommand::ReadVin)
+    }
+
+    pub fn read_iin(&self) -> Result<f32, PmbusError> {
+        self.read_linear11(PmbusCommand::ReadIin)
+    }
+
+    pub fn read_iout(&self) -> Result<f32, PmbusError> {
+        self.read_linear11(PmbusCommand::ReadIout)
+    }
+
+    pub fn read_pin(&self) -> Result<f32, PmbusError> {
+        self.read_linear11(PmbusCommand::ReadPin)
+    }
+
+    pub fn read_pout(&self) -> Result<f32, PmbusError> {
+        self.read_linear11(PmbusCommand::ReadPout)
+    }
+
+    pub fn read_temperature_1(&self) -> Result<f32, PmbusError> {
+        self.read_linear11(PmbusCommand::ReadTemperature1)
+    }
+
+    pub fn read_fan_speed_1(&self) -> Result<f32, PmbusError> {
+        self.read_linear11(PmbusCommand::ReadFanSpeed1)
+    }
+
+    // Output voltages use LINEAR16, the exponent is read from VOUT_MODE
+    pub fn read_vout(&self) -> Result<f32, PmbusError> {
+        let exponent = self.vout_exponent()?;
+        let raw = self.read_word(PmbusCommand::ReadVout)?;
+        Ok(linear16_to_f32(raw, exponent))
+    }
+
+    pub fn set_vout(&self, volts: f32) -> Result<(), PmbusError> {
+        let exponent = self.vout_exponent()?;
+        self.write_word(PmbusCommand::VoutCommand, f32_to_linear16(volts, exponent))
+    }
+
+    pub fn vout_exponent(&self) -> Result<i8, PmbusError> {
+        let vout_mode = self.read_byte(PmbusCommand::VoutMode)?;
+        // Mode bits 7:5, 0b000 is the linear format
+        if (vout_mode >> 5) != 0 {
+            return Err(PmbusError::UnsupportedVoutMode)
+        }
+        // Exponent bits 4:0, two's complement
+        Ok(((vout_mode << 3) as i8) >> 3)
+    }
+
+    pub fn manufacturer_id(&self) -> Result<String, PmbusError> {
+        self.read_string(PmbusCommand::MfrId)
+    }
+
+    pub fn manufacturer_model(&self) -> Result<String, PmbusError> {
+        self.read_string(PmbusCommand::MfrModel)
+    }
+
+    pub fn manufacturer_revision(&self) -> Result<String, PmbusError> {
+        self.read_string(PmbusCommand::MfrRevision)
+    }
+
+    pub fn read_linear11(&self, command: PmbusCommand) -> Result<f32, PmbusError> {
+        Ok(linear11_to_f32(self.read_word(command)?))
+    }
+
+    pub fn write_linear11(&self, command: PmbusCommand, value: f32) -> Result<(), PmbusError> {
+        self.write_word(command, f32_to_linear11(value))
+    }
+
+    pub fn read_byte(&self, command: PmbusCommand) -> Result<u8, PmbusError> {
+        self.bus.read_byte(self.address, command.code()).map_err(PmbusError::Bus)
+    }
+
+    pub fn write_byte(&self, command: PmbusCommand, value: u8) -> Result<(), PmbusError> {
+        self.bus.write_byte(self.address, command.code(), value).map_err(PmbusError::Bus)
+    }
+
+    pub fn read_word(&self, command: PmbusCommand) -> Result<u16, PmbusError> {
+        self.bus.read_word(self.address, command.code()).map_err(PmbusError::Bus)
+    }
+
+    pub fn write_word(&self, command: PmbusCommand, value: u16) -> Result<(), PmbusError> {
+        self.bus.write_word(self.address, command.code(), value).map_err(PmbusError::Bus)
+    }
+
+    fn read_string(&self, command: PmbusCommand) -> Result<String, PmbusError> {
+        let block = self.bus.block_read(self.address, command.code()).map_err(PmbusError::Bus)?;
+        Ok(String::from_utf8_lossy(&block).into_owned())
+    }
+}
+
+impl PmbusCommand {
+    pub fn code(&self) -> u8 {
+        self.as_u32() as u8
+    }
+}
+
+// Y = mantissa * 2^exponent, exponent bits 15:11 and mantissa bits 10:0
+pub fn linear11_to_f32(raw: u16) -> f32 {
+    let exponent = (raw as i16 >> 11) as i32;
+    let mantissa = (((raw << 5) as i16) >> 5) as i32;
+    mantissa as f32 * power_of_two(exponent)
+}
+
+pub fn f32_to_linear11(value: f32) -> u16 {
+    // The smallest exponent that fits the mantissa keeps the most precision
+    let mut exponent = LINEAR11_EXPONENT_MIN;
+    let mut mantissa = round(value / power_of_two(exponent));
+    while !(LINEAR11_MANTISSA_MIN..=LINEAR11_MANTISSA_MAX).contains(&mantissa) && exponent < LINEAR11_EXPONENT_MAX {
+        exponent += 1;
+        mantissa = round(value / power_of_two(exponent));
+    }
+    let mantissa = mantissa.clamp(LINEAR11_MANTISSA_MIN, LINEAR11_MANTISSA_MAX);
+    (((exponent as u16) & 0x1F) << 11) | ((mantissa as u16) & 0x7FF)
+}
+
+// Y = mantissa * 2^exponent, the mantissa is unsigned and the exponent comes from VOUT_MODE
+pub fn linear16_to_f32(raw: u16, exponent: i8) -> f32 {
+    raw as f32 * power_of_two(exponent as i32)
+}
+
+pub fn f32_to_linear16(value: f32, exponent: i8) -> u16 {
+    round(value / power_of_two(exponent as i32)).clamp(0, u16::MAX as i32) as u16
+}
+
+fn power_of_two(exponent: i32) -> f32 {
+    // Built from the IEEE 754 exponent field, valid for normal numbers
+    f32::from_bits(((exponent + 127) as u32) << 23)
+}
+
+fn round(value: f32) -> i32 {
+    if value >= 0.0 {
+        (value + 0.5) as i32
+    } else {
+        (value - 0.5) as i32
+    }
+}
+
+impl Conversions for PmbusCommand {
+    fn as_u32(&self) -> u32 {
+        match self {
+            PmbusCommand::Page => 0x00,
+            PmbusCommand::Operation => 0x01,
+            PmbusCommand::OnOffConfig => 0x02,
+            PmbusCommand::ClearFaults => 0x03,
+            PmbusCommand::Capability => 0x19,
+            PmbusCommand::VoutMode => 0x20,
+            PmbusCommand::VoutCommand => 0x21,
+            PmbusCommand::StatusByte => 0x78,
+            PmbusCommand::StatusWord => 0x79,
+            PmbusCommand::StatusVout => 0x7A,
+            PmbusCommand::StatusIout => 0x7B,
+            PmbusCommand::StatusInput => 0x7C,
+            PmbusCommand::StatusTemperature => 0x7D,
+            PmbusCommand::ReadVin => 0x88,
+            PmbusCommand::ReadIin => 0x89,
+            PmbusCommand::ReadVout => 0x8B,
+            PmbusCommand::ReadIout => 0x8C,
+            PmbusCommand::ReadTemperature1 => 0x8D,
+            PmbusCommand::ReadTemperature2 => 0x8E,
+            PmbusCommand::ReadFanSpeed1 => 0x90,
+            PmbusCommand::ReadPout => 0x96,
+            PmbusCommand::ReadPin => 0x97,
+            PmbusCommand::PmbusRevision => 0x98,
+            PmbusCommand::MfrId => 0x99,
+            PmbusCommand::MfrModel => 0x9A,
+            PmbusCommand::MfrRevision => 0x9B,
+        }
+    }
+}
@@ -0,0 +1,316 @@
+#![allow(dead_code)]
+
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::core::Conversions;
+use crate::drivers::gpio;
+use crate::drivers::gpio::GPIOPORT;
+use crate::drivers::I2C::{I2C, I2cConfig, I2cError, I2cPin, I2cSlaveConfig, I2cTransferDirection, SLAVE_STATES};
+
+pub const I2C1_SMBA_PB5: I2cPin = I2cPin {
+    gpio: gpio::Gpio { port: GPIOPORT::GPIOB, pin_number: 5 },
+    alternate_function: 4
+};
+
+pub const I2C2_SMBA_PA10: I2cPin = I2cPin {
+    gpio: gpio::Gpio { port: GPIOPORT::GPIOA, pin_number: 10 },
+    alternate_function: 4
+};
+
+pub const I2C3_SMBA_PB2: I2cPin = I2cPin {
+    gpio: gpio::Gpio { port: GPIOPORT::GPIOB, pin_number: 2 },
+    alternate_function: 4
+};
+
+// Devices pulling SMBALERT low answer a read on this address with their own address
+const SMBUS_ALERT_RESPONSE_ADDRESS: u8 = 0x0C;
+// TIMEOUTA and TIMEOUTB are 12 bits wide and count periods of 2048 kernel clocks
+const SMBUS_MAX_TIMEOUT_COUNT: u64 = 0xFFF;
+const SMBUS_TIMEOUT_CLOCK_DIVIDER: u64 = 2048;
+
+pub struct Smbus {
+    pub i2c: I2C
+}
+
+pub struct SmbusConfig {
+    pub pec: bool,
+    pub alert_pin: Option<I2cPin>,
+    // SCL low timeout, tTIMEOUT is 25 ms in the SMBus specification
+    pub clock_low_timeout_us: Option<u32>,
+    // Cumulative clock low extension, tLOW:MEXT is 10 ms for a host and tLOW:SEXT 25 ms for a device
+    pub clock_extension_timeout_us: Option<u32>,
+    // Device mode with PEC: data bytes following the command code, the PEC byte comes after them
+    pub device_data_length: fn(command: u8, read: bool) -> u8
+}
+
+impl Smbus {
+    pub fn new(i2c: I2C) -> Smbus {
+        Smbus {
+            i2c
+        }
+    }
+
+    pub fn begin(&self, config: I2cConfig, smbus_config: SmbusConfig) -> Result<(), I2cError> {
+        self.i2c.begin(config)?;
+        self.configure_smbus(&smbus_config, false)
+    }
+
+    // Device mode reuses the slave register map, the command code is the register.
+    // With PEC the slave counts the bytes (SBC) so the hardware checks or sends the PEC after device_data_length bytes.
+    pub fn begin_device(&self, config: I2cConfig, slave_config: I2cSlaveConfig, smbus_config: SmbusConfig) -> Result<(), I2cError> {
+        self.i2c.begin_slave(config, slave_config)?;
+        unsafe {
+            SLAVE_STATES[self.i2c.instance.index()].pec_data_length = smbus_config.device_data_length;
+        }
+        self.configure_smbus(&smbus_config, true)
+    }
+
+    pub fn quick_command(&self, address: u8, read: bool) -> Result<(), I2cError> {
+        // The R/W bit is the only data, there is no PEC
+        let direction = if read {
+            I2cTransferDirection::MasterRequestARead
+        } else {
+            I2cTransferDirection::MasterRequestAWrite
+        };
+        self.i2c.wait_while_busy()?;
+        self.i2c.master_request(address, 0, direction, true);
+        self.i2c.wait_for_stop_condition()
+    }
+
+    pub fn send_byte(&self, address: u8, value: u8) -> Result<(), I2cError> {
+        self.transaction(address, &[value], &mut [])
+    }
+
+    pub fn receive_byte(&self, address: u8) -> Result<u8, I2cError> {
+        let mut buffer = [0u8; 1];
+        self.transaction(address, &[], &mut buffer)?;
+        Ok(buffer[0])
+    }
+
+    pub fn write_byte(&self, address: u8, command: u8, value: u8) -> Result<(), I2cError> {
+        self.transaction(address, &[command, value], &mut [])
+    }
+
+    pub fn read_byte(&self, address: u8, command: u8) -> Result<u8, I2cError> {
+        let mut buffer = [0u8; 1];
+        self.transaction(address, &[command], &mut buffer)?;
+        Ok(buffer[0])
+    }
+
+    // Words are sent low byte first
+    pub fn write_word(&self, address: u8, command: u8, value: u16) -> Result<(), I2cError> {
+        let [low, high] = value.to_le_bytes();
+        self.transaction(address, &[command, low, high], &mut [])
+    }
+
+    pub fn read_word(&self, address: u8, command: u8) -> Result<u16, I2cError> {
+        let mut buffer = [0u8; 2];
+        self.transaction(address, &[command], &mut buffer)?;
+        Ok(u16::from_le_bytes(buffer))
+    }
+
+    pub fn process_call(&self, address: u8, command: u8, value: u16) -> Result<u16, I2cError> {
+        let [low, high] = value.to_le_bytes();
+        let mut buffer = [0u8; 2];
+        self.transaction(address, &[command, low, high], &mut buffer)?;
+        Ok(u16::from_le_bytes(buffer))
+    }
+
+    pub fn block_write(&self, address: u8, command: u8, data: &[u8]) -> Result<(), I2cError> {
+        if data.len() > u8::MAX as usize {
+            return Err(I2cError::InvalidLength)
+        }
+        let mut frame = Vec::with_capacity(data.len() + 2);
+        frame.push(command);
+        frame.push(data.len() as u8);
+        frame.extend_from_slice(data);
+        self.transaction(address, &frame, &mut [])
+    }
+
+    pub fn block_read(&self, address: u8, command: u8) -> Result<Vec<u8>, I2cError> {
+        let pec = self.pec_enabled();
+        let pec_bytes = pec.as_u32() as usize;
+
+        self.i2c.wait_while_busy()?;
+        self.i2c.master_request(address, 1, I2cTransferDirection::MasterRequestAWrite, false);
+        self.i2c.write_data(&[command], false)?;
+        self.i2c.wait_for_transfer_complete()?;
+
+        unsafe {
+            let port = self.i2c.instance.registers();
+            // The byte count is read first with RELOAD bit 24 set, NBYTES is updated once the count is known
+            port.cr2.as_ptr().write(
+                ((address as u32 & 0x7F) << 1)
+                    | (I2cTransferDirection::MasterRequestARead.as_u32() << 10)
+                    | (1 << 16)
+                    | (1 << 24)
+                    | (pec.as_u32() << 26)
+                    | (1 << 13)
+            );
+        }
+        let count = self.i2c.read_from_rx_register()? as usize;
+
+        let mut block = vec![0; count];
+
+        self.i2c.reload_next_chunk(count + pec_bytes, true)?;
+        if count + pec_bytes == 0 {
+            self.i2c.stop_condition();
+        }
+        self.i2c.read_chunks(&mut block, true, pec_bytes)?;
+        self.finish_reception(pec)?;
+
+        Ok(block)
+    }
+
+    pub fn alert_pending(&self) -> bool {
+        unsafe {
+            let port = self.i2c.instance.registers();
+            // ALERT bit 13 in I2C_ISR is set on a falling edge of SMBALERT
+            (port.isr.as_ptr().read() & (1 << 13)) > 0
+        }
+    }
+
+    // Returns the address of the device that pulled SMBALERT low
+    pub fn alert_response(&self) -> Result<Option<u8>, I2cError> {
+        if !self.alert_pending() {
+            return Ok(None)
+        }
+        unsafe {
+            let port = self.i2c.instance.registers();
+            // Write ALERTCF bit 13 in I2C_ICR
+            port.icr.as_ptr().write(1 << 13);
+        }
+        let mut buffer = [0u8; 1];
+        self.i2c.read(SMBUS_ALERT_RESPONSE_ADDRESS, &mut buffer)?;
+        // The device address is sent in the 7 most significant bits
+        Ok(Some(buffer[0] >> 1))
+    }
+
+    // In device mode ALERTEN drives SMBALERT low, the host then reads the alert response address
+    pub fn set_alert(&self, active: bool){
+        unsafe {
+            let port = self.i2c.instance.registers();
+            // ALERTEN bit 22 in I2C_CR1
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !(1 << 22));
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() | (active.as_u32() << 22));
+        }
+    }
+
+    pub fn pec_enabled(&self) -> bool {
+        unsafe {
+            let port = self.i2c.instance.registers();
+            // PECEN bit 23 in I2C_CR1
+            (port.cr1.as_ptr().read() & (1 << 23)) > 0
+        }
+    }
+
+    /* Private methods */
+
+    fn transaction(&self, address: u8, data: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        let pec = self.pec_enabled();
+        let pec_bytes = pec.as_u32() as usize;
+
+        self.i2c.wait_while_busy()?;
+
+        if buffer.is_empty() {
+            self.i2c.master_request_with_pec(address, data.len() + pec_bytes, I2cTransferDirection::MasterRequestAWrite, true, pec);
+            self.i2c.write_chunks(data, true, pec_bytes)?;
+            return self.i2c.wait_for_stop_condition()
+        }
+
+        if !data.is_empty() {
+            // The PEC of a read covers the command phase too, it is only sent at the end of the read phase
+            self.i2c.master_request(address, data.len(), I2cTransferDirection::MasterRequestAWrite, false);
+            self.i2c.write_data(data, false)?;
+            self.i2c.wait_for_transfer_complete()?;
+        }
+
+        self.i2c.master_request_with_pec(address, buffer.len() + pec_bytes, I2cTransferDirection::MasterRequestARead, true, pec);
+        self.i2c.read_chunks(buffer, true, pec_bytes)?;
+        self.finish_reception(pec)
+    }
+
+    fn finish_reception(&self, pec: bool) -> Result<(), I2cError> {
+        if pec {
+            // The PEC byte is compared with I2C_PECR by the hardware, PECERR is set on a mismatch
+            self.i2c.read_from_rx_register()?;
+        }
+        self.i2c.wait_for_stop_condition()?;
+        self.i2c.check_error_flags()
+    }
+
+    fn configure_smbus(&self, smbus_config: &SmbusConfig, device: bool) -> Result<(), I2cError> {
+        let timeout = self.timeout_register(smbus_config)?;
+
+        // In host mode SMBALERT is an input, a device drives it only while an alert is pending
+        let host_alert = smbus_config.alert_pin.is_some() && !device;
+        if let Some(alert_pin) = &smbus_config.alert_pin {
+            alert_pin.configure();
+        }
+
+        unsafe {
+            let port = self.i2c.instance.registers();
+            // PECEN bit 23 and the SMBus modes can only be programmed with PE bit 0 in I2C_CR1 cleared
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !(1 << 0));
+            // Clear TCIE bit 6, SBC bit 16, SMBHEN bit 20, SMBDEN bit 21, ALERTEN bit 22 and PECEN bit 23 in I2C_CR1
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !((1 << 6) | (1 << 16) | (1 << 20) | (1 << 21) | (1 << 22) | (1 << 23)));
+            // A device with PEC controls NBYTES itself, TCR stops the transfer once the command code is received
+            let slave_byte_control = device && smbus_config.pec;
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read()
+                | (slave_byte_control.as_u32() << 6)
+                | (slave_byte_control.as_u32() << 16)
+                | ((!device).as_u32() << 20)
+                | (device.as_u32() << 21)
+                | (host_alert.as_u32() << 22)
+                | (smbus_config.pec.as_u32() << 23));
+
+            // TIMOUTEN bit 15 and TEXTEN bit 31 must be cleared before writing I2C_TIMEOUTR
+            port.timeoutr.as_ptr().write(0);
+            port.timeoutr.as_ptr().write(timeout);
+        }
+
+        self.i2c.enable_peripheral();
+        Ok(())
+    }
+
+    fn timeout_register(&self, smbus_config: &SmbusConfig) -> Result<u32, I2cError> {
+        let i2c_clock = self.i2c.kernel_clock_freq();
+        let mut timeout = 0u32;
+
+        if let Some(timeout_us) = smbus_config.clock_low_timeout_us {
+            // TIMEOUTA bits 11:0 with TIDLE bit 12 cleared, TIMOUTEN bit 15
+            timeout |= timeout_count(timeout_us, i2c_clock)? | (1 << 15);
+        }
+        if let Some(timeout_us) = smbus_config.clock_extension_timeout_us {
+            // TIMEOUTB bits 27:16, TEXTEN bit 31
+            timeout |= (timeout_count(timeout_us, i2c_clock)? << 16) | (1 << 31);
+        }
+        Ok(timeout)
+    }
+}
+
+impl SmbusConfig {
+    pub fn new() -> SmbusConfig {
+        SmbusConfig {
+            pec: true,
+            alert_pin: None,
+            clock_low_timeout_us: Some(25000),
+            clock_extension_timeout_us: None,
+            device_data_length: byte_data_length
+        }
+    }
+}
+
+// Write Byte and Read Byte, one data byte follows the command code
+fn byte_data_length(_command: u8, _read: bool) -> u8 {
+    1
+}
+
+// t = (TIMEOUT + 1) * 2048 * tI2CCLK
+fn timeout_count(timeout_us: u32, i2c_clock: u32) -> Result<u32, I2cError> {
+    let periods = (timeout_us as u64 * i2c_clock as u64) / (SMBUS_TIMEOUT_CLOCK_DIVIDER * 1000000);
+    if periods == 0 || periods - 1 > SMBUS_MAX_TIMEOUT_COUNT {
+        return Err(I2cError::InvalidConfiguration)
+    }
+    Ok((periods - 1) as u32)
+}
@@ -0,0 +1,420 @@
+// 10-bit addressing of the I2C master, the peripheral sends the header and the second address byte itself
+// but the restart sequence depends on how I2C_CR2 is programmed.
+// The I2C_CR2 encoding is shared with the 7-bit, SMBus and interrupt transfers of the driver.
+
+// NBYTES is 8 bits wide, longer transfers are split using RELOAD
+pub const MAX_NBYTES: usize = 255;
+
+// I2C_ISR flags waited on by the transfers
+const ISR_TXIS: u32 = 1;
+const ISR_RXNE: u32 = 2;
+const ISR_TC: u32 = 6;
+const ISR_TCR: u32 = 7;
+
+// Register access needed by the transfers, the firmware implements it on the peripheral
+pub trait I2cRegisters {
+    type Error;
+
+    fn read_cr2(&self) -> u32;
+    fn write_cr2(&self, value: u32);
+    fn write_txdr(&self, data: u8);
+    fn read_rxdr(&self) -> u8;
+    // Waits for a flag of I2C_ISR, an error flag such as NACKF ends the wait
+    fn wait_for_flag(&self, bit: u32) -> Result<(), Self::Error>;
+    // Waits for STOPF and clears it, a NACK received during the transfer is reported
+    fn wait_for_stop_condition(&self) -> Result<(), Self::Error>;
+}
+
+// The peripheral must be idle, the caller waits for BUSY to be cleared
+pub fn write<R: I2cRegisters>(registers: &R, address: u16, data: &[u8]) -> Result<(), R::Error> {
+    registers.write_cr2(request(address, data.len(), false, true, false));
+    write_data(registers, data, true)?;
+    registers.wait_for_stop_condition()
+}
+
+// A read that does not follow a write always sends the complete 10-bit address
+pub fn read<R: I2cRegisters>(registers: &R, address: u16, buffer: &mut [u8]) -> Result<(), R::Error> {
+    registers.write_cr2(request(address, buffer.len(), true, true, false));
+    read_data(registers, buffer, true)?;
+    registers.wait_for_stop_condition()
+}
+
+// The slave is still addressed after the write, with header_only the repeated start only sends the header with the read direction
+pub fn write_read<R: I2cRegisters>(registers: &R, address: u16, data: &[u8], buffer: &mut [u8], header_only: bool) -> Result<(), R::Error> {
+    // Write phase without AUTOEND, TC is set once every byte is sent
+    registers.write_cr2(request(address, data.len(), false, false, false));
+    write_data(registers, data, false)?;
+    registers.wait_for_flag(ISR_TC)?;
+
+    registers.write_cr2(request(address, buffer.len(), true, true, header_only));
+    read_data(registers, buffer, true)?;
+    registers.wait_for_stop_condition()
+}
+
+// I2C_CR2 value starting a 10-bit transfer
+pub fn request(address: u16, number_of_bytes: usize, read: bool, autoend: bool, header_only: bool) -> u32 {
+    // SADD[9:0] with ADD10 bit 11
+    transfer_request((address as u32 & 0x3FF) | (1 << 11), number_of_bytes, read, autoend, header_only)
+}
+
+// address_bits holds SADD and ADD10 already encoded, so 7-bit requests are built here too.
+// Writing the whole register also clears the previous NBYTES.
+pub fn transfer_request(address_bits: u32, number_of_bytes: usize, read: bool, autoend: bool, header_only: bool) -> u32 {
+    // RD_WRN bit 10, HEAD10R bit 12, START bit 13
+    let request = address_bits | ((read as u32) << 10) | ((header_only as u32) << 12) | (1 << 13);
+    with_number_of_bytes(request, number_of_bytes, autoend)
+}
+
+// Loads the next chunk into a I2C_CR2 value, RELOAD while more chunks follow and AUTOEND only on the last one
+pub fn with_number_of_bytes(cr2: u32, remaining: usize, autoend: bool) -> u32 {
+    let reload = remaining > MAX_NBYTES;
+    // NBYTES bits 23:16, RELOAD bit 24, AUTOEND bit 25
+    (cr2 & !((0xFF << 16) | (1 << 24) | (1 << 25)))
+        | ((remaining.min(MAX_NBYTES) as u32) << 16)
+        | ((reload as u32) << 24)
+        | (((autoend && !reload) as u32) << 25)
+}
+
+fn write_data<R: I2cRegisters>(registers: &R, data: &[u8], autoend: bool) -> Result<(), R::Error> {
+    for (index, &data_slice) in data.iter().enumerate() {
+        if index > 0 && index.is_multiple_of(MAX_NBYTES) {
+            reload_next_chunk(registers, data.len() - index, autoend)?;
+        }
+        registers.wait_for_flag(ISR_TXIS)?;
+        registers.write_txdr(data_slice);
+    }
+    Ok(())
+}
+
+fn read_data<R: I2cRegisters>(registers: &R, buffer: &mut [u8], autoend: bool) -> Result<(), R::Error> {
+    let length = buffer.len();
+    for (index, data_slice) in buffer.iter_mut().enumerate() {
+        if index > 0 && index.is_multiple_of(MAX_NBYTES) {
+            reload_next_chunk(registers, length - index, autoend)?;
+        }
+        registers.wait_for_flag(ISR_RXNE)?;
+        *data_slice = registers.read_rxdr();
+    }
+    Ok(())
+}
+
+fn reload_next_chunk<R: I2cRegisters>(registers: &R, remaining: usize, autoend: bool) -> Result<(), R::Error> {
+    // SCL is stretched after TCR until NBYTES is written
+    registers.wait_for_flag(ISR_TCR)?;
+    registers.write_cr2(with_number_of_bytes(registers.read_cr2(), remaining, autoend));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    const ISR_STOPF: u32 = 5;
+
+    #[derive(PartialEq, Debug)]
+    enum Wire {
+        Start,
+        Byte(u8),
+        Nack,
+        Stop
+    }
+
+    #[derive(PartialEq, Debug)]
+    enum MockError {
+        Nack,
+        TimeOut
+    }
+
+    // Peripheral with a 10-bit slave on the bus, the wire records what the master sends and receives
+    struct Target {
+        slave_address: u16,
+        // The slave refuses the second address byte
+        refuse_second_byte: bool,
+        // A header only restart is acknowledged by the slave addressed by the previous write
+        addressed: bool,
+        read_data: VecDeque<u8>,
+        received: Vec<u8>,
+        wire: Vec<Wire>,
+        requests: Vec<u32>,
+        cr2: u32,
+        reading: bool,
+        remaining: usize,
+        nackf: bool,
+        stopf: bool
+    }
+
+    struct ScriptedTarget {
+        target: RefCell<Target>
+    }
+
+    impl ScriptedTarget {
+        fn new(slave_address: u16, read_data: &[u8]) -> ScriptedTarget {
+            ScriptedTarget {
+                target: RefCell::new(Target {
+                    slave_address,
+                    refuse_second_byte: false,
+                    addressed: false,
+                    read_data: read_data.iter().copied().collect(),
+                    received: Vec::new(),
+                    wire: Vec::new(),
+                    requests: Vec::new(),
+                    cr2: 0,
+                    reading: false,
+                    remaining: 0,
+                    nackf: false,
+                    stopf: false
+                })
+            }
+        }
+    }
+
+    impl Target {
+        fn header(&self, address: u16, read: bool) -> u8 {
+            0xF0 | (((address >> 7) as u8) & 0x06) | read as u8
+        }
+
+        fn start(&mut self, cr2: u32) {
+            let address = (cr2 & 0x3FF) as u16;
+            let read = (cr2 & (1 << 10)) > 0;
+            let header_only = (cr2 & (1 << 12)) > 0;
+            self.requests.push(cr2);
+            self.wire.push(Wire::Start);
+
+            if read && header_only {
+                self.wire.push(Wire::Byte(self.header(address, true)));
+                if !self.addressed || address != self.slave_address {
+                    return self.refuse()
+                }
+            } else {
+                self.wire.push(Wire::Byte(self.header(address, false)));
+                if (address >> 8) != (self.slave_address >> 8) {
+                    return self.refuse()
+                }
+                self.wire.push(Wire::Byte(address as u8));
+                if self.refuse_second_byte || address != self.slave_address {
+                    return self.refuse()
+                }
+                if read {
+                    self.wire.push(Wire::Start);
+                    self.wire.push(Wire::Byte(self.header(address, true)));
+                }
+            }
+
+            self.addressed = true;
+            self.reading = read;
+            self.load_chunk();
+        }
+
+        // The STOP is sent automatically after the NACK
+        fn refuse(&mut self) {
+            self.wire.push(Wire::Nack);
+            self.end();
+            self.nackf = true;
+        }
+
+        fn load_chunk(&mut self) {
+            self.remaining = ((self.cr2 >> 16) & 0xFF) as usize;
+            if self.remaining == 0 {
+                self.chunk_done();
+            }
+        }
+
+        fn chunk_done(&mut self) {
+            let reload = (self.cr2 & (1 << 24)) > 0;
+            let autoend = (self.cr2 & (1 << 25)) > 0;
+            if !reload && autoend {
+                self.end();
+            }
+        }
+
+        fn end(&mut self) {
+            self.wire.push(Wire::Stop);
+            self.stopf = true;
+            self.addressed = false;
+        }
+
+        fn byte_done(&mut self) {
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                self.chunk_done();
+            }
+        }
+
+        fn flag(&self, bit: u32) -> bool {
+            let reload = (self.cr2 & (1 << 24)) > 0;
+            let autoend = (self.cr2 & (1 << 25)) > 0;
+            match bit {
+                ISR_TXIS => !self.reading && self.remaining > 0,
+                ISR_RXNE => self.reading && self.remaining > 0,
+                ISR_STOPF => self.stopf,
+                ISR_TC => self.remaining == 0 && !reload && !autoend && !self.stopf,
+                ISR_TCR => self.remaining == 0 && reload,
+                _ => false
+            }
+        }
+    }
+
+    impl I2cRegisters for ScriptedTarget {
+        type Error = MockError;
+
+        fn read_cr2(&self) -> u32 {
+            self.target.borrow().cr2
+        }
+
+        fn write_cr2(&self, value: u32) {
+            let mut target = self.target.borrow_mut();
+            // START bit 13 is cleared by the hardware once the address is sent
+            target.cr2 = value & !(1 << 13);
+            if (value & (1 << 13)) > 0 {
+                target.start(value);
+            } else {
+                target.load_chunk();
+            }
+        }
+
+        fn write_txdr(&self, data: u8) {
+            let mut target = self.target.borrow_mut();
+            target.wire.push(Wire::Byte(data));
+            target.received.push(data);
+            target.byte_done();
+        }
+
+        fn read_rxdr(&self) -> u8 {
+            let mut target = self.target.borrow_mut();
+            let data = target.read_data.pop_front().unwrap();
+            target.wire.push(Wire::Byte(data));
+            target.byte_done();
+            data
+        }
+
+        fn wait_for_flag(&self, bit: u32) -> Result<(), MockError> {
+            let mut target = self.target.borrow_mut();
+            if target.nackf && bit != ISR_STOPF {
+                // Same as the firmware, the NACK and the following STOP are cleared
+                target.nackf = false;
+                target.stopf = false;
+                return Err(MockError::Nack)
+            }
+            if target.flag(bit) {
+                Ok(())
+            } else {
+                Err(MockError::TimeOut)
+            }
+        }
+
+        fn wait_for_stop_condition(&self) -> Result<(), MockError> {
+            let mut target = self.target.borrow_mut();
+            if !target.stopf {
+                return Err(MockError::TimeOut)
+            }
+            target.stopf = false;
+            if target.nackf {
+                target.nackf = false;
+                return Err(MockError::Nack)
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_sends_header_and_second_address_byte() {
+        let bus = ScriptedTarget::new(0x2A5, &[]);
+        assert_eq!(write(&bus, 0x2A5, &[0x11, 0x22]), Ok(()));
+
+        let target = bus.target.borrow();
+        assert_eq!(target.wire, vec![Wire::Start, Wire::Byte(0xF4), Wire::Byte(0xA5), Wire::Byte(0x11), Wire::Byte(0x22), Wire::Stop]);
+        assert_eq!(target.received, vec![0x11, 0x22]);
+    }
+
+    #[test]
+    fn read_sends_complete_address_then_restarts_with_read_header() {
+        let bus = ScriptedTarget::new(0x2A5, &[0x33, 0x44, 0x55]);
+        let mut buffer = [0u8; 3];
+        assert_eq!(read(&bus, 0x2A5, &mut buffer), Ok(()));
+        assert_eq!(buffer, [0x33, 0x44, 0x55]);
+
+        let target = bus.target.borrow();
+        assert_eq!(target.requests.len(), 1);
+        assert_eq!(target.requests[0] & (1 << 12), 0);
+        assert_eq!(target.wire, vec![
+            Wire::Start, Wire::Byte(0xF4), Wire::Byte(0xA5),
+            Wire::Start, Wire::Byte(0xF5),
+            Wire::Byte(0x33), Wire::Byte(0x44), Wire::Byte(0x55), Wire::Stop
+        ]);
+    }
+
+    #[test]
+    fn write_read_with_head10r_only_resends_the_header() {
+        let bus = ScriptedTarget::new(0x2A5, &[0x66, 0x77]);
+        let mut buffer = [0u8; 2];
+        assert_eq!(write_read(&bus, 0x2A5, &[0x10], &mut buffer, true), Ok(()));
+        assert_eq!(buffer, [0x66, 0x77]);
+
+        let target = bus.target.borrow();
+        assert_eq!(target.requests.len(), 2);
+        // The write phase ends without AUTOEND, the read phase sets HEAD10R
+        assert_eq!(target.requests[0] & ((1 << 12) | (1 << 25)), 0);
+        assert_eq!(target.requests[1] & ((1 << 10) | (1 << 12) | (1 << 25)), (1 << 10) | (1 << 12) | (1 << 25));
+        assert_eq!(target.wire, vec![
+            Wire::Start, Wire::Byte(0xF4), Wire::Byte(0xA5), Wire::Byte(0x10),
+            Wire::Start, Wire::Byte(0xF5),
+            Wire::Byte(0x66), Wire::Byte(0x77), Wire::Stop
+        ]);
+    }
+
+    #[test]
+    fn write_read_without_head10r_resends_the_complete_address() {
+        let bus = ScriptedTarget::new(0x2A5, &[0x66]);
+        let mut buffer = [0u8; 1];
+        assert_eq!(write_read(&bus, 0x2A5, &[0x10], &mut buffer, false), Ok(()));
+
+        let target = bus.target.borrow();
+        assert_eq!(target.wire, vec![
+            Wire::Start, Wire::Byte(0xF4), Wire::Byte(0xA5), Wire::Byte(0x10),
+            Wire::Start, Wire::Byte(0xF4), Wire::Byte(0xA5),
+            Wire::Start, Wire::Byte(0xF5),
+            Wire::Byte(0x66), Wire::Stop
+        ]);
+    }
+
+    #[test]
+    fn nack_on_second_address_byte_ends_the_write() {
+        let bus = ScriptedTarget::new(0x2A5, &[]);
+        bus.target.borrow_mut().refuse_second_byte = true;
+        assert_eq!(write(&bus, 0x2A5, &[0x11]), Err(MockError::Nack));
+
+        let target = bus.target.borrow();
+        assert_eq!(target.wire, vec![Wire::Start, Wire::Byte(0xF4), Wire::Byte(0xA5), Wire::Nack, Wire::Stop]);
+        assert!(target.received.is_empty());
+    }
+
+    #[test]
+    fn request_encodes_seven_bit_addresses_too() {
+        // SADD[7:1] = 0x50, read, 2 bytes with AUTOEND
+        assert_eq!(transfer_request(0x50 << 1, 2, true, true, false), (0x50 << 1) | (1 << 10) | (1 << 13) | (2 << 16) | (1 << 25));
+    }
+
+    #[test]
+    fn chunks_keep_reload_until_the_last_one() {
+        let first = request(0x2A5, 300, false, true, false);
+        assert_eq!(first & ((0xFF << 16) | (1 << 24) | (1 << 25)), (255 << 16) | (1 << 24));
+        // The other fields, PECBYTE bit 26 included, are kept
+        let last = with_number_of_bytes(first | (1 << 26), 45, true);
+        assert_eq!(last, (first & !((0xFF << 16) | (1 << 24))) | (1 << 26) | (45 << 16) | (1 << 25));
+    }
+
+    #[test]
+    fn long_write_is_split_with_reload() {
+        let bus = ScriptedTarget::new(0x0A5, &[]);
+        let data = [0x5A; 300];
+        assert_eq!(write(&bus, 0x0A5, &data), Ok(()));
+
+        let target = bus.target.borrow();
+        assert_eq!(target.received.len(), 300);
+        assert_eq!(target.wire.last(), Some(&Wire::Stop));
+    }
+}
@@ -0,0 +1,572 @@
+#![allow(dead_code)]
+
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll, Waker};
+use cortex_m::peripheral::NVIC;
+use stm32g4::stm32g431;
+use crate::core::Conversions;
+use crate::drivers::I2C::{I2C, I2cAddress, I2cError, I2cInstance, I2cTransferDirection, I2C_MAX_NBYTES};
+
+// DMA channel registers are 20 bytes apart, starting with DMA_CCR1 at 0x08
+const DMA_CHANNEL_OFFSET: u32 = 0x08;
+const DMA_CHANNEL_STRIDE: u32 = 0x14;
+const DMA_CHANNELS_PER_CONTROLLER: u8 = 6;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DmaController {
+    DMA1,
+    DMA2
+}
+
+#[derive(Clone, Copy)]
+pub struct DmaChannel {
+    controller: DmaController,
+    // Channel number, 1 to 6, checked by DmaChannel::new
+    channel: u8
+}
+
+#[derive(Clone, Copy)]
+pub struct I2cDmaChannels {
+    pub tx: DmaChannel,
+    pub rx: DmaChannel
+}
+
+#[derive(Clone, Copy)]
+pub enum I2cTransferMode {
+    // Every byte is moved by the event interrupt
+    Interrupt,
+    // Bytes are moved by DMA, the event interrupt only handles the end of each phase
+    Dma(I2cDmaChannels)
+}
+
+// Buffers of an asynchronous transfer, they must stay valid until it ends
+pub(crate) struct I2cTransfer {
+    address: I2cAddress,
+    write_data: *const u8,
+    write_length: usize,
+    read_buffer: *mut u8,
+    read_length: usize,
+    // The bus is still owned by a previous transfer of the same transaction, it starts with a repeated start
+    repeated_start: bool,
+    // 10-bit read after a write to the same slave, only the header is sent again (HEAD10R)
+    header_only: bool,
+    // The transfer ends at TC without a STOP, the next one of the transaction follows
+    keep_bus: bool
+}
+
+#[derive(PartialEq)]
+enum I2cTransferPhase {
+    Write,
+    Read
+}
+
+struct I2cTransferState {
+    active: bool,
+    address: I2cAddress,
+    mode: I2cTransferMode,
+    phase: I2cTransferPhase,
+    write_data: *const u8,
+    write_length: usize,
+    read_buffer: *mut u8,
+    read_length: usize,
+    header_only: bool,
+    keep_bus: bool,
+    index: usize,
+    error: Option<I2cError>,
+    result: Option<Result<(), I2cError>>,
+    on_complete: Option<fn(Result<(), I2cError>)>,
+    waker: Option<Waker>
+}
+
+const IDLE_TRANSFER: I2cTransferState = I2cTransferState {
+    active: false,
+    address: I2cAddress::SevenBit(0),
+    mode: I2cTransferMode::Interrupt,
+    phase: I2cTransferPhase::Write,
+    write_data: ptr::null(),
+    write_length: 0,
+    read_buffer: ptr::null_mut(),
+    read_length: 0,
+    header_only: false,
+    keep_bus: false,
+    index: 0,
+    error: None,
+    result: None,
+    on_complete: None,
+    waker: None
+};
+
+static mut TRANSFERS: [I2cTransferState; 3] = [IDLE_TRANSFER; 3];
+
+// Resolves once the transfer of the instance ends
+pub struct I2cTransferFuture {
+    instance: I2cInstance,
+    // Set when the buffers are borrowed by the future, they must not be used after it is dropped
+    abort_on_drop: bool
+}
+
+impl I2C {
+    pub fn write_async(&self, address: u8, data: &'static [u8], mode: I2cTransferMode, on_complete: Option<fn(Result<(), I2cError>)>) -> Result<(), I2cError> {
+        unsafe {
+            self.start_transfer(I2cTransfer::new(I2cAddress::SevenBit(address), data, &mut []), mode, on_complete)
+        }
+    }
+
+    pub fn read_async(&self, address: u8, buffer: &'static mut [u8], mode: I2cTransferMode, on_complete: Option<fn(Result<(), I2cError>)>) -> Result<(), I2cError> {
+        unsafe {
+            self.start_transfer(I2cTransfer::new(I2cAddress::SevenBit(address), &[], buffer), mode, on_complete)
+        }
+    }
+
+    pub fn write_read_async(&self, address: u8, data: &'static [u8], buffer: &'static mut [u8], mode: I2cTransferMode, on_complete: Option<fn(Result<(), I2cError>)>) -> Result<(), I2cError> {
+        unsafe {
+            self.start_transfer(I2cTransfer::new(I2cAddress::SevenBit(address), data, buffer), mode, on_complete)
+        }
+    }
+
+    pub fn wait_for_transfer(&self) -> I2cTransferFuture {
+        I2cTransferFuture::new(self.instance, false)
+    }
+
+    pub fn transfer_in_progress(&self) -> bool {
+        unsafe {
+            TRANSFERS[self.instance.index()].active
+        }
+    }
+
+    pub fn abort_async_transfer(&self){
+        abort(self.instance);
+    }
+
+    // The buffers must stay valid until the transfer ends
+    pub(crate) unsafe fn start_transfer(&self, transfer: I2cTransfer, mode: I2cTransferMode, on_complete: Option<fn(Result<(), I2cError>)>) -> Result<(), I2cError> {
+        let index = self.instance.index();
+        if TRANSFERS[index].active {
+            return Err(I2cError::TransferInProgress)
+        }
+        if !transfer.repeated_start {
+            self.wait_while_busy()?;
+        }
+
+        TRANSFERS[index] = I2cTransferState {
+            active: true,
+            address: transfer.address,
+            mode,
+            phase: I2cTransferPhase::Write,
+            write_data: transfer.write_data,
+            write_length: transfer.write_length,
+            read_buffer: transfer.read_buffer,
+            read_length: transfer.read_length,
+            header_only: transfer.header_only,
+            keep_bus: transfer.keep_bus,
+            index: 0,
+            error: None,
+            result: None,
+            on_complete,
+            waker: None
+        };
+
+        let port = self.instance.registers();
+        // NACKIE bit 4, STOPIE bit 5, TCIE bit 6 and ERRIE bit 7 in I2C_CR1, TCIE also enables the TCR interrupt
+        let mut interrupts = (1 << 4) | (1 << 5) | (1 << 6) | (1 << 7);
+        match mode {
+            // TXIE bit 1 and RXIE bit 2 in I2C_CR1
+            I2cTransferMode::Interrupt => interrupts |= (1 << 1) | (1 << 2),
+            I2cTransferMode::Dma(channels) => {
+                enable_dma_clocks(channels);
+                // TXDMAEN bit 14 and RXDMAEN bit 15 in I2C_CR1
+                interrupts |= (1 << 14) | (1 << 15);
+            }
+        }
+        // After a transfer that kept the bus TC is still set, it is only cleared once START is written
+        cortex_m::interrupt::free(|_| {
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() | interrupts);
+
+            NVIC::unmask(self.instance.event_interrupt());
+            NVIC::unmask(self.instance.error_interrupt());
+
+            if transfer.write_length > 0 || transfer.read_length == 0 {
+                start_phase(self.instance, I2cTransferPhase::Write);
+            } else {
+                start_phase(self.instance, I2cTransferPhase::Read);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl I2cTransfer {
+    // An empty data slice skips the write phase, an empty buffer the read phase
+    pub(crate) fn new(address: I2cAddress, data: &[u8], buffer: &mut [u8]) -> I2cTransfer {
+        I2cTransfer {
+            address,
+            write_data: data.as_ptr(),
+            write_length: data.len(),
+            read_buffer: buffer.as_mut_ptr(),
+            read_length: buffer.len(),
+            repeated_start: false,
+            header_only: false,
+            keep_bus: false
+        }
+    }
+
+    // Part of a transaction made of several transfers, only the last one releases the bus
+    pub(crate) fn segment(self, repeated_start: bool, header_only: bool, keep_bus: bool) -> I2cTransfer {
+        I2cTransfer {
+            repeated_start,
+            header_only,
+            keep_bus,
+            ..self
+        }
+    }
+}
+
+impl I2cTransferFuture {
+    pub(crate) fn new(instance: I2cInstance, abort_on_drop: bool) -> I2cTransferFuture {
+        I2cTransferFuture {
+            instance,
+            abort_on_drop
+        }
+    }
+}
+
+impl Future for I2cTransferFuture {
+    type Output = Result<(), I2cError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let index = self.instance.index();
+        // The waker is stored with interrupts disabled so the completion cannot be missed
+        cortex_m::interrupt::free(|_| unsafe {
+            if let Some(result) = TRANSFERS[index].result.take() {
+                return Poll::Ready(result)
+            }
+            if !TRANSFERS[index].active {
+                return Poll::Ready(Ok(()))
+            }
+            TRANSFERS[index].waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for I2cTransferFuture {
+    fn drop(&mut self) {
+        if self.abort_on_drop {
+            abort(self.instance);
+        }
+    }
+}
+
+// Called from the event interrupt, returns false when no transfer is running on the instance
+pub(crate) fn transfer_event(instance: I2cInstance) -> bool {
+    unsafe {
+        let index = instance.index();
+        if !TRANSFERS[index].active {
+            return false
+        }
+
+        let port = instance.registers();
+        let isr = port.isr.as_ptr().read();
+
+        // In DMA mode TXIS and RXNE are DMA requests, the DMA moves the bytes and index counts the finished chunks
+        if let I2cTransferMode::Interrupt = TRANSFERS[index].mode {
+            // TXIS bit 1 in I2C_ISR
+            if (isr & (1 << 1)) > 0 && TRANSFERS[index].index < TRANSFERS[index].write_length {
+                port.txdr.as_ptr().write(*TRANSFERS[index].write_data.add(TRANSFERS[index].index) as u32);
+                TRANSFERS[index].index += 1;
+            }
+
+            // RXNE bit 2 in I2C_ISR
+            if (isr & (1 << 2)) > 0 && TRANSFERS[index].index < TRANSFERS[index].read_length {
+                *TRANSFERS[index].read_buffer.add(TRANSFERS[index].index) = port.rxdr.as_ptr().read() as u8;
+                TRANSFERS[index].index += 1;
+            }
+        }
+
+        // TCR bit 7 in I2C_ISR, the next chunk of NBYTES is loaded
+        if (isr & (1 << 7)) > 0 {
+            let (length, autoend) = phase_length(index);
+            // DMA moves the bytes, the finished chunks are counted instead
+            if let I2cTransferMode::Dma(_) = TRANSFERS[index].mode {
+                TRANSFERS[index].index += I2C_MAX_NBYTES;
+            }
+            instance.set_number_of_bytes(length - TRANSFERS[index].index, autoend);
+        }
+
+        // TC bit 6 in I2C_ISR, the write phase ended without AUTOEND, the read phase starts with a repeated start
+        if (isr & (1 << 6)) > 0 {
+            if TRANSFERS[index].phase == I2cTransferPhase::Write && TRANSFERS[index].read_length > 0 {
+                start_phase(instance, I2cTransferPhase::Read);
+            } else if TRANSFERS[index].keep_bus {
+                // SCL is stretched until the next transfer of the transaction writes START
+                finish(instance, Ok(()));
+            } else {
+                // Generate a STOP, STOPF ends the transfer
+                port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (1 << 14));
+            }
+        }
+
+        // NACKF bit 4 in I2C_ISR, a STOP is sent automatically after the NACK
+        if (isr & (1 << 4)) > 0 {
+            TRANSFERS[index].error = Some(if nothing_transferred(index) {
+                I2cError::AddressNotAcknowledged
+            } else {
+                I2cError::NotAcknowledgedReceived
+            });
+            // Write NACKCF bit 4 in I2C_ICR
+            port.icr.as_ptr().write(1 << 4);
+        }
+
+        // STOPF bit 5 in I2C_ISR
+        if (isr & (1 << 5)) > 0 {
+            // Write STOPCF bit 5 in I2C_ICR
+            port.icr.as_ptr().write(1 << 5);
+            let result = match TRANSFERS[index].error {
+                Some(error) => Err(error),
+                None => Ok(())
+            };
+            finish(instance, result);
+        }
+    }
+    true
+}
+
+// Called from the error interrupt, returns false when no transfer is running on the instance
+pub(crate) fn transfer_error(instance: I2cInstance) -> bool {
+    unsafe {
+        let index = instance.index();
+        if !TRANSFERS[index].active {
+            return false
+        }
+
+        let port = instance.registers();
+        let isr = port.isr.as_ptr().read();
+
+        let error = if (isr & (1 << 9)) > 0 {
+            I2cError::ArbitrationLost
+        } else if (isr & (1 << 8)) > 0 {
+            I2cError::BusError
+        } else if (isr & (1 << 10)) > 0 {
+            I2cError::OverrunUnderrun
+        } else if (isr & (1 << 11)) > 0 {
+            I2cError::PECErrorInReception
+        } else {
+            I2cError::TimeOut
+        };
+
+        // Write BERRCF bit 8, ARLOCF bit 9, OVRCF bit 10, PECCF bit 11 and TIMOUTCF bit 12 in I2C_ICR
+        port.icr.as_ptr().write((1 << 8) | (1 << 9) | (1 << 10) | (1 << 11) | (1 << 12));
+
+        // After an arbitration loss the bus is already released, otherwise the interface still owns it
+        if error != I2cError::ArbitrationLost && (port.isr.as_ptr().read() & (1 << 15)) > 0 {
+            TRANSFERS[index].error = Some(error);
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (1 << 14));
+        } else {
+            finish(instance, Err(error));
+        }
+    }
+    true
+}
+
+fn start_phase(instance: I2cInstance, phase: I2cTransferPhase){
+    unsafe {
+        let index = instance.index();
+        TRANSFERS[index].phase = phase;
+        TRANSFERS[index].index = 0;
+
+        let (length, autoend) = phase_length(index);
+        let direction = match TRANSFERS[index].phase {
+            I2cTransferPhase::Write => I2cTransferDirection::MasterRequestAWrite,
+            I2cTransferPhase::Read => I2cTransferDirection::MasterRequestARead
+        };
+
+        if let I2cTransferMode::Dma(channels) = TRANSFERS[index].mode {
+            let port = instance.registers();
+            match TRANSFERS[index].phase {
+                I2cTransferPhase::Write if length > 0 => {
+                    configure_dma_channel(channels.tx, tx_request(instance), port.txdr.as_ptr() as u32, TRANSFERS[index].write_data as u32, length, true)
+                }
+                I2cTransferPhase::Read => {
+                    configure_dma_channel(channels.rx, rx_request(instance), port.rxdr.as_ptr() as u32, TRANSFERS[index].read_buffer as u32, length, false)
+                }
+                _ => {}
+            }
+        }
+
+        let header_only = TRANSFERS[index].phase == I2cTransferPhase::Read && TRANSFERS[index].header_only;
+        instance.write_request(TRANSFERS[index].address, length, direction, autoend, false, header_only);
+    }
+}
+
+// Length of the current phase and whether it ends with AUTOEND
+fn phase_length(index: usize) -> (usize, bool) {
+    unsafe {
+        let last = !TRANSFERS[index].keep_bus;
+        match TRANSFERS[index].phase {
+            I2cTransferPhase::Write => (TRANSFERS[index].write_length, TRANSFERS[index].read_length == 0 && last),
+            I2cTransferPhase::Read => (TRANSFERS[index].read_length, last)
+        }
+    }
+}
+
+// Nothing of the phase was moved when the address was refused, a data NACK comes after at least one byte
+fn nothing_transferred(index: usize) -> bool {
+    unsafe {
+        // index counts the bytes in interrupt mode and the finished chunks in DMA mode
+        if TRANSFERS[index].index > 0 {
+            return false
+        }
+        let (length, _) = phase_length(index);
+        match TRANSFERS[index].mode {
+            I2cTransferMode::Interrupt => true,
+            // A write phase without data does not use the DMA
+            I2cTransferMode::Dma(_) if length == 0 => true,
+            I2cTransferMode::Dma(channels) => {
+                let channel = match TRANSFERS[index].phase {
+                    I2cTransferPhase::Write => channels.tx,
+                    I2cTransferPhase::Read => channels.rx
+                };
+                // DMA_CNDTRx still holds the phase length when no byte was moved
+                ptr::read_volatile(dma_channel_register(channel, 1)) as usize == length
+            }
+        }
+    }
+}
+
+fn finish(instance: I2cInstance, result: Result<(), I2cError>){
+    unsafe {
+        let index = instance.index();
+        let port = instance.registers();
+
+        // Clear TXIE bit 1, RXIE bit 2, NACKIE bit 4, STOPIE bit 5, TCIE bit 6, ERRIE bit 7, TXDMAEN bit 14 and RXDMAEN bit 15 in I2C_CR1
+        port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !((1 << 1) | (1 << 2) | (1 << 4) | (1 << 5) | (1 << 6) | (1 << 7) | (1 << 14) | (1 << 15)));
+        // Flush I2C_TXDR by setting TXE bit 0 in I2C_ISR
+        port.isr.as_ptr().write(port.isr.as_ptr().read() | (1 << 0));
+
+        if let I2cTransferMode::Dma(channels) = TRANSFERS[index].mode {
+            disable_dma_channel(channels.tx);
+            disable_dma_channel(channels.rx);
+        }
+
+        TRANSFERS[index].active = false;
+        TRANSFERS[index].result = Some(result);
+
+        if let Some(on_complete) = TRANSFERS[index].on_complete {
+            on_complete(result);
+        }
+        if let Some(waker) = TRANSFERS[index].waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+fn abort(instance: I2cInstance){
+    cortex_m::interrupt::free(|_| unsafe {
+        let index = instance.index();
+        if !TRANSFERS[index].active {
+            TRANSFERS[index].result = None;
+            return
+        }
+        let port = instance.registers();
+        // Generate a STOP if the bus is still owned by the interface
+        if (port.isr.as_ptr().read() & (1 << 15)) > 0 {
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (1 << 14));
+        }
+        finish(instance, Err(I2cError::TimeOut));
+        TRANSFERS[index].result = None;
+    })
+}
+
+fn enable_dma_clocks(channels: I2cDmaChannels){
+    unsafe {
+        let rcc = &*stm32g431::RCC::ptr();
+        // DMA1EN bit 0, DMA2EN bit 1 and DMAMUX1EN bit 2 in RCC_AHB1ENR
+        let mut enable = 1 << 2;
+        for channel in [channels.tx, channels.rx] {
+            enable |= match channel.controller {
+                DmaController::DMA1 => 1 << 0,
+                DmaController::DMA2 => 1 << 1
+            };
+        }
+        rcc.ahb1enr.as_ptr().write(rcc.ahb1enr.as_ptr().read() | enable);
+    }
+}
+
+fn configure_dma_channel(channel: DmaChannel, request: u32, peripheral_address: u32, memory_address: u32, length: usize, memory_to_peripheral: bool){
+    unsafe {
+        let dmamux = &*stm32g431::DMAMUX::ptr();
+        let ccr = dma_channel_register(channel, 0);
+
+        // EN bit 0 in DMA_CCRx must be cleared before programming the channel
+        ptr::write_volatile(ccr, ptr::read_volatile(ccr) & !(1 << 0));
+
+        // DMAREQ_ID bits 6:0 in DMAMUX_CxCR, DMAMUX channels 0 to 5 serve DMA1 and 6 to 11 serve DMA2
+        let dmamux_ccr = (dmamux as *const _ as *mut u32).add(channel.dmamux_channel());
+        ptr::write_volatile(dmamux_ccr, request & 0x7F);
+
+        // DMA_CNDTRx, DMA_CPARx and DMA_CMARx follow DMA_CCRx
+        ptr::write_volatile(dma_channel_register(channel, 1), length as u32);
+        ptr::write_volatile(dma_channel_register(channel, 2), peripheral_address);
+        ptr::write_volatile(dma_channel_register(channel, 3), memory_address);
+
+        // DIR bit 4, MINC bit 7 and EN bit 0 in DMA_CCRx, 8-bit transfers
+        ptr::write_volatile(ccr, (memory_to_peripheral.as_u32() << 4) | (1 << 7) | (1 << 0));
+    }
+}
+
+fn disable_dma_channel(channel: DmaChannel){
+    unsafe {
+        let ccr = dma_channel_register(channel, 0);
+        ptr::write_volatile(ccr, ptr::read_volatile(ccr) & !(1 << 0));
+    }
+}
+
+// Register 0 is DMA_CCRx, 1 DMA_CNDTRx, 2 DMA_CPARx and 3 DMA_CMARx
+fn dma_channel_register(channel: DmaChannel, register: u32) -> *mut u32 {
+    let base = match channel.controller {
+        DmaController::DMA1 => stm32g431::DMA1::ptr() as u32,
+        DmaController::DMA2 => stm32g431::DMA2::ptr() as u32
+    };
+    (base + DMA_CHANNEL_OFFSET + DMA_CHANNEL_STRIDE * (channel.channel as u32 - 1) + 4 * register) as *mut u32
+}
+
+// DMAMUX request lines
+fn rx_request(instance: I2cInstance) -> u32 {
+    match instance {
+        I2cInstance::I2C1 => 16,
+        I2cInstance::I2C2 => 18,
+        I2cInstance::I2C3 => 20
+    }
+}
+
+fn tx_request(instance: I2cInstance) -> u32 {
+    match instance {
+        I2cInstance::I2C1 => 17,
+        I2cInstance::I2C2 => 19,
+        I2cInstance::I2C3 => 21
+    }
+}
+
+impl DmaChannel {
+    // Channels are numbered from 1 as in the reference manual, 1 to 6 on both controllers
+    pub fn new(controller: DmaController, channel: u8) -> Result<DmaChannel, I2cError> {
+        if !(1..=DMA_CHANNELS_PER_CONTROLLER).contains(&channel) {
+            return Err(I2cError::InvalidConfiguration)
+        }
+        Ok(DmaChannel {
+            controller,
+            channel
+        })
+    }
+
+    fn dmamux_channel(&self) -> usize {
+        let offset = match self.controller {
+            DmaController::DMA1 => 0,
+            DmaController::DMA2 => DMA_CHANNELS_PER_CONTROLLER
+        };
+        (offset + self.channel - 1) as usize
+    }
+}
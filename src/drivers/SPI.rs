@@ -116,6 +116,26 @@ impl SPI {
 
 
 
+    // Returns once the byte has been clocked in, so the chip select can be released right after
+    pub fn exchange(data: u8) -> u8 {
+        unsafe {
+            let port = &*stm32g431::SPI3::ptr();
+            let dr = port.dr.as_ptr() as *mut u8;
+
+            // Empty the RX FIFO (FRLVL bits 10:9) so the received byte matches the transmitted one
+            while (port.sr.as_ptr().read_volatile() >> 9) & 0b11 != 0 {
+                dr.read_volatile();
+            }
+
+            while port.sr.read().txe().bit_is_clear() { };
+            dr.write_volatile(data);
+
+            // RXNE needs FRXTH for 8-bit frames, the FIFO level is checked instead
+            while (port.sr.as_ptr().read_volatile() >> 9) & 0b11 == 0 { };
+            dr.read_volatile()
+        }
+    }
+
     pub fn set_settings(spi_settings: SpiSettings){
         Self::set_clock_divider(spi_settings.spi_clock_divider);
         Self::set_frame_format(spi_settings.spi_frame_format);
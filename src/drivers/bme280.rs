@@ -0,0 +1,411 @@
+#![allow(dead_code)]
+
+use crate::core::delay::delay_us;
+use crate::drivers::gpio::Gpio;
+use crate::drivers::I2C::{I2C, I2cError};
+use crate::drivers::SPI::SPI;
+
+// The compensation is part of the host-tested library
+pub use stm32g431xx::drivers::bme280::compensation::{
+    compensate_humidity, compensate_pressure, compensate_t_fine, compensate_temperature, Bme280Calibration
+};
+
+// SDO pin low, 0x77 with SDO high
+pub const BME280_ADDRESS_SDO_LOW: u8 = 0x76;
+pub const BME280_ADDRESS_SDO_HIGH: u8 = 0x77;
+
+const BMP280_CHIP_ID: u8 = 0x58;
+const BME280_CHIP_ID: u8 = 0x60;
+const SOFT_RESET_COMMAND: u8 = 0xB6;
+
+const REGISTER_CALIBRATION_TP: u8 = 0x88;
+const REGISTER_CHIP_ID: u8 = 0xD0;
+const REGISTER_RESET: u8 = 0xE0;
+const REGISTER_CALIBRATION_H: u8 = 0xE1;
+const REGISTER_CTRL_HUM: u8 = 0xF2;
+const REGISTER_STATUS: u8 = 0xF3;
+const REGISTER_CTRL_MEAS: u8 = 0xF4;
+const REGISTER_CONFIG: u8 = 0xF5;
+const REGISTER_DATA: u8 = 0xF7;
+
+// Startup time is 2 ms, the calibration copy ends well before
+const RESET_DELAY_US: u32 = 2000;
+const POLL_INTERVAL_US: u32 = 100;
+// Margin added to the maximum measurement time before giving up
+const MEASUREMENT_TIMEOUT_MARGIN_US: u32 = 10000;
+
+pub enum Bme280Interface<'a> {
+    I2c(&'a I2C, u8),
+    // SPI3 must be started in mode 0 or 3 by the caller, the chip select is driven here
+    Spi(Gpio)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Bme280Chip {
+    Bmp280,
+    Bme280
+}
+
+pub enum Bme280Error {
+    Bus(I2cError),
+    UnknownChipId(u8),
+    InvalidConfiguration,
+    TimeOut
+}
+
+#[derive(Clone, Copy)]
+pub enum Bme280Oversampling {
+    Skipped,
+    X1,
+    X2,
+    X4,
+    X8,
+    X16
+}
+
+#[derive(Clone, Copy)]
+pub enum Bme280Filter {
+    Off,
+    X2,
+    X4,
+    X8,
+    X16
+}
+
+// Inactive time between two measurements in normal mode, the last four values depend on the chip
+#[derive(Clone, Copy)]
+pub enum Bme280Standby {
+    Ms0_5,
+    Ms62_5,
+    Ms125,
+    Ms250,
+    Ms500,
+    Ms1000,
+    // BME280 only
+    Ms10,
+    Ms20,
+    // BMP280 only
+    Ms2000,
+    Ms4000
+}
+
+pub struct Bme280Config {
+    pub temperature_oversampling: Bme280Oversampling,
+    pub pressure_oversampling: Bme280Oversampling,
+    // Ignored on the BMP280
+    pub humidity_oversampling: Bme280Oversampling,
+    pub filter: Bme280Filter,
+    pub standby: Bme280Standby
+}
+
+impl Bme280Config {
+    pub fn new() -> Bme280Config {
+        Bme280Config {
+            temperature_oversampling: Bme280Oversampling::X1,
+            pressure_oversampling: Bme280Oversampling::X1,
+            humidity_oversampling: Bme280Oversampling::X1,
+            filter: Bme280Filter::Off,
+            standby: Bme280Standby::Ms1000
+        }
+    }
+}
+
+pub struct Bme280Measurement {
+    // 0.01 degC
+    pub temperature: i32,
+    // Pa in Q24.8 format
+    pub pressure: u32,
+    // %RH in Q22.10 format, None on the BMP280 or when skipped
+    pub humidity: Option<u32>
+}
+
+impl Bme280Measurement {
+    pub fn temperature_celsius(&self) -> f32 {
+        self.temperature as f32 / 100.0
+    }
+
+    pub fn pressure_pa(&self) -> f32 {
+        self.pressure as f32 / 256.0
+    }
+
+    pub fn humidity_percent(&self) -> Option<f32> {
+        self.humidity.map(|humidity| humidity as f32 / 1024.0)
+    }
+}
+
+pub struct Bme280<'a> {
+    pub interface: Bme280Interface<'a>,
+    chip: Bme280Chip,
+    calibration: Bme280Calibration,
+    // Oversampling bits of CTRL_MEAS, the mode bits are added when a measurement is started
+    ctrl_meas: u8,
+    measurement_time_us: u32,
+    humidity_enabled: bool
+}
+
+impl<'a> Bme280<'a> {
+    pub fn new(interface: Bme280Interface<'a>) -> Bme280<'a> {
+        Bme280 {
+            interface,
+            chip: Bme280Chip::Bme280,
+            calibration: Bme280Calibration::default(),
+            ctrl_meas: 0,
+            measurement_time_us: 0,
+            humidity_enabled: false
+        }
+    }
+
+    // Identifies the chip, resets it, reads the calibration and leaves it in sleep mode
+    pub fn begin(&mut self, config: Bme280Config) -> Result<(), Bme280Error> {
+        if let Bme280Interface::Spi(chip_select) = &self.interface {
+            chip_select.high();
+        }
+
+        self.chip = match self.read_register(REGISTER_CHIP_ID)? {
+            BMP280_CHIP_ID => Bme280Chip::Bmp280,
+            BME280_CHIP_ID => Bme280Chip::Bme280,
+            chip_id => return Err(Bme280Error::UnknownChipId(chip_id))
+        };
+
+        self.write_register(REGISTER_RESET, SOFT_RESET_COMMAND)?;
+        delay_us(RESET_DELAY_US);
+        // im_update bit 0 is set while the NVM is copied to the image registers
+        self.wait_while_status(0x01, RESET_DELAY_US)?;
+
+        self.read_calibration()?;
+        self.configure(config)
+    }
+
+    pub fn chip(&self) -> Bme280Chip {
+        self.chip
+    }
+
+    pub fn calibration(&self) -> &Bme280Calibration {
+        &self.calibration
+    }
+
+    // The chip is put in sleep mode, CONFIG is ignored by the sensor in normal mode
+    pub fn configure(&mut self, config: Bme280Config) -> Result<(), Bme280Error> {
+        let standby = match (config.standby, self.chip) {
+            (Bme280Standby::Ms0_5, _) => 0b000,
+            (Bme280Standby::Ms62_5, _) => 0b001,
+            (Bme280Standby::Ms125, _) => 0b010,
+            (Bme280Standby::Ms250, _) => 0b011,
+            (Bme280Standby::Ms500, _) => 0b100,
+            (Bme280Standby::Ms1000, _) => 0b101,
+            (Bme280Standby::Ms10, Bme280Chip::Bme280) => 0b110,
+            (Bme280Standby::Ms20, Bme280Chip::Bme280) => 0b111,
+            (Bme280Standby::Ms2000, Bme280Chip::Bmp280) => 0b110,
+            (Bme280Standby::Ms4000, Bme280Chip::Bmp280) => 0b111,
+            _ => return Err(Bme280Error::InvalidConfiguration)
+        };
+
+        self.write_register(REGISTER_CTRL_MEAS, 0)?;
+
+        // t_sb bits 7:5, filter bits 4:2, spi3w_en bit 0 left cleared
+        self.write_register(REGISTER_CONFIG, (standby << 5) | (config.filter.code() << 2))?;
+
+        self.humidity_enabled = false;
+        let mut humidity_samples = 0;
+        if let Bme280Chip::Bme280 = self.chip {
+            // osrs_h bits 2:0, applied by the next CTRL_MEAS write
+            self.write_register(REGISTER_CTRL_HUM, config.humidity_oversampling.code())?;
+            humidity_samples = config.humidity_oversampling.samples();
+            self.humidity_enabled = humidity_samples > 0;
+        }
+
+        // osrs_t bits 7:5, osrs_p bits 4:2, mode bits 1:0 left in sleep mode
+        self.ctrl_meas = (config.temperature_oversampling.code() << 5) | (config.pressure_oversampling.code() << 2);
+        self.write_register(REGISTER_CTRL_MEAS, self.ctrl_meas)?;
+
+        self.measurement_time_us = measurement_time_us(
+            config.temperature_oversampling.samples(),
+            config.pressure_oversampling.samples(),
+            humidity_samples
+        );
+        Ok(())
+    }
+
+    // Single-shot measurement, the chip goes back to sleep mode on its own
+    pub fn measure_forced(&self) -> Result<Bme280Measurement, Bme280Error> {
+        self.write_register(REGISTER_CTRL_MEAS, self.ctrl_meas | 0b01)?;
+        delay_us(self.measurement_time_us);
+        // measuring bit 3
+        self.wait_while_status(0x08, MEASUREMENT_TIMEOUT_MARGIN_US)?;
+        self.read_measurement()
+    }
+
+    // Measures continuously with the configured standby time
+    pub fn start_normal_mode(&self) -> Result<(), Bme280Error> {
+        self.write_register(REGISTER_CTRL_MEAS, self.ctrl_meas | 0b11)
+    }
+
+    pub fn sleep(&self) -> Result<(), Bme280Error> {
+        self.write_register(REGISTER_CTRL_MEAS, self.ctrl_meas)
+    }
+
+    // Reads the last conversion, all data registers are read in one burst so they belong to the same measurement
+    pub fn read_measurement(&self) -> Result<Bme280Measurement, Bme280Error> {
+        let mut data = [0u8; 8];
+        let length = if let Bme280Chip::Bme280 = self.chip { 8 } else { 6 };
+        self.read_registers(REGISTER_DATA, &mut data[..length])?;
+
+        // Pressure and temperature are 20-bit, msb, lsb and xlsb bits 7:4
+        let adc_p = ((data[0] as i32) << 12) | ((data[1] as i32) << 4) | ((data[2] as i32) >> 4);
+        let adc_t = ((data[3] as i32) << 12) | ((data[4] as i32) << 4) | ((data[5] as i32) >> 4);
+        let adc_h = ((data[6] as i32) << 8) | data[7] as i32;
+
+        let t_fine = compensate_t_fine(&self.calibration, adc_t);
+        let humidity = if self.humidity_enabled {
+            Some(compensate_humidity(&self.calibration, t_fine, adc_h))
+        } else {
+            None
+        };
+
+        Ok(Bme280Measurement {
+            temperature: compensate_temperature(t_fine),
+            pressure: compensate_pressure(&self.calibration, t_fine, adc_p),
+            humidity
+        })
+    }
+
+    /* Private methods */
+
+    fn read_calibration(&mut self) -> Result<(), Bme280Error> {
+        // 0x88 to 0x9F, little endian words, then dig_H1 at 0xA1
+        let mut tp = [0u8; 26];
+        self.read_registers(REGISTER_CALIBRATION_TP, &mut tp)?;
+        let word = |index: usize| u16::from_le_bytes([tp[index], tp[index + 1]]);
+
+        let mut calibration = Bme280Calibration {
+            dig_t1: word(0),
+            dig_t2: word(2) as i16,
+            dig_t3: word(4) as i16,
+            dig_p1: word(6),
+            dig_p2: word(8) as i16,
+            dig_p3: word(10) as i16,
+            dig_p4: word(12) as i16,
+            dig_p5: word(14) as i16,
+            dig_p6: word(16) as i16,
+            dig_p7: word(18) as i16,
+            dig_p8: word(20) as i16,
+            dig_p9: word(22) as i16,
+            ..Bme280Calibration::default()
+        };
+
+        if let Bme280Chip::Bme280 = self.chip {
+            // 0xE1 to 0xE7, dig_H4 and dig_H5 are 12-bit values sharing 0xE5
+            let mut h = [0u8; 7];
+            self.read_registers(REGISTER_CALIBRATION_H, &mut h)?;
+            calibration.dig_h1 = tp[25];
+            calibration.dig_h2 = i16::from_le_bytes([h[0], h[1]]);
+            calibration.dig_h3 = h[2];
+            calibration.dig_h4 = ((h[3] as i8 as i16) << 4) | (h[4] & 0x0F) as i16;
+            calibration.dig_h5 = ((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16;
+            calibration.dig_h6 = h[6] as i8;
+        }
+
+        self.calibration = calibration;
+        Ok(())
+    }
+
+    fn wait_while_status(&self, mask: u8, timeout_us: u32) -> Result<(), Bme280Error> {
+        let mut elapsed_us = 0u32;
+        while (self.read_register(REGISTER_STATUS)? & mask) > 0 {
+            if elapsed_us >= timeout_us {
+                return Err(Bme280Error::TimeOut)
+            }
+            delay_us(POLL_INTERVAL_US);
+            elapsed_us += POLL_INTERVAL_US;
+        }
+        Ok(())
+    }
+
+    fn read_register(&self, register: u8) -> Result<u8, Bme280Error> {
+        let mut value = [0u8; 1];
+        self.read_registers(register, &mut value)?;
+        Ok(value[0])
+    }
+
+    fn read_registers(&self, register: u8, buffer: &mut [u8]) -> Result<(), Bme280Error> {
+        match &self.interface {
+            Bme280Interface::I2c(bus, address) => {
+                bus.write_read(*address, &[register], buffer).map_err(Bme280Error::Bus)
+            }
+            Bme280Interface::Spi(chip_select) => {
+                // Bit 7 of the control byte set for a read, the address auto-increments
+                chip_select.low();
+                SPI::exchange(register | 0x80);
+                for data in buffer.iter_mut() {
+                    *data = SPI::exchange(0);
+                }
+                chip_select.high();
+                Ok(())
+            }
+        }
+    }
+
+    fn write_register(&self, register: u8, value: u8) -> Result<(), Bme280Error> {
+        match &self.interface {
+            Bme280Interface::I2c(bus, address) => {
+                bus.write(*address, &[register, value]).map_err(Bme280Error::Bus)
+            }
+            Bme280Interface::Spi(chip_select) => {
+                // Bit 7 of the control byte cleared for a write
+                chip_select.low();
+                SPI::exchange(register & 0x7F);
+                SPI::exchange(value);
+                chip_select.high();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Bme280Oversampling {
+    fn code(&self) -> u8 {
+        match self {
+            Bme280Oversampling::Skipped => 0b000,
+            Bme280Oversampling::X1 => 0b001,
+            Bme280Oversampling::X2 => 0b010,
+            Bme280Oversampling::X4 => 0b011,
+            Bme280Oversampling::X8 => 0b100,
+            Bme280Oversampling::X16 => 0b101
+        }
+    }
+
+    fn samples(&self) -> u32 {
+        match self {
+            Bme280Oversampling::Skipped => 0,
+            Bme280Oversampling::X1 => 1,
+            Bme280Oversampling::X2 => 2,
+            Bme280Oversampling::X4 => 4,
+            Bme280Oversampling::X8 => 8,
+            Bme280Oversampling::X16 => 16
+        }
+    }
+}
+
+impl Bme280Filter {
+    fn code(&self) -> u8 {
+        match self {
+            Bme280Filter::Off => 0b000,
+            Bme280Filter::X2 => 0b001,
+            Bme280Filter::X4 => 0b010,
+            Bme280Filter::X8 => 0b011,
+            Bme280Filter::X16 => 0b100
+        }
+    }
+}
+
+// Maximum measurement time from the datasheet, 1.25 ms plus 2.3 ms per sample and 0.575 ms per enabled pressure or humidity
+fn measurement_time_us(temperature_samples: u32, pressure_samples: u32, humidity_samples: u32) -> u32 {
+    let mut time_us = 1250 + 2300 * temperature_samples;
+    if pressure_samples > 0 {
+        time_us += 2300 * pressure_samples + 575;
+    }
+    if humidity_samples > 0 {
+        time_us += 2300 * humidity_samples + 575;
+    }
+    time_us
+}
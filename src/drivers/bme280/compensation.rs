@@ -0,0 +1,130 @@
+// Trimming parameters stored in the sensor NVM
+#[derive(Clone, Copy, Default)]
+pub struct Bme280Calibration {
+    pub dig_t1: u16,
+    pub dig_t2: i16,
+    pub dig_t3: i16,
+    pub dig_p1: u16,
+    pub dig_p2: i16,
+    pub dig_p3: i16,
+    pub dig_p4: i16,
+    pub dig_p5: i16,
+    pub dig_p6: i16,
+    pub dig_p7: i16,
+    pub dig_p8: i16,
+    pub dig_p9: i16,
+    pub dig_h1: u8,
+    pub dig_h2: i16,
+    pub dig_h3: u8,
+    pub dig_h4: i16,
+    pub dig_h5: i16,
+    pub dig_h6: i8
+}
+
+// Fine temperature shared by the three compensations, the formulas below are the datasheet fixed-point ones
+pub fn compensate_t_fine(calibration: &Bme280Calibration, adc_t: i32) -> i32 {
+    let dig_t1 = calibration.dig_t1 as i32;
+    let var1 = (((adc_t >> 3) - (dig_t1 << 1)) * calibration.dig_t2 as i32) >> 11;
+    let var2 = (((((adc_t >> 4) - dig_t1) * ((adc_t >> 4) - dig_t1)) >> 12) * calibration.dig_t3 as i32) >> 14;
+    var1 + var2
+}
+
+// 0.01 degC
+pub fn compensate_temperature(t_fine: i32) -> i32 {
+    (t_fine * 5 + 128) >> 8
+}
+
+// Pa in Q24.8 format
+pub fn compensate_pressure(calibration: &Bme280Calibration, t_fine: i32, adc_p: i32) -> u32 {
+    let mut var1 = t_fine as i64 - 128000;
+    let mut var2 = var1 * var1 * calibration.dig_p6 as i64;
+    var2 += (var1 * calibration.dig_p5 as i64) << 17;
+    var2 += (calibration.dig_p4 as i64) << 35;
+    var1 = ((var1 * var1 * calibration.dig_p3 as i64) >> 8) + ((var1 * calibration.dig_p2 as i64) << 12);
+    var1 = (((1i64 << 47) + var1) * calibration.dig_p1 as i64) >> 33;
+    if var1 == 0 {
+        // Avoids a division by zero
+        return 0
+    }
+    let mut p = 1048576 - adc_p as i64;
+    p = (((p << 31) - var2) * 3125) / var1;
+    var1 = (calibration.dig_p9 as i64 * (p >> 13) * (p >> 13)) >> 25;
+    var2 = (calibration.dig_p8 as i64 * p) >> 19;
+    (((p + var1 + var2) >> 8) + ((calibration.dig_p7 as i64) << 4)) as u32
+}
+
+// %RH in Q22.10 format
+pub fn compensate_humidity(calibration: &Bme280Calibration, t_fine: i32, adc_h: i32) -> u32 {
+    let mut v = t_fine - 76800;
+    v = (((adc_h << 14) - ((calibration.dig_h4 as i32) << 20) - (calibration.dig_h5 as i32 * v) + 16384) >> 15)
+        * (((((((v * calibration.dig_h6 as i32) >> 10) * (((v * calibration.dig_h3 as i32) >> 11) + 32768)) >> 10)
+        + 2097152) * calibration.dig_h2 as i32 + 8192) >> 14);
+    v -= ((((v >> 15) * (v >> 15)) >> 7) * calibration.dig_h1 as i32) >> 4;
+    (v.clamp(0, 419430400) >> 12) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Trimming parameters of the datasheet example, the humidity ones are typical values of a BME280
+    const CALIBRATION: Bme280Calibration = Bme280Calibration {
+        dig_t1: 27504,
+        dig_t2: 26435,
+        dig_t3: -1000,
+        dig_p1: 36477,
+        dig_p2: -10685,
+        dig_p3: 3024,
+        dig_p4: 2855,
+        dig_p5: 140,
+        dig_p6: -7,
+        dig_p7: 15500,
+        dig_p8: -14600,
+        dig_p9: 6000,
+        dig_h1: 75,
+        dig_h2: 362,
+        dig_h3: 0,
+        dig_h4: 313,
+        dig_h5: 50,
+        dig_h6: 30
+    };
+
+    const ADC_T: i32 = 519888;
+    const ADC_P: i32 = 415148;
+
+    #[test]
+    fn temperature_matches_datasheet_example() {
+        let t_fine = compensate_t_fine(&CALIBRATION, ADC_T);
+        assert_eq!(t_fine, 128422);
+        assert_eq!(compensate_temperature(t_fine), 2508);
+    }
+
+    #[test]
+    fn pressure_matches_datasheet_example() {
+        let t_fine = compensate_t_fine(&CALIBRATION, ADC_T);
+        let pressure = compensate_pressure(&CALIBRATION, t_fine, ADC_P);
+        // 100653.27 Pa in the datasheet, the 64-bit integer formula is within a few LSB of Q24.8
+        assert!((pressure as f64 / 256.0 - 100653.27).abs() < 0.05, "pressure {}", pressure);
+    }
+
+    #[test]
+    fn pressure_without_calibration_is_zero() {
+        let calibration = Bme280Calibration::default();
+        assert_eq!(compensate_pressure(&calibration, 128422, ADC_P), 0);
+    }
+
+    #[test]
+    fn humidity_matches_floating_point_formula() {
+        let t_fine = compensate_t_fine(&CALIBRATION, ADC_T);
+        // 55.0007 and 82.6941 %RH with the floating point formula of the datasheet
+        assert_eq!(compensate_humidity(&CALIBRATION, t_fine, 30000), 56317);
+        assert_eq!(compensate_humidity(&CALIBRATION, t_fine, 35000), 84675);
+    }
+
+    #[test]
+    fn humidity_is_clamped() {
+        let t_fine = compensate_t_fine(&CALIBRATION, ADC_T);
+        assert_eq!(compensate_humidity(&CALIBRATION, t_fine, 0), 0);
+        assert_eq!(compensate_humidity(&CALIBRATION, t_fine, 0xFFFF), 100 << 10);
+    }
+}
@@ -0,0 +1,214 @@
+#![allow(dead_code)]
+
+use crate::core::delay::delay_us;
+use crate::drivers::I2C::{I2C, I2cError};
+
+// 1010 A2 A1 A0
+const EEPROM_BASE_ADDRESS: u8 = 0x50;
+// Write cycle time is 5 ms on most parts, 10 ms on the oldest ones
+const EEPROM_WRITE_CYCLE_TIMEOUT_US: u32 = 10000;
+const EEPROM_POLL_INTERVAL_US: u32 = 100;
+// Largest page, AT24C512 and M24512
+const EEPROM_MAX_PAGE_SIZE: usize = 128;
+const EEPROM_BLOCK_SIZE: u32 = 256;
+
+pub enum EepromError {
+    Bus(I2cError),
+    OutOfRange,
+    // The device did not acknowledge again after a page write
+    WriteTimeOut
+}
+
+pub enum EepromModel {
+    At24c01,
+    At24c02,
+    At24c04,
+    At24c08,
+    At24c16,
+    At24c32,
+    At24c64,
+    At24c128,
+    At24c256,
+    At24c512,
+    M24c01,
+    M24c02,
+    M24c04,
+    M24c08,
+    M24c16,
+    M24c32,
+    M24c64,
+    M24128,
+    M24256,
+    M24512
+}
+
+pub struct Eeprom<'a> {
+    pub bus: &'a I2C,
+    pub model: EepromModel,
+    // Level of the A2, A1 and A0 pins, the ones used as block select bits are ignored
+    pub address_pins: u8
+}
+
+impl<'a> Eeprom<'a> {
+    pub fn new(bus: &'a I2C, model: EepromModel, address_pins: u8) -> Eeprom<'a> {
+        Eeprom {
+            bus,
+            model,
+            address_pins
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.model.size()
+    }
+
+    pub fn read_byte(&self, address: u32) -> Result<u8, EepromError> {
+        let mut buffer = [0u8; 1];
+        self.read(address, &mut buffer)?;
+        Ok(buffer[0])
+    }
+
+    pub fn write_byte(&self, address: u32, value: u8) -> Result<(), EepromError> {
+        self.write(address, &[value])
+    }
+
+    pub fn read(&self, address: u32, buffer: &mut [u8]) -> Result<(), EepromError> {
+        self.check_range(address, buffer.len())?;
+
+        // Parts with a single address byte are read block by block, the block is selected in the device address
+        let chunk_size = if self.model.address_bytes() == 1 {
+            EEPROM_BLOCK_SIZE
+        } else {
+            self.size()
+        };
+
+        let mut offset = 0usize;
+        while offset < buffer.len() {
+            let current = address + offset as u32;
+            let length = ((chunk_size - current % chunk_size) as usize).min(buffer.len() - offset);
+
+            let mut memory_address = [0u8; 2];
+            let memory_address = self.memory_address(current, &mut memory_address);
+
+            self.bus.write_read(self.device_address(current), memory_address, &mut buffer[offset..offset + length])
+                .map_err(EepromError::Bus)?;
+            offset += length;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, address: u32, data: &[u8]) -> Result<(), EepromError> {
+        self.check_range(address, data.len())?;
+
+        let page_size = self.model.page_size();
+        let mut frame = [0u8; EEPROM_MAX_PAGE_SIZE + 2];
+
+        let mut offset = 0usize;
+        while offset < data.len() {
+            let current = address + offset as u32;
+            // A page write wraps around inside the page, writes are split on page boundaries
+            let length = ((page_size - current % page_size) as usize).min(data.len() - offset);
+
+            let address_length = self.memory_address(current, &mut frame).len();
+            frame[address_length..address_length + length].copy_from_slice(&data[offset..offset + length]);
+
+            let device_address = self.device_address(current);
+            self.bus.write(device_address, &frame[..address_length + length]).map_err(EepromError::Bus)?;
+            self.wait_for_write_cycle(device_address)?;
+
+            offset += length;
+        }
+        Ok(())
+    }
+
+    /* Private methods */
+
+    // The device does not acknowledge its address until the internal write cycle ends
+    fn wait_for_write_cycle(&self, device_address: u8) -> Result<(), EepromError> {
+        let mut elapsed_us = 0u32;
+        loop {
+            match self.bus.write(device_address, &[]) {
+                Ok(()) => return Ok(()),
+                Err(I2cError::NotAcknowledgedReceived) => {}
+                Err(error) => return Err(EepromError::Bus(error))
+            }
+            if elapsed_us >= EEPROM_WRITE_CYCLE_TIMEOUT_US {
+                return Err(EepromError::WriteTimeOut)
+            }
+            delay_us(EEPROM_POLL_INTERVAL_US);
+            elapsed_us += EEPROM_POLL_INTERVAL_US;
+        }
+    }
+
+    fn device_address(&self, address: u32) -> u8 {
+        let block_mask = self.model.block_select_mask();
+        // Block select bits replace the address pins on the smaller parts
+        let block = ((address / EEPROM_BLOCK_SIZE) as u8) & block_mask;
+        EEPROM_BASE_ADDRESS | (self.address_pins & 0x7 & !block_mask) | block
+    }
+
+    fn memory_address<'b>(&self, address: u32, buffer: &'b mut [u8]) -> &'b [u8] {
+        if self.model.address_bytes() == 2 {
+            buffer[0] = (address >> 8) as u8;
+            buffer[1] = address as u8;
+            &buffer[..2]
+        } else {
+            buffer[0] = address as u8;
+            &buffer[..1]
+        }
+    }
+
+    fn check_range(&self, address: u32, length: usize) -> Result<(), EepromError> {
+        if address as u64 + length as u64 > self.size() as u64 {
+            return Err(EepromError::OutOfRange)
+        }
+        Ok(())
+    }
+}
+
+impl EepromModel {
+    pub fn size(&self) -> u32 {
+        match self {
+            EepromModel::At24c01 | EepromModel::M24c01 => 128,
+            EepromModel::At24c02 | EepromModel::M24c02 => 256,
+            EepromModel::At24c04 | EepromModel::M24c04 => 512,
+            EepromModel::At24c08 | EepromModel::M24c08 => 1024,
+            EepromModel::At24c16 | EepromModel::M24c16 => 2048,
+            EepromModel::At24c32 | EepromModel::M24c32 => 4096,
+            EepromModel::At24c64 | EepromModel::M24c64 => 8192,
+            EepromModel::At24c128 | EepromModel::M24128 => 16384,
+            EepromModel::At24c256 | EepromModel::M24256 => 32768,
+            EepromModel::At24c512 | EepromModel::M24512 => 65536
+        }
+    }
+
+    pub fn page_size(&self) -> u32 {
+        match self {
+            EepromModel::At24c01 | EepromModel::At24c02 => 8,
+            EepromModel::At24c04 | EepromModel::At24c08 | EepromModel::At24c16 => 16,
+            EepromModel::M24c01 | EepromModel::M24c02 | EepromModel::M24c04 | EepromModel::M24c08 | EepromModel::M24c16 => 16,
+            EepromModel::At24c32 | EepromModel::At24c64 | EepromModel::M24c32 | EepromModel::M24c64 => 32,
+            EepromModel::At24c128 | EepromModel::At24c256 | EepromModel::M24128 | EepromModel::M24256 => 64,
+            EepromModel::At24c512 | EepromModel::M24512 => 128
+        }
+    }
+
+    // Parts up to 16 Kbit use one memory address byte
+    pub fn address_bytes(&self) -> usize {
+        if self.size() <= 2048 {
+            1
+        } else {
+            2
+        }
+    }
+
+    // Device address bits used as the upper memory address bits
+    fn block_select_mask(&self) -> u8 {
+        match self.size() {
+            512 => 0b001,
+            1024 => 0b011,
+            2048 => 0b111,
+            _ => 0
+        }
+    }
+}
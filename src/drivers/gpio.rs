@@ -1,6 +1,8 @@
 #![allow(dead_code)]
 
+use cortex_m::peripheral::NVIC;
 use stm32g4::stm32g431;
+use stm32g4::stm32g431::interrupt;
 
 pub enum MODER {
     Input,
@@ -87,6 +89,15 @@ pub enum GPIOPORT {
     GPIOG,
 }
 
+pub enum ExtiTrigger {
+    RisingEdge,
+    FallingEdge,
+    BothEdges
+}
+
+// EXTI lines 0 to 15, a line is shared by the pins with the same number on every port
+static mut EXTI_CALLBACKS: [Option<fn()>; 16] = [None; 16];
+
 #[derive(Clone)]
 pub struct Gpio {
     pub port: GPIOPORT,
@@ -130,6 +141,65 @@ impl Gpio {
         }
     }
 
+    // The pin must be configured as an input, the callback runs in interrupt context
+    pub fn enable_interrupt(&self, trigger: ExtiTrigger, callback: fn()){
+        let line = self.pin_number;
+        unsafe {
+            let rcc = &*stm32g431::RCC::ptr();
+            let syscfg = &*stm32g431::SYSCFG::ptr();
+            let exti = &*stm32g431::EXTI::ptr();
+
+            // Set SYSCFGEN bit 0 in RCC_APB2ENR
+            rcc.apb2enr.as_ptr().write(rcc.apb2enr.as_ptr().read() | (1 << 0));
+
+            // Route the port to the line, 4 bits per line in SYSCFG_EXTICR1..4
+            let exticr = match line / 4 {
+                0 => syscfg.exticr1.as_ptr(),
+                1 => syscfg.exticr2.as_ptr(),
+                2 => syscfg.exticr3.as_ptr(),
+                _ => syscfg.exticr4.as_ptr()
+            };
+            let shift = (line % 4) * 4;
+            exticr.write((exticr.read() & !(0xF << shift)) | (self.port.index() << shift));
+
+            EXTI_CALLBACKS[line as usize] = Some(callback);
+
+            // Rising and falling trigger selection in EXTI_RTSR1 and EXTI_FTSR1
+            let (rising, falling) = match trigger {
+                ExtiTrigger::RisingEdge => (true, false),
+                ExtiTrigger::FallingEdge => (false, true),
+                ExtiTrigger::BothEdges => (true, true)
+            };
+            if rising {
+                exti.rtsr1.as_ptr().write(exti.rtsr1.as_ptr().read() | (1 << line));
+            } else {
+                exti.rtsr1.as_ptr().write(exti.rtsr1.as_ptr().read() & !(1 << line));
+            }
+            if falling {
+                exti.ftsr1.as_ptr().write(exti.ftsr1.as_ptr().read() | (1 << line));
+            } else {
+                exti.ftsr1.as_ptr().write(exti.ftsr1.as_ptr().read() & !(1 << line));
+            }
+
+            // Clear a stale pending bit by writing 1 in EXTI_PR1, then unmask the line in EXTI_IMR1
+            exti.pr1.as_ptr().write(1 << line);
+            exti.imr1.as_ptr().write(exti.imr1.as_ptr().read() | (1 << line));
+
+            NVIC::unmask(exti_interrupt(line));
+        }
+    }
+
+    // The NVIC vector stays enabled, EXTI9_5 and EXTI15_10 may serve other lines
+    pub fn disable_interrupt(&self){
+        let line = self.pin_number;
+        unsafe {
+            let exti = &*stm32g431::EXTI::ptr();
+            exti.imr1.as_ptr().write(exti.imr1.as_ptr().read() & !(1 << line));
+            exti.pr1.as_ptr().write(1 << line);
+            EXTI_CALLBACKS[line as usize] = None;
+        }
+    }
+
     fn enable_in_rcc(&self){
         unsafe  {
             let rcc = &*stm32g431::RCC::ptr();
@@ -153,6 +223,7 @@ fn configure_in_gpio_a(config: GpioConfig, pin_number: u32){
         port.moder.as_ptr().write(port.moder.as_ptr().read() & !(0x3 << (pin_number*2)));
         port.moder.as_ptr().write(port.moder.as_ptr().read() | (config.moder.to_u32() << (pin_number*2)));
         // Set OTYPER
+        port.otyper.as_ptr().write(port.otyper.as_ptr().read() & !(0x1 << pin_number));
         port.otyper.as_ptr().write(port.otyper.as_ptr().read() | (config.otyper.to_u32() << pin_number));
         // Set OSPEEDR
         port.ospeedr.as_ptr().write(port.ospeedr.as_ptr().read() & !(0x3 << (pin_number*2)));
@@ -163,8 +234,10 @@ fn configure_in_gpio_a(config: GpioConfig, pin_number: u32){
 
         if config.alf_func_sel.is_some() {
             if pin_number > 7 {
+                port.afrh.as_ptr().write(port.afrh.as_ptr().read() & !(0xF << ((pin_number * 4) - 32)));
                 port.afrh.as_ptr().write(port.afrh.as_ptr().read() | ((config.alf_func_sel.unwrap() as u32) << ((pin_number * 4) - 32)))
             } else {
+                port.afrl.as_ptr().write(port.afrl.as_ptr().read() & !(0xF << (pin_number * 4)));
                 port.afrl.as_ptr().write(port.afrl.as_ptr().read() | ((config.alf_func_sel.unwrap() as u32) << (pin_number * 4)))
             }
         }
@@ -178,6 +251,7 @@ fn configure_in_gpio_b(config: GpioConfig, pin_number: u32){
         port.moder.as_ptr().write(port.moder.as_ptr().read() & !(0x3 << (pin_number*2)));
         port.moder.as_ptr().write(port.moder.as_ptr().read() | (config.moder.to_u32() << (pin_number*2)));
         // Set OTYPER
+        port.otyper.as_ptr().write(port.otyper.as_ptr().read() & !(0x1 << pin_number));
         port.otyper.as_ptr().write(port.otyper.as_ptr().read() | (config.otyper.to_u32() << pin_number));
         // Set OSPEEDR
         port.ospeedr.as_ptr().write(port.ospeedr.as_ptr().read() & !(0x3 << (pin_number*2)));
@@ -188,8 +262,10 @@ fn configure_in_gpio_b(config: GpioConfig, pin_number: u32){
 
         if config.alf_func_sel.is_some() {
             if pin_number > 7 {
+                port.afrh.as_ptr().write(port.afrh.as_ptr().read() & !(0xF << ((pin_number * 4) - 32)));
                 port.afrh.as_ptr().write(port.afrh.as_ptr().read() | ((config.alf_func_sel.unwrap() as u32) << ((pin_number * 4) - 32)))
             } else {
+                port.afrl.as_ptr().write(port.afrl.as_ptr().read() & !(0xF << (pin_number * 4)));
                 port.afrl.as_ptr().write(port.afrl.as_ptr().read() | ((config.alf_func_sel.unwrap() as u32) << (pin_number * 4)))
             }
         }
@@ -203,6 +279,7 @@ fn configure_in_gpio_c(config: GpioConfig, pin_number: u32){
         port.moder.as_ptr().write(port.moder.as_ptr().read() & !(0x3 << (pin_number*2)));
         port.moder.as_ptr().write(port.moder.as_ptr().read() | (config.moder.to_u32() << (pin_number*2)));
         // Set OTYPER
+        port.otyper.as_ptr().write(port.otyper.as_ptr().read() & !(0x1 << pin_number));
         port.otyper.as_ptr().write(port.otyper.as_ptr().read() | (config.otyper.to_u32() << pin_number));
         // Set OSPEEDR
         port.ospeedr.as_ptr().write(port.ospeedr.as_ptr().read() & !(0x3 << (pin_number*2)));
@@ -213,8 +290,10 @@ fn configure_in_gpio_c(config: GpioConfig, pin_number: u32){
 
         if config.alf_func_sel.is_some() {
             if pin_number > 7 {
+                port.afrh.as_ptr().write(port.afrh.as_ptr().read() & !(0xF << ((pin_number * 4) - 32)));
                 port.afrh.as_ptr().write(port.afrh.as_ptr().read() | ((config.alf_func_sel.unwrap() as u32) << ((pin_number * 4) - 32)))
             } else {
+                port.afrl.as_ptr().write(port.afrl.as_ptr().read() & !(0xF << (pin_number * 4)));
                 port.afrl.as_ptr().write(port.afrl.as_ptr().read() | ((config.alf_func_sel.unwrap() as u32) << (pin_number * 4)))
             }
         }
@@ -272,3 +351,81 @@ fn read_in_gpio_c(pin_number: u32) -> bool {
         (port.idr.as_ptr().read() & (1 << pin_number)) > 0
     }
 }
+
+impl GPIOPORT {
+    // Port selection value of the SYSCFG_EXTICR fields
+    fn index(&self) -> u32 {
+        match self {
+            GPIOPORT::GPIOA => 0,
+            GPIOPORT::GPIOB => 1,
+            GPIOPORT::GPIOC => 2,
+            GPIOPORT::GPIOD => 3,
+            GPIOPORT::GPIOE => 4,
+            GPIOPORT::GPIOF => 5,
+            GPIOPORT::GPIOG => 6,
+        }
+    }
+}
+
+fn exti_interrupt(line: u32) -> stm32g431::Interrupt {
+    match line {
+        0 => stm32g431::Interrupt::EXTI0,
+        1 => stm32g431::Interrupt::EXTI1,
+        2 => stm32g431::Interrupt::EXTI2,
+        3 => stm32g431::Interrupt::EXTI3,
+        4 => stm32g431::Interrupt::EXTI4,
+        5..=9 => stm32g431::Interrupt::EXTI9_5,
+        _ => stm32g431::Interrupt::EXTI15_10
+    }
+}
+
+fn exti_handler(first_line: u32, last_line: u32){
+    unsafe {
+        let exti = &*stm32g431::EXTI::ptr();
+        let pending = exti.pr1.as_ptr().read();
+        for line in first_line..=last_line {
+            if (pending & (1 << line)) > 0 {
+                // Write 1 in EXTI_PR1 to clear the pending bit before the callback, an edge during it is not lost
+                exti.pr1.as_ptr().write(1 << line);
+                if let Some(callback) = EXTI_CALLBACKS[line as usize] {
+                    callback();
+                }
+            }
+        }
+    }
+}
+
+#[interrupt]
+fn EXTI0() {
+    exti_handler(0, 0)
+}
+
+#[interrupt]
+fn EXTI1() {
+    exti_handler(1, 1)
+}
+
+#[interrupt]
+fn EXTI2() {
+    exti_handler(2, 2)
+}
+
+#[interrupt]
+fn EXTI3() {
+    exti_handler(3, 3)
+}
+
+#[interrupt]
+fn EXTI4() {
+    exti_handler(4, 4)
+}
+
+#[interrupt]
+fn EXTI9_5() {
+    exti_handler(5, 9)
+}
+
+#[interrupt]
+fn EXTI15_10() {
+    exti_handler(10, 15)
+}
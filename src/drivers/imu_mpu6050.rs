@@ -0,0 +1,480 @@
+#![allow(dead_code)]
+
+use core::cell::Cell;
+use crate::core::delay::delay_ms;
+use crate::drivers::gpio::{ExtiTrigger, Gpio, GpioConfig, MODER, OSPEEDR, OTYPER, PUPDR};
+use crate::drivers::I2C::{I2C, I2cError};
+
+// AD0 pin low, 0x69 with AD0 high
+pub const IMU_ADDRESS_AD0_LOW: u8 = 0x68;
+pub const IMU_ADDRESS_AD0_HIGH: u8 = 0x69;
+
+const MPU6050_WHO_AM_I_VALUE: u8 = 0x68;
+const ICM20948_WHO_AM_I_VALUE: u8 = 0xEA;
+
+// MPU6050 registers
+const MPU6050_SMPLRT_DIV: u8 = 0x19;
+const MPU6050_CONFIG: u8 = 0x1A;
+const MPU6050_GYRO_CONFIG: u8 = 0x1B;
+const MPU6050_ACCEL_CONFIG: u8 = 0x1C;
+const MPU6050_FIFO_EN: u8 = 0x23;
+const MPU6050_INT_PIN_CFG: u8 = 0x37;
+const MPU6050_INT_ENABLE: u8 = 0x38;
+const MPU6050_INT_STATUS: u8 = 0x3A;
+const MPU6050_ACCEL_XOUT_H: u8 = 0x3B;
+const MPU6050_USER_CTRL: u8 = 0x6A;
+const MPU6050_PWR_MGMT_1: u8 = 0x6B;
+const MPU6050_FIFO_COUNTH: u8 = 0x72;
+const MPU6050_FIFO_R_W: u8 = 0x74;
+const MPU6050_WHO_AM_I: u8 = 0x75;
+
+// ICM-20948 bank 0 registers
+const ICM20948_WHO_AM_I: u8 = 0x00;
+const ICM20948_USER_CTRL: u8 = 0x03;
+const ICM20948_PWR_MGMT_1: u8 = 0x06;
+const ICM20948_PWR_MGMT_2: u8 = 0x07;
+const ICM20948_INT_PIN_CFG: u8 = 0x0F;
+const ICM20948_INT_ENABLE_1: u8 = 0x11;
+const ICM20948_INT_ENABLE_2: u8 = 0x12;
+const ICM20948_INT_STATUS_1: u8 = 0x1A;
+const ICM20948_INT_STATUS_2: u8 = 0x1B;
+const ICM20948_ACCEL_XOUT_H: u8 = 0x2D;
+const ICM20948_FIFO_EN_2: u8 = 0x67;
+const ICM20948_FIFO_RST: u8 = 0x68;
+const ICM20948_FIFO_COUNTH: u8 = 0x70;
+const ICM20948_FIFO_R_W: u8 = 0x72;
+// ICM-20948 bank 2 registers
+const ICM20948_GYRO_SMPLRT_DIV: u8 = 0x00;
+const ICM20948_GYRO_CONFIG_1: u8 = 0x01;
+const ICM20948_ACCEL_SMPLRT_DIV_2: u8 = 0x11;
+const ICM20948_ACCEL_CONFIG: u8 = 0x14;
+// Present in every bank
+const ICM20948_REG_BANK_SEL: u8 = 0x7F;
+
+const RESET_DELAY_MS: u32 = 100;
+// Accelerometer then gyroscope, 3 big endian words each
+const FIFO_FRAME_SIZE: usize = 12;
+// Whole frames per I2C burst, bounded by the stack buffer
+const FIFO_FRAMES_PER_BURST: usize = 20;
+const STANDARD_GRAVITY: f32 = 9.80665;
+const DEGREES_TO_RADIANS: f32 = core::f32::consts::PI / 180.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ImuChip {
+    Mpu6050,
+    Icm20948
+}
+
+pub enum ImuError {
+    Bus(I2cError),
+    UnknownChipId(u8),
+    // Frames were lost and the FIFO content is no longer aligned, it has been reset
+    FifoOverflow
+}
+
+#[derive(Clone, Copy)]
+pub enum AccelRange {
+    G2,
+    G4,
+    G8,
+    G16
+}
+
+#[derive(Clone, Copy)]
+pub enum GyroRange {
+    Dps250,
+    Dps500,
+    Dps1000,
+    Dps2000
+}
+
+// Approximate bandwidth, each chip uses its closest setting
+#[derive(Clone, Copy)]
+pub enum ImuDlpf {
+    Hz5,
+    Hz10,
+    Hz20,
+    Hz45,
+    Hz95,
+    Hz185,
+    Hz250,
+    // Widest bandwidth on the MPU6050, filter bypassed on the ICM-20948
+    Disabled
+}
+
+pub struct ImuConfig {
+    pub accel_range: AccelRange,
+    pub gyro_range: GyroRange,
+    pub dlpf: ImuDlpf,
+    // Output rate is 1 kHz / (1 + divider) on the MPU6050 with the DLPF enabled, 1.125 kHz / (1 + divider) on the ICM-20948
+    pub sample_rate_divider: u8
+}
+
+impl ImuConfig {
+    pub fn new() -> ImuConfig {
+        ImuConfig {
+            accel_range: AccelRange::G2,
+            gyro_range: GyroRange::Dps250,
+            dlpf: ImuDlpf::Hz45,
+            sample_rate_divider: 9
+        }
+    }
+}
+
+// Acceleration in m/s^2 and angular rate in rad/s, the temperature is not stored in the FIFO
+#[derive(Clone, Copy, Default)]
+pub struct ImuSample {
+    pub accel: [f32; 3],
+    pub gyro: [f32; 3],
+    pub temperature: Option<f32>
+}
+
+pub struct Imu<'a> {
+    pub bus: &'a I2C,
+    pub address: u8,
+    chip: ImuChip,
+    accel_range: AccelRange,
+    gyro_range: GyroRange,
+    // MPU6050 INT_STATUS bits read but not consumed yet, reading the register clears all of them
+    pending_status: Cell<u8>
+}
+
+impl<'a> Imu<'a> {
+    pub fn new(bus: &'a I2C, address: u8, chip: ImuChip) -> Imu<'a> {
+        Imu {
+            bus,
+            address,
+            chip,
+            accel_range: AccelRange::G2,
+            gyro_range: GyroRange::Dps250,
+            pending_status: Cell::new(0)
+        }
+    }
+
+    // Checks WHO_AM_I, resets the chip, wakes it up on the gyroscope PLL and applies the configuration
+    pub fn begin(&mut self, config: ImuConfig) -> Result<(), ImuError> {
+        match self.chip {
+            ImuChip::Mpu6050 => {
+                self.expect_chip_id(MPU6050_WHO_AM_I, MPU6050_WHO_AM_I_VALUE)?;
+                // DEVICE_RESET bit 7 in PWR_MGMT_1
+                self.write_register(MPU6050_PWR_MGMT_1, 0x80)?;
+                delay_ms(RESET_DELAY_MS);
+                // SLEEP bit 6 cleared, CLKSEL bits 2:0 = 1 PLL with the X axis gyroscope
+                self.write_register(MPU6050_PWR_MGMT_1, 0x01)?;
+            }
+            ImuChip::Icm20948 => {
+                self.select_bank(0)?;
+                self.expect_chip_id(ICM20948_WHO_AM_I, ICM20948_WHO_AM_I_VALUE)?;
+                // DEVICE_RESET bit 7 in PWR_MGMT_1, the bank selection is reset too
+                self.write_register(ICM20948_PWR_MGMT_1, 0x80)?;
+                delay_ms(RESET_DELAY_MS);
+                // SLEEP bit 6 cleared, CLKSEL bits 2:0 = 1 best available clock
+                self.write_register(ICM20948_PWR_MGMT_1, 0x01)?;
+                // All accelerometer and gyroscope axes on
+                self.write_register(ICM20948_PWR_MGMT_2, 0x00)?;
+            }
+        }
+        self.configure(config)
+    }
+
+    pub fn chip(&self) -> ImuChip {
+        self.chip
+    }
+
+    pub fn configure(&mut self, config: ImuConfig) -> Result<(), ImuError> {
+        let accel_code = config.accel_range.code();
+        let gyro_code = config.gyro_range.code();
+
+        match self.chip {
+            ImuChip::Mpu6050 => {
+                let dlpf = match config.dlpf {
+                    ImuDlpf::Hz5 => 6,
+                    ImuDlpf::Hz10 => 5,
+                    ImuDlpf::Hz20 => 4,
+                    ImuDlpf::Hz45 => 3,
+                    ImuDlpf::Hz95 => 2,
+                    ImuDlpf::Hz185 => 1,
+                    ImuDlpf::Hz250 | ImuDlpf::Disabled => 0
+                };
+                // DLPF_CFG bits 2:0, FS_SEL and AFS_SEL bits 4:3
+                self.write_register(MPU6050_CONFIG, dlpf)?;
+                self.write_register(MPU6050_SMPLRT_DIV, config.sample_rate_divider)?;
+                self.write_register(MPU6050_GYRO_CONFIG, gyro_code << 3)?;
+                self.write_register(MPU6050_ACCEL_CONFIG, accel_code << 3)?;
+            }
+            ImuChip::Icm20948 => {
+                // DLPFCFG bits 5:3 and FCHOICE bit 0, FCHOICE cleared bypasses the filter
+                let filter = match config.dlpf {
+                    ImuDlpf::Hz5 => (6 << 3) | 1,
+                    ImuDlpf::Hz10 => (5 << 3) | 1,
+                    ImuDlpf::Hz20 => (4 << 3) | 1,
+                    ImuDlpf::Hz45 => (3 << 3) | 1,
+                    ImuDlpf::Hz95 => (2 << 3) | 1,
+                    ImuDlpf::Hz185 => (1 << 3) | 1,
+                    ImuDlpf::Hz250 => 1,
+                    ImuDlpf::Disabled => 0
+                };
+                self.select_bank(2)?;
+                // FS_SEL bits 2:1
+                self.write_register(ICM20948_GYRO_SMPLRT_DIV, config.sample_rate_divider)?;
+                self.write_register(ICM20948_GYRO_CONFIG_1, filter | (gyro_code << 1))?;
+                self.write_register(ICM20948_ACCEL_SMPLRT_DIV_2, config.sample_rate_divider)?;
+                self.write_register(ICM20948_ACCEL_CONFIG, filter | (accel_code << 1))?;
+                self.select_bank(0)?;
+            }
+        }
+
+        self.accel_range = config.accel_range;
+        self.gyro_range = config.gyro_range;
+        Ok(())
+    }
+
+    // Accelerometer, gyroscope and temperature from a single burst so they belong to the same sample
+    pub fn read(&self) -> Result<ImuSample, ImuError> {
+        let mut data = [0u8; 14];
+        match self.chip {
+            ImuChip::Mpu6050 => {
+                // Accelerometer, temperature then gyroscope
+                self.read_registers(MPU6050_ACCEL_XOUT_H, &mut data)?;
+                let temperature = word(&data, 6) as f32 / 340.0 + 36.53;
+                let mut sample = self.scale(&data[0..6], &data[8..14]);
+                sample.temperature = Some(temperature);
+                Ok(sample)
+            }
+            ImuChip::Icm20948 => {
+                // Accelerometer, gyroscope then temperature
+                self.read_registers(ICM20948_ACCEL_XOUT_H, &mut data)?;
+                let temperature = (word(&data, 12) as f32 - 21.0) / 333.87 + 21.0;
+                let mut sample = self.scale(&data[0..6], &data[6..12]);
+                sample.temperature = Some(temperature);
+                Ok(sample)
+            }
+        }
+    }
+
+    pub fn enable_fifo(&self) -> Result<(), ImuError> {
+        match self.chip {
+            ImuChip::Mpu6050 => {
+                // FIFO_EN bit 6 cleared and FIFO_RESET bit 2 set in USER_CTRL
+                self.write_register(MPU6050_USER_CTRL, 0x04)?;
+                // XG, YG, ZG bits 6:4 and ACCEL bit 3, stored in register order so the accelerometer comes first
+                self.write_register(MPU6050_FIFO_EN, 0x78)?;
+                self.write_register(MPU6050_USER_CTRL, 0x40)
+            }
+            ImuChip::Icm20948 => {
+                // FIFO_RESET bits 4:0 asserted then released
+                self.write_register(ICM20948_FIFO_RST, 0x1F)?;
+                self.write_register(ICM20948_FIFO_RST, 0x00)?;
+                // ACCEL_FIFO_EN bit 4, GYRO_Z/Y/X_FIFO_EN bits 3:1
+                self.write_register(ICM20948_FIFO_EN_2, 0x1E)?;
+                // FIFO_EN bit 6 in USER_CTRL
+                self.modify_register(ICM20948_USER_CTRL, 0, 0x40)
+            }
+        }
+    }
+
+    pub fn disable_fifo(&self) -> Result<(), ImuError> {
+        match self.chip {
+            ImuChip::Mpu6050 => {
+                self.write_register(MPU6050_FIFO_EN, 0)?;
+                self.write_register(MPU6050_USER_CTRL, 0)
+            }
+            ImuChip::Icm20948 => {
+                self.write_register(ICM20948_FIFO_EN_2, 0)?;
+                self.modify_register(ICM20948_USER_CTRL, 0x40, 0)
+            }
+        }
+    }
+
+    // Bytes waiting in the FIFO
+    pub fn fifo_count(&self) -> Result<u16, ImuError> {
+        let register = match self.chip {
+            ImuChip::Mpu6050 => MPU6050_FIFO_COUNTH,
+            ImuChip::Icm20948 => ICM20948_FIFO_COUNTH
+        };
+        let mut count = [0u8; 2];
+        self.read_registers(register, &mut count)?;
+        // Only 13 bits are used on the ICM-20948
+        Ok(u16::from_be_bytes(count) & 0x1FFF)
+    }
+
+    // Reads whole frames in bursts, returns how many samples were written
+    pub fn read_fifo(&self, samples: &mut [ImuSample]) -> Result<usize, ImuError> {
+        if self.fifo_overflowed()? {
+            self.enable_fifo()?;
+            return Err(ImuError::FifoOverflow)
+        }
+
+        let available = self.fifo_count()? as usize / FIFO_FRAME_SIZE;
+        let total = available.min(samples.len());
+        let register = match self.chip {
+            ImuChip::Mpu6050 => MPU6050_FIFO_R_W,
+            ImuChip::Icm20948 => ICM20948_FIFO_R_W
+        };
+
+        let mut burst = [0u8; FIFO_FRAME_SIZE * FIFO_FRAMES_PER_BURST];
+        let mut read = 0;
+        while read < total {
+            let frames = (total - read).min(FIFO_FRAMES_PER_BURST);
+            // The FIFO register does not auto-increment, the whole burst comes out of it
+            self.read_registers(register, &mut burst[..frames * FIFO_FRAME_SIZE])?;
+            for (index, frame) in burst[..frames * FIFO_FRAME_SIZE].chunks_exact(FIFO_FRAME_SIZE).enumerate() {
+                samples[read + index] = self.scale(&frame[0..6], &frame[6..12]);
+            }
+            read += frames;
+        }
+        Ok(read)
+    }
+
+    // The INT pin pulses high for every new sample, the callback runs in the EXTI interrupt
+    pub fn enable_data_ready_interrupt(&self, pin: &Gpio, callback: fn()) -> Result<(), ImuError> {
+        match self.chip {
+            ImuChip::Mpu6050 => {
+                // Active high, push-pull, 50 us pulse, INT_RD_CLEAR bit 4 left off so reading the samples
+                // does not clear FIFO_OFLOW_INT before fifo_overflowed reads INT_STATUS
+                self.write_register(MPU6050_INT_PIN_CFG, 0x00)?;
+                // DATA_RDY_EN bit 0, FIFO_OFLOW_EN bit 4 left off so the pin only reports new samples
+                self.write_register(MPU6050_INT_ENABLE, 0x01)?;
+            }
+            ImuChip::Icm20948 => {
+                // INT1 active high, push-pull, 50 us pulse, INT_ANYRD_2CLEAR bit 4 left off as on the MPU6050
+                self.write_register(ICM20948_INT_PIN_CFG, 0x00)?;
+                // RAW_DATA_0_RDY_EN bit 0
+                self.write_register(ICM20948_INT_ENABLE_1, 0x01)?;
+            }
+        }
+
+        pin.configure(GpioConfig {
+            moder: MODER::Input,
+            otyper: OTYPER::PushPull,
+            ospeedr: OSPEEDR::Low,
+            pupdr: PUPDR::PullDown,
+            alf_func_sel: None
+        });
+        pin.enable_interrupt(ExtiTrigger::RisingEdge, callback);
+        Ok(())
+    }
+
+    pub fn disable_data_ready_interrupt(&self, pin: &Gpio) -> Result<(), ImuError> {
+        pin.disable_interrupt();
+        match self.chip {
+            ImuChip::Mpu6050 => self.write_register(MPU6050_INT_ENABLE, 0),
+            ImuChip::Icm20948 => self.write_register(ICM20948_INT_ENABLE_1, 0)
+        }
+    }
+
+    // Reading the status clears it
+    pub fn data_ready(&self) -> Result<bool, ImuError> {
+        match self.chip {
+            // DATA_RDY_INT bit 0
+            ImuChip::Mpu6050 => self.take_status(0x01),
+            ImuChip::Icm20948 => Ok((self.read_register(ICM20948_INT_STATUS_1)? & 0x01) > 0)
+        }
+    }
+
+    /* Private methods */
+
+    fn fifo_overflowed(&self) -> Result<bool, ImuError> {
+        match self.chip {
+            // FIFO_OFLOW_INT bit 4
+            ImuChip::Mpu6050 => self.take_status(0x10),
+            // FIFO_OVERFLOW_INT bits 4:0
+            ImuChip::Icm20948 => Ok((self.read_register(ICM20948_INT_STATUS_2)? & 0x1F) > 0)
+        }
+    }
+
+    // The bits of the other callers are kept until they read them
+    fn take_status(&self, mask: u8) -> Result<bool, ImuError> {
+        let status = self.pending_status.get() | self.read_register(MPU6050_INT_STATUS)?;
+        self.pending_status.set(status & !mask);
+        Ok((status & mask) > 0)
+    }
+
+    fn scale(&self, accel: &[u8], gyro: &[u8]) -> ImuSample {
+        let accel_scale = STANDARD_GRAVITY / self.accel_range.lsb_per_g();
+        let gyro_scale = DEGREES_TO_RADIANS / self.gyro_range.lsb_per_dps();
+        let mut sample = ImuSample::default();
+        for axis in 0..3 {
+            sample.accel[axis] = word(accel, axis * 2) as f32 * accel_scale;
+            sample.gyro[axis] = word(gyro, axis * 2) as f32 * gyro_scale;
+        }
+        sample
+    }
+
+    fn expect_chip_id(&self, register: u8, expected: u8) -> Result<(), ImuError> {
+        let chip_id = self.read_register(register)?;
+        if chip_id != expected {
+            return Err(ImuError::UnknownChipId(chip_id))
+        }
+        Ok(())
+    }
+
+    // USER_BANK bits 5:4
+    fn select_bank(&self, bank: u8) -> Result<(), ImuError> {
+        self.write_register(ICM20948_REG_BANK_SEL, bank << 4)
+    }
+
+    fn read_register(&self, register: u8) -> Result<u8, ImuError> {
+        let mut value = [0u8; 1];
+        self.read_registers(register, &mut value)?;
+        Ok(value[0])
+    }
+
+    fn read_registers(&self, register: u8, buffer: &mut [u8]) -> Result<(), ImuError> {
+        self.bus.write_read(self.address, &[register], buffer).map_err(ImuError::Bus)
+    }
+
+    fn write_register(&self, register: u8, value: u8) -> Result<(), ImuError> {
+        self.bus.write(self.address, &[register, value]).map_err(ImuError::Bus)
+    }
+
+    fn modify_register(&self, register: u8, clear_mask: u8, set_mask: u8) -> Result<(), ImuError> {
+        let value = self.read_register(register)?;
+        self.write_register(register, (value & !clear_mask) | set_mask)
+    }
+}
+
+impl AccelRange {
+    fn code(&self) -> u8 {
+        match self {
+            AccelRange::G2 => 0,
+            AccelRange::G4 => 1,
+            AccelRange::G8 => 2,
+            AccelRange::G16 => 3
+        }
+    }
+
+    fn lsb_per_g(&self) -> f32 {
+        match self {
+            AccelRange::G2 => 16384.0,
+            AccelRange::G4 => 8192.0,
+            AccelRange::G8 => 4096.0,
+            AccelRange::G16 => 2048.0
+        }
+    }
+}
+
+impl GyroRange {
+    fn code(&self) -> u8 {
+        match self {
+            GyroRange::Dps250 => 0,
+            GyroRange::Dps500 => 1,
+            GyroRange::Dps1000 => 2,
+            GyroRange::Dps2000 => 3
+        }
+    }
+
+    fn lsb_per_dps(&self) -> f32 {
+        match self {
+            GyroRange::Dps250 => 131.0,
+            GyroRange::Dps500 => 65.5,
+            GyroRange::Dps1000 => 32.8,
+            GyroRange::Dps2000 => 16.4
+        }
+    }
+}
+
+// Sensor outputs are big endian two's complement words
+fn word(data: &[u8], index: usize) -> i16 {
+    i16::from_be_bytes([data[index], data[index + 1]])
+}
@@ -1,14 +1,33 @@
 #![allow(dead_code)]
 
-use crate::core::delay::non_exact_time_delay;
+use core::cell::Cell;
+use crate::core::delay::{delay_us, non_exact_time_delay};
 use crate::drivers::gpio::*;
+use crate::drivers::I2C::{I2C, I2cError};
 
 const INITIAL_DELAY: u32 = 2400000;
 const FIRST_PAUSE: u32 = 2400;
 const SECOND_PAUSE: u32 = 3200;
 const THIRD_PAUSE: u32 = 1200;
+// Clear display and return home take up to 1.52 ms
+const CLEAR_DELAY_US: u32 = 2000;
+
+// PCF8574 answers on 0100 A2 A1 A0, PCF8574A on 0111 A2 A1 A0
+pub const PCF8574_BASE_ADDRESS: u8 = 0x20;
+pub const PCF8574A_BASE_ADDRESS: u8 = 0x38;
+
+// Moves bytes to the controller, in 4-bit mode only the low nibble of data is used
+pub trait LcdTransport {
+    fn init(&self);
+    fn four_bit_mode(&self) -> bool;
+    fn write(&self, register_select: bool, data: u8);
+}
+
+pub struct Lcd<T: LcdTransport = LcdPins> {
+    pub transport: T,
+}
 
-pub struct Lcd{
+pub struct LcdPins {
     pub register_select: Gpio,
     pub read_write: Option<Gpio>, //Optional Pin
     pub enable: Gpio,
@@ -22,6 +41,41 @@ pub struct Lcd{
     pub d7: Gpio,
 }
 
+// Port bit of each LCD signal on the backpack
+pub struct Pcf8574Mapping {
+    pub register_select: u8,
+    pub read_write: u8,
+    pub enable: u8,
+    pub backlight: u8,
+    pub d4: u8,
+    pub d5: u8,
+    pub d6: u8,
+    pub d7: u8,
+    pub backlight_active_high: bool,
+}
+
+// P0 RS, P1 RW, P2 EN, P3 backlight, P4-P7 D4-D7, used by most backpacks
+pub const PCF8574_DEFAULT_MAPPING: Pcf8574Mapping = Pcf8574Mapping {
+    register_select: 0,
+    read_write: 1,
+    enable: 2,
+    backlight: 3,
+    d4: 4,
+    d5: 5,
+    d6: 6,
+    d7: 7,
+    backlight_active_high: true,
+};
+
+pub struct Pcf8574Backpack<'a> {
+    pub bus: &'a I2C,
+    pub address: u8,
+    pub mapping: Pcf8574Mapping,
+    backlight: Cell<bool>,
+    // The LCD API does not return errors, the last bus error is kept here
+    last_error: Cell<Option<I2cError>>,
+}
+
 const TYPE_GPIO_LCD_PORT: GpioConfig = GpioConfig {
     moder: MODER::GeneralPurposeOutput,
     otyper: OTYPER::PushPull,
@@ -30,10 +84,16 @@ const TYPE_GPIO_LCD_PORT: GpioConfig = GpioConfig {
     alf_func_sel: None,
 };
 
-impl Lcd {
+impl<T: LcdTransport> Lcd<T> {
+    pub fn new(transport: T) -> Lcd<T> {
+        Lcd {
+            transport
+        }
+    }
+
     pub fn configure(&self){
 
-        self.init_pins();
+        self.transport.init();
         //400000
         non_exact_time_delay(INITIAL_DELAY);
         self.send_init_commands();
@@ -54,26 +114,36 @@ impl Lcd {
     }
 
     pub fn send_character(&self, c: char){
-        if (self.d0.is_none()) | (self.d1.is_none()) | (self.d2.is_none()) | (self.d3.is_none()) {
-            self.send_character_8_bit((c as u8 >> 4) as char);
-            self.send_character_8_bit((c as u8 & 0xF ) as char);
-        } else {
-            self.send_character_8_bit(c);
-        }
+        self.send(true, c as u8);
     }
 
     pub fn send_command(&self, cmd: u8){
-        if (self.d0.is_none()) | (self.d1.is_none()) | (self.d2.is_none()) | (self.d3.is_none()) {
-            self.send_command_8_bit(cmd >> 4);
-            self.send_command_8_bit(cmd & 0xF);
+        self.send(false, cmd);
+    }
+
+    pub fn clear(&self){
+        self.send_command(0x1);
+        delay_us(CLEAR_DELAY_US);
+    }
+
+    fn send(&self, register_select: bool, data: u8) {
+        if self.transport.four_bit_mode() {
+            self.transport.write(register_select, data >> 4);
+            self.transport.write(register_select, data & 0xF);
         } else {
-            self.send_command_8_bit(cmd);
+            self.transport.write(register_select, data);
         }
     }
 
     fn send_init_commands(&self) {
-        if (self.d0.is_none()) | (self.d1.is_none()) | (self.d2.is_none()) | (self.d3.is_none()) {
-            self.send_command_8_bit(0x2);
+        if self.transport.four_bit_mode() {
+            // The controller may be in any mode after a MCU reset, 8-bit mode is forced before switching to 4-bit
+            self.transport.write(false, 0x3);
+            delay_us(4500);
+            self.transport.write(false, 0x3);
+            delay_us(150);
+            self.transport.write(false, 0x3);
+            self.transport.write(false, 0x2);
 
             self.send_command(0x28);
 
@@ -90,55 +160,38 @@ impl Lcd {
         }
 
     }
+}
 
-    fn send_character_8_bit(&self, c: char){
-        // RS -> 1
-        // RW -> 0
-        if self.read_write.is_some() {
-            let rw = self.read_write.clone().unwrap();
-            rw.low()
-        };
-        self.register_select.high();
-
-        // first pause
-        //400
-        non_exact_time_delay(FIRST_PAUSE);
-
-        // EN -> 1
-        // c -> D0 - D7
-        self.enable.high();
-        self.toggle_pins_char(c);
-
-        // second pause
-        //800
-        non_exact_time_delay(SECOND_PAUSE);
-
-        // EN -> 0
-        self.enable.low();
-
-        // third pause
-        //200
-        non_exact_time_delay(THIRD_PAUSE);
+impl LcdTransport for LcdPins {
+    fn init(&self) {
+        self.init_pins();
+    }
 
+    fn four_bit_mode(&self) -> bool {
+        (self.d0.is_none()) | (self.d1.is_none()) | (self.d2.is_none()) | (self.d3.is_none())
     }
 
-    fn send_command_8_bit(&self, cmd: u8){
-        // RS -> 0
+    fn write(&self, register_select: bool, data: u8) {
+        // RS -> 1 for characters, 0 for commands
         // RW -> 0
         if self.read_write.is_some() {
             let rw = self.read_write.clone().unwrap();
             rw.low()
         };
-        self.register_select.low();
+        if register_select {
+            self.register_select.high();
+        } else {
+            self.register_select.low();
+        }
 
         // first pause
         //400
         non_exact_time_delay(FIRST_PAUSE);
 
         // EN -> 1
-        // c -> D0 - D7
+        // data -> D0 - D7
         self.enable.high();
-        self.toggle_pins(cmd);
+        self.toggle_pins(data);
 
         // second pause
         //800
@@ -152,23 +205,16 @@ impl Lcd {
         non_exact_time_delay(THIRD_PAUSE);
 
     }
+}
 
-    pub fn clear(&self){
-        self.send_command(0x1);
-    }
-
-    fn toggle_pins_char(&self, data: char){
-        self.toggle_pins(data as u8)
-    }
-
+impl LcdPins {
     pub fn toggle_pins(&self, data: u8) {
-        if (self.d0.is_none()) | (self.d1.is_none()) | (self.d2.is_none()) | (self.d3.is_none()) {
+        if self.four_bit_mode() {
             self.toggle_pins_4_bit(data)
         } else {
             self.toggle_pins_8_bit(data);
         }
     }
-
     fn toggle_pins_8_bit(&self, data: u8) {
         if (data & 0b00000001) > 0 {
             self.d0.as_ref().unwrap().high()
@@ -238,7 +284,7 @@ impl Lcd {
 
     pub fn init_pins(&self){
 
-        if (self.d0.is_none()) | (self.d1.is_none()) | (self.d2.is_none()) | (self.d3.is_none()) {
+        if self.four_bit_mode() {
             self.init_4bit_pins()
         } else {
             self.d0.as_ref().unwrap().configure(TYPE_GPIO_LCD_PORT);
@@ -261,3 +307,96 @@ impl Lcd {
         self.d7.configure(TYPE_GPIO_LCD_PORT);
     }
 }
+
+impl<'a> Pcf8574Backpack<'a> {
+    pub fn new(bus: &'a I2C, address: u8, mapping: Pcf8574Mapping) -> Pcf8574Backpack<'a> {
+        Pcf8574Backpack {
+            bus,
+            address,
+            mapping,
+            backlight: Cell::new(true),
+            last_error: Cell::new(None),
+        }
+    }
+
+    pub fn set_backlight(&self, on: bool) {
+        self.backlight.set(on);
+        self.write_port(&[self.port_value(false, 0, false)]);
+    }
+
+    pub fn backlight(&self) -> bool {
+        self.backlight.get()
+    }
+
+    // The error stays until clear_error, a later successful write does not hide it
+    pub fn last_error(&self) -> Option<I2cError> {
+        self.last_error.get()
+    }
+
+    pub fn clear_error(&self) {
+        self.last_error.set(None);
+    }
+
+    fn port_value(&self, register_select: bool, data: u8, enable: bool) -> u8 {
+        let mapping = &self.mapping;
+        let mut value = 0u8;
+        // RW stays low, the backpack is only written
+        if register_select {
+            value |= 1 << mapping.register_select;
+        }
+        if enable {
+            value |= 1 << mapping.enable;
+        }
+        if self.backlight.get() == mapping.backlight_active_high {
+            value |= 1 << mapping.backlight;
+        }
+        if (data & 0b0001) > 0 {
+            value |= 1 << mapping.d4;
+        }
+        if (data & 0b0010) > 0 {
+            value |= 1 << mapping.d5;
+        }
+        if (data & 0b0100) > 0 {
+            value |= 1 << mapping.d6;
+        }
+        if (data & 0b1000) > 0 {
+            value |= 1 << mapping.d7;
+        }
+        value
+    }
+
+    fn write_port(&self, values: &[u8]) {
+        if let Err(error) = self.bus.write(self.address, values) {
+            self.last_error.set(Some(error));
+        }
+    }
+}
+
+impl<'a> LcdTransport for Pcf8574Backpack<'a> {
+    fn init(&self) {
+        self.write_port(&[self.port_value(false, 0, false)]);
+    }
+
+    // Only D4-D7 are wired to the expander
+    fn four_bit_mode(&self) -> bool {
+        true
+    }
+
+    fn write(&self, register_select: bool, data: u8) {
+        // Each byte updates the port, EN is pulsed by the second and third bytes.
+        // A byte takes about 90 us at 100 kHz, longer than the EN pulse and hold times
+        self.write_port(&[
+            self.port_value(register_select, data, false),
+            self.port_value(register_select, data, true),
+            self.port_value(register_select, data, false),
+        ]);
+        // Most instructions take 37 us to execute
+        delay_us(50);
+    }
+}
+
+impl<'a> Lcd<Pcf8574Backpack<'a>> {
+    pub fn set_backlight(&self, on: bool) {
+        self.transport.set_backlight(on);
+    }
+}
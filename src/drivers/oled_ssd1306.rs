@@ -0,0 +1,286 @@
+#![allow(dead_code)]
+
+use core::convert::Infallible;
+use embedded_graphics_core::pixelcolor::BinaryColor;
+use embedded_graphics_core::prelude::{DrawTarget, OriginDimensions, Pixel, Size};
+use crate::drivers::gpio::Gpio;
+use crate::drivers::I2C::{I2C, I2cError};
+use crate::drivers::SPI::SPI;
+
+// SA0 pin low, 0x3D with SA0 high
+pub const OLED_ADDRESS_SA0_LOW: u8 = 0x3C;
+pub const OLED_ADDRESS_SA0_HIGH: u8 = 0x3D;
+
+const OLED_WIDTH: usize = 128;
+const OLED_MAX_PAGES: usize = 8;
+// Control byte sent before the bytes of an I2C write, Co bit 7 cleared and D/C bit 6
+const I2C_CONTROL_COMMAND: u8 = 0x00;
+const I2C_CONTROL_DATA: u8 = 0x40;
+// The SH1106 RAM is 132 columns wide, the panel is centered on it
+const SH1106_COLUMN_OFFSET: u8 = 2;
+
+pub enum OledInterface<'a> {
+    I2c(&'a I2C, u8),
+    // SPI3 must be started by the caller, D/C low selects a command
+    Spi { data_command: Gpio, chip_select: Gpio }
+}
+
+pub enum OledController {
+    Ssd1306,
+    Sh1106
+}
+
+pub enum OledSize {
+    Size128x64,
+    Size128x32
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OledRotation {
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270
+}
+
+pub struct Oled<'a> {
+    pub interface: OledInterface<'a>,
+    pub controller: OledController,
+    pub size: OledSize,
+    rotation: OledRotation,
+    // One byte per column and page, bit 0 is the top row of the page
+    framebuffer: [u8; OLED_WIDTH * OLED_MAX_PAGES],
+    // Columns and pages touched since the last flush, inclusive
+    dirty: Option<(usize, usize, usize, usize)>
+}
+
+impl<'a> Oled<'a> {
+    pub fn new(interface: OledInterface<'a>, controller: OledController, size: OledSize) -> Oled<'a> {
+        Oled {
+            interface,
+            controller,
+            size,
+            rotation: OledRotation::Rotate0,
+            framebuffer: [0; OLED_WIDTH * OLED_MAX_PAGES],
+            dirty: None
+        }
+    }
+
+    pub fn configure(&mut self) -> Result<(), I2cError> {
+        if let OledInterface::Spi { chip_select, .. } = &self.interface {
+            chip_select.high();
+        }
+
+        let height = self.height() as u8;
+        // Display off, clock divide ratio, multiplex ratio, display offset and start line 0
+        self.send_commands(&[0xAE, 0xD5, 0x80, 0xA8, height - 1, 0xD3, 0x00, 0x40])?;
+
+        match self.controller {
+            // Charge pump enabled, page addressing mode
+            OledController::Ssd1306 => self.send_commands(&[0x8D, 0x14, 0x20, 0x02])?,
+            // DC-DC converter enabled, the SH1106 only has the page addressing mode
+            OledController::Sh1106 => self.send_commands(&[0xAD, 0x8B])?
+        }
+
+        // COM pins sequential on 32 rows and alternative on 64 rows
+        let com_pins = if height == 64 { 0x12 } else { 0x02 };
+        // COM pins, contrast, pre-charge period, VCOMH level, output follows RAM, normal display
+        self.send_commands(&[0xDA, com_pins, 0x81, 0xCF, 0xD9, 0xF1, 0xDB, 0x40, 0xA4, 0xA6])?;
+        self.set_rotation(self.rotation)?;
+
+        self.clear_buffer();
+        self.flush()?;
+        self.display_on(true)
+    }
+
+    pub fn display_on(&self, on: bool) -> Result<(), I2cError> {
+        self.send_commands(&[if on { 0xAF } else { 0xAE }])
+    }
+
+    pub fn set_contrast(&self, contrast: u8) -> Result<(), I2cError> {
+        self.send_commands(&[0x81, contrast])
+    }
+
+    pub fn invert(&self, inverted: bool) -> Result<(), I2cError> {
+        self.send_commands(&[if inverted { 0xA7 } else { 0xA6 }])
+    }
+
+    // 180 degrees is done by the controller scan directions, 90 and 270 swap the axes when drawing
+    pub fn set_rotation(&mut self, rotation: OledRotation) -> Result<(), I2cError> {
+        self.rotation = rotation;
+        let flipped = matches!(rotation, OledRotation::Rotate180 | OledRotation::Rotate270);
+        // Segment remap 0xA1 maps column 127 to SEG0, COM scan 0xC8 goes from COM[N-1] to COM0
+        if flipped {
+            self.send_commands(&[0xA0, 0xC0])?;
+        } else {
+            self.send_commands(&[0xA1, 0xC8])?;
+        }
+        // The content drawn before does not follow the new orientation
+        self.mark_all_dirty();
+        Ok(())
+    }
+
+    pub fn rotation(&self) -> OledRotation {
+        self.rotation
+    }
+
+    // Width and height seen when drawing, swapped at 90 and 270 degrees
+    pub fn dimensions(&self) -> (usize, usize) {
+        match self.rotation {
+            OledRotation::Rotate0 | OledRotation::Rotate180 => (OLED_WIDTH, self.height()),
+            OledRotation::Rotate90 | OledRotation::Rotate270 => (self.height(), OLED_WIDTH)
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
+        let (width, height) = self.dimensions();
+        if x >= width || y >= height {
+            return
+        }
+
+        let (column, row) = match self.rotation {
+            OledRotation::Rotate0 | OledRotation::Rotate180 => (x, y),
+            OledRotation::Rotate90 | OledRotation::Rotate270 => (OLED_WIDTH - 1 - y, x)
+        };
+
+        let page = row / 8;
+        let index = page * OLED_WIDTH + column;
+        let previous = self.framebuffer[index];
+        if on {
+            self.framebuffer[index] |= 1 << (row % 8);
+        } else {
+            self.framebuffer[index] &= !(1 << (row % 8));
+        }
+        if self.framebuffer[index] != previous {
+            self.mark_dirty(column, column, page, page);
+        }
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        let (width, height) = self.dimensions();
+        if x >= width || y >= height {
+            return false
+        }
+        let (column, row) = match self.rotation {
+            OledRotation::Rotate0 | OledRotation::Rotate180 => (x, y),
+            OledRotation::Rotate90 | OledRotation::Rotate270 => (OLED_WIDTH - 1 - y, x)
+        };
+        (self.framebuffer[(row / 8) * OLED_WIDTH + column] & (1 << (row % 8))) > 0
+    }
+
+    pub fn fill(&mut self, on: bool) {
+        let pages = self.pages();
+        self.framebuffer[..pages * OLED_WIDTH].fill(if on { 0xFF } else { 0x00 });
+        self.mark_all_dirty();
+    }
+
+    pub fn clear_buffer(&mut self) {
+        self.fill(false);
+    }
+
+    // Only the columns and pages changed since the last flush are sent
+    pub fn flush(&mut self) -> Result<(), I2cError> {
+        let (first_column, last_column, first_page, last_page) = match self.dirty {
+            Some(dirty) => dirty,
+            None => return Ok(())
+        };
+
+        let column = first_column as u8 + match self.controller {
+            OledController::Ssd1306 => 0,
+            OledController::Sh1106 => SH1106_COLUMN_OFFSET
+        };
+
+        for page in first_page..=last_page {
+            // Page start address, lower and higher column start address nibbles
+            self.send_commands(&[0xB0 | page as u8, column & 0x0F, 0x10 | (column >> 4)])?;
+            let start = page * OLED_WIDTH;
+            self.send_data(&self.framebuffer[start + first_column..=start + last_column])?;
+        }
+
+        self.dirty = None;
+        Ok(())
+    }
+
+    /* Private methods */
+
+    fn height(&self) -> usize {
+        match self.size {
+            OledSize::Size128x64 => 64,
+            OledSize::Size128x32 => 32
+        }
+    }
+
+    fn pages(&self) -> usize {
+        self.height() / 8
+    }
+
+    fn mark_all_dirty(&mut self) {
+        self.dirty = Some((0, OLED_WIDTH - 1, 0, self.pages() - 1));
+    }
+
+    fn mark_dirty(&mut self, first_column: usize, last_column: usize, first_page: usize, last_page: usize) {
+        self.dirty = Some(match self.dirty {
+            None => (first_column, last_column, first_page, last_page),
+            Some((c0, c1, p0, p1)) => (c0.min(first_column), c1.max(last_column), p0.min(first_page), p1.max(last_page))
+        });
+    }
+
+    fn send_commands(&self, commands: &[u8]) -> Result<(), I2cError> {
+        self.send(I2C_CONTROL_COMMAND, commands)
+    }
+
+    fn send_data(&self, data: &[u8]) -> Result<(), I2cError> {
+        self.send(I2C_CONTROL_DATA, data)
+    }
+
+    fn send(&self, control: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        match &self.interface {
+            OledInterface::I2c(bus, address) => {
+                // Control byte followed by up to one page of bytes
+                let mut frame = [0u8; OLED_WIDTH + 1];
+                frame[0] = control;
+                frame[1..=bytes.len()].copy_from_slice(bytes);
+                bus.write(*address, &frame[..=bytes.len()])
+            }
+            OledInterface::Spi { data_command, chip_select } => {
+                data_command.set(control == I2C_CONTROL_DATA);
+                chip_select.low();
+                for &byte in bytes {
+                    SPI::exchange(byte);
+                }
+                chip_select.high();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a> OriginDimensions for Oled<'a> {
+    fn size(&self) -> Size {
+        let (width, height) = self.dimensions();
+        Size::new(width as u32, height as u32)
+    }
+}
+
+// Drawing only touches the framebuffer, flush sends it to the display
+impl<'a> DrawTarget for Oled<'a> {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>
+    {
+        for Pixel(point, color) in pixels {
+            if point.x >= 0 && point.y >= 0 {
+                self.set_pixel(point.x as usize, point.y as usize, color.is_on());
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.fill(color.is_on());
+        Ok(())
+    }
+}
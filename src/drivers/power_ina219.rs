@@ -0,0 +1,373 @@
+#![allow(dead_code)]
+
+use crate::drivers::I2C::{I2C, I2cError};
+
+// A1 and A0 pins low, up to 0x4F with the other pin combinations
+pub const POWER_MONITOR_BASE_ADDRESS: u8 = 0x40;
+
+const REGISTER_CONFIGURATION: u8 = 0x00;
+const REGISTER_SHUNT_VOLTAGE: u8 = 0x01;
+const REGISTER_BUS_VOLTAGE: u8 = 0x02;
+const REGISTER_POWER: u8 = 0x03;
+const REGISTER_CURRENT: u8 = 0x04;
+const REGISTER_CALIBRATION: u8 = 0x05;
+// INA226 only
+const REGISTER_MASK_ENABLE: u8 = 0x06;
+const REGISTER_ALERT_LIMIT: u8 = 0x07;
+
+// Fixed scaling constants of the calibration equations
+const INA219_CALIBRATION_SCALE: f32 = 0.04096;
+const INA226_CALIBRATION_SCALE: f32 = 0.00512;
+const INA219_SHUNT_VOLTAGE_LSB: f32 = 10e-6;
+const INA226_SHUNT_VOLTAGE_LSB: f32 = 2.5e-6;
+const INA219_BUS_VOLTAGE_LSB: f32 = 4e-3;
+const INA226_BUS_VOLTAGE_LSB: f32 = 1.25e-3;
+// Power LSB as a multiple of the current LSB
+const INA219_POWER_LSB_RATIO: f32 = 20.0;
+const INA226_POWER_LSB_RATIO: f32 = 25.0;
+// The current register is a signed 16-bit value
+const CURRENT_FULL_SCALE: f32 = 32768.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PowerMonitorChip {
+    Ina219,
+    Ina226
+}
+
+pub enum PowerMonitorError {
+    Bus(I2cError),
+    InvalidConfiguration,
+    // The calibration register has not been written, current and power read 0
+    NotCalibrated,
+    // INA219 OVF, the current or power calculation is out of range
+    Overflow,
+    // The feature does not exist on this chip
+    Unsupported
+}
+
+pub enum Ina219BusRange {
+    V16,
+    V32
+}
+
+// Full scale shunt voltage
+pub enum Ina219Gain {
+    Mv40,
+    Mv80,
+    Mv160,
+    Mv320
+}
+
+// Resolution of a single conversion or number of 12-bit samples averaged
+pub enum Ina219Adc {
+    Bits9,
+    Bits10,
+    Bits11,
+    Bits12,
+    Samples2,
+    Samples4,
+    Samples8,
+    Samples16,
+    Samples32,
+    Samples64,
+    Samples128
+}
+
+pub enum Ina226Averaging {
+    Samples1,
+    Samples4,
+    Samples16,
+    Samples64,
+    Samples128,
+    Samples256,
+    Samples512,
+    Samples1024
+}
+
+pub enum Ina226ConversionTime {
+    Us140,
+    Us204,
+    Us332,
+    Us588,
+    Us1100,
+    Us2116,
+    Us4156,
+    Us8244
+}
+
+// Both chips convert the shunt and bus voltages continuously
+pub enum PowerMonitorConfig {
+    Ina219 {
+        bus_range: Ina219BusRange,
+        gain: Ina219Gain,
+        bus_adc: Ina219Adc,
+        shunt_adc: Ina219Adc
+    },
+    Ina226 {
+        averaging: Ina226Averaging,
+        bus_conversion_time: Ina226ConversionTime,
+        shunt_conversion_time: Ina226ConversionTime
+    }
+}
+
+// INA226 ALERT pin functions, the limits are in volts and watts
+pub enum PowerMonitorAlert {
+    ShuntOverVoltage(f32),
+    ShuntUnderVoltage(f32),
+    BusOverVoltage(f32),
+    BusUnderVoltage(f32),
+    PowerOverLimit(f32),
+    ConversionReady
+}
+
+pub struct PowerMonitor<'a> {
+    pub bus: &'a I2C,
+    pub address: u8,
+    pub chip: PowerMonitorChip,
+    // Amperes per bit of the current register, 0 until calibrated
+    current_lsb: f32
+}
+
+impl<'a> PowerMonitor<'a> {
+    pub fn new(bus: &'a I2C, address: u8, chip: PowerMonitorChip) -> PowerMonitor<'a> {
+        PowerMonitor {
+            bus,
+            address,
+            chip,
+            current_lsb: 0.0
+        }
+    }
+
+    // Restores the power-on configuration, the calibration is cleared
+    pub fn reset(&mut self) -> Result<(), PowerMonitorError> {
+        // RST bit 15
+        self.write_register(REGISTER_CONFIGURATION, 0x8000)?;
+        self.current_lsb = 0.0;
+        Ok(())
+    }
+
+    pub fn configure(&self, config: PowerMonitorConfig) -> Result<(), PowerMonitorError> {
+        let configuration = match (config, self.chip) {
+            (PowerMonitorConfig::Ina219 { bus_range, gain, bus_adc, shunt_adc }, PowerMonitorChip::Ina219) => {
+                // BRNG bit 13, PG bits 12:11, BADC bits 10:7, SADC bits 6:3
+                let bus_range = match bus_range {
+                    Ina219BusRange::V16 => 0,
+                    Ina219BusRange::V32 => 1
+                };
+                let gain = match gain {
+                    Ina219Gain::Mv40 => 0b00,
+                    Ina219Gain::Mv80 => 0b01,
+                    Ina219Gain::Mv160 => 0b10,
+                    Ina219Gain::Mv320 => 0b11
+                };
+                (bus_range << 13) | (gain << 11) | (bus_adc.code() << 7) | (shunt_adc.code() << 3)
+            }
+            (PowerMonitorConfig::Ina226 { averaging, bus_conversion_time, shunt_conversion_time }, PowerMonitorChip::Ina226) => {
+                // Bits 14:12 read 0b100, AVG bits 11:9, VBUSCT bits 8:6, VSHCT bits 5:3
+                let averaging = match averaging {
+                    Ina226Averaging::Samples1 => 0b000,
+                    Ina226Averaging::Samples4 => 0b001,
+                    Ina226Averaging::Samples16 => 0b010,
+                    Ina226Averaging::Samples64 => 0b011,
+                    Ina226Averaging::Samples128 => 0b100,
+                    Ina226Averaging::Samples256 => 0b101,
+                    Ina226Averaging::Samples512 => 0b110,
+                    Ina226Averaging::Samples1024 => 0b111
+                };
+                0x4000 | (averaging << 9) | (bus_conversion_time.code() << 6) | (shunt_conversion_time.code() << 3)
+            }
+            _ => return Err(PowerMonitorError::InvalidConfiguration)
+        };
+        // MODE bits 2:0 = 0b111 shunt and bus continuous
+        self.write_register(REGISTER_CONFIGURATION, configuration | 0b111)
+    }
+
+    // The current LSB is chosen so that the maximum expected current uses the whole register range
+    pub fn calibrate(&mut self, shunt_ohms: f32, max_current_amps: f32) -> Result<(), PowerMonitorError> {
+        if shunt_ohms <= 0.0 || max_current_amps <= 0.0 {
+            return Err(PowerMonitorError::InvalidConfiguration)
+        }
+
+        let (scale, max_calibration) = match self.chip {
+            // Bit 0 of the INA219 calibration register is not used
+            PowerMonitorChip::Ina219 => (INA219_CALIBRATION_SCALE, 0xFFFE),
+            PowerMonitorChip::Ina226 => (INA226_CALIBRATION_SCALE, 0x7FFF)
+        };
+
+        let current_lsb = max_current_amps / CURRENT_FULL_SCALE;
+        let calibration = (scale / (current_lsb * shunt_ohms)) as u32;
+        let calibration = match self.chip {
+            PowerMonitorChip::Ina219 => calibration & !1,
+            PowerMonitorChip::Ina226 => calibration
+        };
+        if calibration == 0 || calibration > max_calibration {
+            return Err(PowerMonitorError::InvalidConfiguration)
+        }
+
+        self.write_register(REGISTER_CALIBRATION, calibration as u16)?;
+        // The truncated calibration value sets the real LSB
+        self.current_lsb = scale / (calibration as f32 * shunt_ohms);
+        Ok(())
+    }
+
+    pub fn current_lsb(&self) -> f32 {
+        self.current_lsb
+    }
+
+    // Volts, signed
+    pub fn shunt_voltage(&self) -> Result<f32, PowerMonitorError> {
+        let raw = self.read_register(REGISTER_SHUNT_VOLTAGE)? as i16;
+        Ok(raw as f32 * self.shunt_voltage_lsb())
+    }
+
+    // Volts
+    pub fn bus_voltage(&self) -> Result<f32, PowerMonitorError> {
+        let raw = self.read_register(REGISTER_BUS_VOLTAGE)?;
+        match self.chip {
+            PowerMonitorChip::Ina219 => {
+                // BD bits 15:3, OVF bit 0
+                if (raw & 0x01) > 0 {
+                    return Err(PowerMonitorError::Overflow)
+                }
+                Ok((raw >> 3) as f32 * INA219_BUS_VOLTAGE_LSB)
+            }
+            PowerMonitorChip::Ina226 => Ok(raw as f32 * INA226_BUS_VOLTAGE_LSB)
+        }
+    }
+
+    // Amperes, signed
+    pub fn current(&self) -> Result<f32, PowerMonitorError> {
+        self.check_calibrated()?;
+        let raw = self.read_register(REGISTER_CURRENT)? as i16;
+        Ok(raw as f32 * self.current_lsb)
+    }
+
+    // Watts
+    pub fn power(&self) -> Result<f32, PowerMonitorError> {
+        self.check_calibrated()?;
+        let raw = self.read_register(REGISTER_POWER)?;
+        Ok(raw as f32 * self.power_lsb())
+    }
+
+    // Only one function drives the ALERT pin at a time
+    pub fn set_alert(&self, alert: PowerMonitorAlert, active_high: bool, latched: bool) -> Result<(), PowerMonitorError> {
+        if let PowerMonitorChip::Ina219 = self.chip {
+            return Err(PowerMonitorError::Unsupported)
+        }
+
+        // SOL bit 15, SUL bit 14, BOL bit 13, BUL bit 12, POL bit 11, CNVR bit 10, the limit has the format of the monitored register
+        let (function, limit) = match alert {
+            PowerMonitorAlert::ShuntOverVoltage(volts) => (1 << 15, Some(to_register(volts / INA226_SHUNT_VOLTAGE_LSB, true))),
+            PowerMonitorAlert::ShuntUnderVoltage(volts) => (1 << 14, Some(to_register(volts / INA226_SHUNT_VOLTAGE_LSB, true))),
+            PowerMonitorAlert::BusOverVoltage(volts) => (1 << 13, Some(to_register(volts / INA226_BUS_VOLTAGE_LSB, false))),
+            PowerMonitorAlert::BusUnderVoltage(volts) => (1 << 12, Some(to_register(volts / INA226_BUS_VOLTAGE_LSB, false))),
+            PowerMonitorAlert::PowerOverLimit(watts) => {
+                self.check_calibrated()?;
+                (1 << 11, Some(to_register(watts / self.power_lsb(), false)))
+            }
+            PowerMonitorAlert::ConversionReady => (1 << 10, None)
+        };
+
+        if let Some(limit) = limit {
+            self.write_register(REGISTER_ALERT_LIMIT, limit)?;
+        }
+        // APOL bit 1, LEN bit 0
+        let polarity = if active_high { 1 << 1 } else { 0 };
+        let latch = if latched { 1 << 0 } else { 0 };
+        self.write_register(REGISTER_MASK_ENABLE, function | polarity | latch)
+    }
+
+    pub fn disable_alert(&self) -> Result<(), PowerMonitorError> {
+        if let PowerMonitorChip::Ina219 = self.chip {
+            return Err(PowerMonitorError::Unsupported)
+        }
+        self.write_register(REGISTER_MASK_ENABLE, 0)
+    }
+
+    // Reading Mask/Enable releases a latched ALERT pin
+    pub fn alert_triggered(&self) -> Result<bool, PowerMonitorError> {
+        if let PowerMonitorChip::Ina219 = self.chip {
+            return Err(PowerMonitorError::Unsupported)
+        }
+        // AFF bit 4
+        Ok((self.read_register(REGISTER_MASK_ENABLE)? & (1 << 4)) > 0)
+    }
+
+    /* Private methods */
+
+    fn shunt_voltage_lsb(&self) -> f32 {
+        match self.chip {
+            PowerMonitorChip::Ina219 => INA219_SHUNT_VOLTAGE_LSB,
+            PowerMonitorChip::Ina226 => INA226_SHUNT_VOLTAGE_LSB
+        }
+    }
+
+    fn power_lsb(&self) -> f32 {
+        match self.chip {
+            PowerMonitorChip::Ina219 => INA219_POWER_LSB_RATIO * self.current_lsb,
+            PowerMonitorChip::Ina226 => INA226_POWER_LSB_RATIO * self.current_lsb
+        }
+    }
+
+    fn check_calibrated(&self) -> Result<(), PowerMonitorError> {
+        if self.current_lsb == 0.0 {
+            return Err(PowerMonitorError::NotCalibrated)
+        }
+        Ok(())
+    }
+
+    // Registers are 16-bit, most significant byte first
+    fn read_register(&self, register: u8) -> Result<u16, PowerMonitorError> {
+        let mut value = [0u8; 2];
+        self.bus.write_read(self.address, &[register], &mut value).map_err(PowerMonitorError::Bus)?;
+        Ok(u16::from_be_bytes(value))
+    }
+
+    fn write_register(&self, register: u8, value: u16) -> Result<(), PowerMonitorError> {
+        let [msb, lsb] = value.to_be_bytes();
+        self.bus.write(self.address, &[register, msb, lsb]).map_err(PowerMonitorError::Bus)
+    }
+}
+
+impl Ina219Adc {
+    fn code(&self) -> u16 {
+        match self {
+            Ina219Adc::Bits9 => 0b0000,
+            Ina219Adc::Bits10 => 0b0001,
+            Ina219Adc::Bits11 => 0b0010,
+            Ina219Adc::Bits12 => 0b0011,
+            Ina219Adc::Samples2 => 0b1001,
+            Ina219Adc::Samples4 => 0b1010,
+            Ina219Adc::Samples8 => 0b1011,
+            Ina219Adc::Samples16 => 0b1100,
+            Ina219Adc::Samples32 => 0b1101,
+            Ina219Adc::Samples64 => 0b1110,
+            Ina219Adc::Samples128 => 0b1111
+        }
+    }
+}
+
+impl Ina226ConversionTime {
+    fn code(&self) -> u16 {
+        match self {
+            Ina226ConversionTime::Us140 => 0b000,
+            Ina226ConversionTime::Us204 => 0b001,
+            Ina226ConversionTime::Us332 => 0b010,
+            Ina226ConversionTime::Us588 => 0b011,
+            Ina226ConversionTime::Us1100 => 0b100,
+            Ina226ConversionTime::Us2116 => 0b101,
+            Ina226ConversionTime::Us4156 => 0b110,
+            Ina226ConversionTime::Us8244 => 0b111
+        }
+    }
+}
+
+// Rounds and saturates a value expressed in LSBs
+fn to_register(lsbs: f32, signed: bool) -> u16 {
+    let rounded = if lsbs >= 0.0 { lsbs + 0.5 } else { lsbs - 0.5 };
+    if signed {
+        (rounded.clamp(i16::MIN as f32, i16::MAX as f32) as i16) as u16
+    } else {
+        rounded.clamp(0.0, u16::MAX as f32) as u16
+    }
+}
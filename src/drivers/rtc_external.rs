@@ -0,0 +1,402 @@
+#![allow(dead_code)]
+
+use crate::core::datetime::{bcd_to_binary, binary_to_bcd, DateTime};
+use crate::drivers::I2C::{I2C, I2cError};
+
+const DS3231_ADDRESS: u8 = 0x68;
+const DS1307_ADDRESS: u8 = 0x68;
+const PCF8563_ADDRESS: u8 = 0x51;
+
+// DS3231 and DS1307 registers
+const DS_SECONDS: u8 = 0x00;
+const DS3231_ALARM_1: u8 = 0x07;
+const DS3231_ALARM_2: u8 = 0x0B;
+const DS3231_CONTROL: u8 = 0x0E;
+const DS3231_STATUS: u8 = 0x0F;
+const DS3231_TEMPERATURE: u8 = 0x11;
+const DS1307_CONTROL: u8 = 0x07;
+
+// PCF8563 registers
+const PCF8563_CONTROL_STATUS_2: u8 = 0x01;
+const PCF8563_SECONDS: u8 = 0x02;
+const PCF8563_MINUTE_ALARM: u8 = 0x09;
+const PCF8563_CLKOUT_CONTROL: u8 = 0x0D;
+
+// Bit 7 of an alarm register masks the field out of the comparison
+const ALARM_FIELD_DISABLED: u8 = 0x80;
+
+pub enum RtcChip {
+    Ds3231,
+    Ds1307,
+    Pcf8563
+}
+
+pub enum RtcError {
+    Bus(I2cError),
+    InvalidDateTime,
+    InvalidAlarm,
+    // The feature does not exist on this chip
+    Unsupported
+}
+
+pub enum RtcAlarm {
+    Alarm1,
+    // DS3231 only, has no seconds field
+    Alarm2
+}
+
+pub enum AlarmDay {
+    Date(u8),
+    // 1 is Monday and 7 is Sunday
+    Weekday(u8)
+}
+
+// A field set to None matches any value
+pub struct AlarmConfig {
+    pub day: Option<AlarmDay>,
+    pub hours: Option<u8>,
+    pub minutes: Option<u8>,
+    // DS3231 alarm 1 only
+    pub seconds: Option<u8>
+}
+
+pub enum SquareWave {
+    Disabled,
+    Hz1,
+    Hz32,
+    Hz1024,
+    Hz4096,
+    Hz8192,
+    Hz32768
+}
+
+pub struct ExternalRtc<'a> {
+    pub bus: &'a I2C,
+    pub chip: RtcChip
+}
+
+impl<'a> ExternalRtc<'a> {
+    pub fn new(bus: &'a I2C, chip: RtcChip) -> ExternalRtc<'a> {
+        ExternalRtc {
+            bus,
+            chip
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        match self.chip {
+            RtcChip::Ds3231 => DS3231_ADDRESS,
+            RtcChip::Ds1307 => DS1307_ADDRESS,
+            RtcChip::Pcf8563 => PCF8563_ADDRESS
+        }
+    }
+
+    pub fn read_date_time(&self) -> Result<DateTime, RtcError> {
+        let mut registers = [0u8; 7];
+        match self.chip {
+            RtcChip::Ds3231 | RtcChip::Ds1307 => {
+                // Seconds, minutes, hours, weekday, date, month, year
+                self.read_registers(DS_SECONDS, &mut registers)?;
+                // Bit 7 of the month is the DS3231 century bit, always 0 on the DS1307
+                let century = if (registers[5] & 0x80) > 0 { 100 } else { 0 };
+                Ok(DateTime {
+                    year: 2000 + century + bcd_to_binary(registers[6]) as u16,
+                    month: bcd_to_binary(registers[5] & 0x1F),
+                    day: bcd_to_binary(registers[4] & 0x3F),
+                    hours: decode_hours(registers[2]),
+                    minutes: bcd_to_binary(registers[1] & 0x7F),
+                    // Bit 7 is the DS1307 clock halt bit
+                    seconds: bcd_to_binary(registers[0] & 0x7F)
+                })
+            }
+            RtcChip::Pcf8563 => {
+                // Seconds, minutes, hours, days, weekdays, century and months, years
+                self.read_registers(PCF8563_SECONDS, &mut registers)?;
+                let century = if (registers[5] & 0x80) > 0 { 100 } else { 0 };
+                Ok(DateTime {
+                    year: 2000 + century + bcd_to_binary(registers[6]) as u16,
+                    month: bcd_to_binary(registers[5] & 0x1F),
+                    day: bcd_to_binary(registers[3] & 0x3F),
+                    hours: bcd_to_binary(registers[2] & 0x3F),
+                    minutes: bcd_to_binary(registers[1] & 0x7F),
+                    // Bit 7 is the voltage low flag
+                    seconds: bcd_to_binary(registers[0] & 0x7F)
+                })
+            }
+        }
+    }
+
+    // Writing the seconds also restarts a halted DS1307 and clears the PCF8563 voltage low flag
+    pub fn set_date_time(&self, date_time: &DateTime) -> Result<(), RtcError> {
+        if !date_time.is_valid() || date_time.year < 2000 || date_time.year > self.max_year() {
+            return Err(RtcError::InvalidDateTime)
+        }
+
+        let years = (date_time.year - 2000) as u8;
+        let century = if years >= 100 { 0x80 } else { 0 };
+        let weekday = date_time.weekday();
+
+        match self.chip {
+            RtcChip::Ds3231 | RtcChip::Ds1307 => {
+                self.write_registers(DS_SECONDS, &[
+                    binary_to_bcd(date_time.seconds),
+                    binary_to_bcd(date_time.minutes),
+                    // Bit 6 cleared selects the 24-hour mode
+                    binary_to_bcd(date_time.hours),
+                    weekday,
+                    binary_to_bcd(date_time.day),
+                    century | binary_to_bcd(date_time.month),
+                    binary_to_bcd(years % 100)
+                ])?;
+                if let RtcChip::Ds3231 = self.chip {
+                    self.clear_oscillator_stop_flag()?;
+                }
+                Ok(())
+            }
+            RtcChip::Pcf8563 => {
+                self.write_registers(PCF8563_SECONDS, &[
+                    binary_to_bcd(date_time.seconds),
+                    binary_to_bcd(date_time.minutes),
+                    binary_to_bcd(date_time.hours),
+                    binary_to_bcd(date_time.day),
+                    // Weekdays count from 0, Sunday is 0
+                    weekday % 7,
+                    century | binary_to_bcd(date_time.month),
+                    binary_to_bcd(years % 100)
+                ])
+            }
+        }
+    }
+
+    // True when the oscillator stopped since the time was last set, the time read back can't be trusted
+    pub fn oscillator_stopped(&self) -> Result<bool, RtcError> {
+        match self.chip {
+            // OSF bit 7 in the status register
+            RtcChip::Ds3231 => Ok((self.read_register(DS3231_STATUS)? & 0x80) > 0),
+            // CH bit 7 in the seconds register, set on the first power up
+            RtcChip::Ds1307 => Ok((self.read_register(DS_SECONDS)? & 0x80) > 0),
+            // VL bit 7 in the seconds register
+            RtcChip::Pcf8563 => Ok((self.read_register(PCF8563_SECONDS)? & 0x80) > 0)
+        }
+    }
+
+    pub fn clear_oscillator_stop_flag(&self) -> Result<(), RtcError> {
+        match self.chip {
+            RtcChip::Ds3231 => self.modify_register(DS3231_STATUS, 0x80, 0),
+            RtcChip::Ds1307 => self.modify_register(DS_SECONDS, 0x80, 0),
+            RtcChip::Pcf8563 => self.modify_register(PCF8563_SECONDS, 0x80, 0)
+        }
+    }
+
+    // The alarm interrupt is enabled, on the DS3231 the INT/SQW pin leaves the square wave output
+    pub fn set_alarm(&self, alarm: RtcAlarm, config: &AlarmConfig) -> Result<(), RtcError> {
+        let day = match config.day {
+            None => ALARM_FIELD_DISABLED,
+            Some(AlarmDay::Date(date)) if (1..=31).contains(&date) => binary_to_bcd(date),
+            Some(AlarmDay::Weekday(weekday)) if (1..=7).contains(&weekday) => weekday,
+            _ => return Err(RtcError::InvalidAlarm)
+        };
+        let hours = alarm_field(config.hours, 24)?;
+        let minutes = alarm_field(config.minutes, 60)?;
+        let seconds = alarm_field(config.seconds, 60)?;
+
+        match self.chip {
+            RtcChip::Ds3231 => {
+                // A field can only be compared when all the lower ones are
+                let fields = [config.seconds.is_some(), config.minutes.is_some(), config.hours.is_some(), config.day.is_some()];
+                let fields = match alarm {
+                    RtcAlarm::Alarm1 => &fields[..],
+                    RtcAlarm::Alarm2 if config.seconds.is_none() => &fields[1..],
+                    RtcAlarm::Alarm2 => return Err(RtcError::InvalidAlarm)
+                };
+                if fields.windows(2).any(|pair| !pair[0] && pair[1]) {
+                    return Err(RtcError::InvalidAlarm)
+                }
+
+                // DY/DT bit 6 selects the weekday
+                let day = match config.day {
+                    Some(AlarmDay::Weekday(_)) => day | 0x40,
+                    _ => day
+                };
+
+                match alarm {
+                    RtcAlarm::Alarm1 => {
+                        self.write_registers(DS3231_ALARM_1, &[seconds, minutes, hours, day])?;
+                        // A1F bit 0 in the status register, A1IE bit 0 and INTCN bit 2 in the control register
+                        self.modify_register(DS3231_STATUS, 0x01, 0)?;
+                        self.modify_register(DS3231_CONTROL, 0, 0x05)
+                    }
+                    RtcAlarm::Alarm2 => {
+                        self.write_registers(DS3231_ALARM_2, &[minutes, hours, day])?;
+                        // A2F bit 1 in the status register, A2IE bit 1 and INTCN bit 2 in the control register
+                        self.modify_register(DS3231_STATUS, 0x02, 0)?;
+                        self.modify_register(DS3231_CONTROL, 0, 0x06)
+                    }
+                }
+            }
+            RtcChip::Pcf8563 => {
+                if let RtcAlarm::Alarm2 = alarm {
+                    return Err(RtcError::Unsupported)
+                }
+                // The alarm has a one minute resolution and would never fire with every field disabled
+                if config.seconds.is_some() || (config.minutes.is_none() && config.hours.is_none() && config.day.is_none()) {
+                    return Err(RtcError::InvalidAlarm)
+                }
+
+                let (date, weekday) = match config.day {
+                    Some(AlarmDay::Date(_)) => (day, ALARM_FIELD_DISABLED),
+                    Some(AlarmDay::Weekday(weekday)) => (ALARM_FIELD_DISABLED, weekday % 7),
+                    None => (ALARM_FIELD_DISABLED, ALARM_FIELD_DISABLED)
+                };
+                self.write_registers(PCF8563_MINUTE_ALARM, &[minutes, hours, date, weekday])?;
+                // AF bit 3 cleared, TF bit 2 written to 1 is left unchanged, AIE bit 1 set
+                self.modify_register(PCF8563_CONTROL_STATUS_2, 0x08, 0x06)
+            }
+            RtcChip::Ds1307 => Err(RtcError::Unsupported)
+        }
+    }
+
+    pub fn disable_alarm(&self, alarm: RtcAlarm) -> Result<(), RtcError> {
+        match (&self.chip, alarm) {
+            // A1IE bit 0 and A2IE bit 1 in the control register
+            (RtcChip::Ds3231, RtcAlarm::Alarm1) => self.modify_register(DS3231_CONTROL, 0x01, 0),
+            (RtcChip::Ds3231, RtcAlarm::Alarm2) => self.modify_register(DS3231_CONTROL, 0x02, 0),
+            // AIE bit 1, TF bit 2 written to 1 is left unchanged
+            (RtcChip::Pcf8563, RtcAlarm::Alarm1) => self.modify_register(PCF8563_CONTROL_STATUS_2, 0x02, 0x04),
+            _ => Err(RtcError::Unsupported)
+        }
+    }
+
+    pub fn alarm_triggered(&self, alarm: RtcAlarm) -> Result<bool, RtcError> {
+        match (&self.chip, alarm) {
+            // A1F bit 0 and A2F bit 1 in the status register
+            (RtcChip::Ds3231, RtcAlarm::Alarm1) => Ok((self.read_register(DS3231_STATUS)? & 0x01) > 0),
+            (RtcChip::Ds3231, RtcAlarm::Alarm2) => Ok((self.read_register(DS3231_STATUS)? & 0x02) > 0),
+            // AF bit 3
+            (RtcChip::Pcf8563, RtcAlarm::Alarm1) => Ok((self.read_register(PCF8563_CONTROL_STATUS_2)? & 0x08) > 0),
+            _ => Err(RtcError::Unsupported)
+        }
+    }
+
+    // Releases the interrupt pin
+    pub fn clear_alarm(&self, alarm: RtcAlarm) -> Result<(), RtcError> {
+        match (&self.chip, alarm) {
+            (RtcChip::Ds3231, RtcAlarm::Alarm1) => self.modify_register(DS3231_STATUS, 0x01, 0),
+            (RtcChip::Ds3231, RtcAlarm::Alarm2) => self.modify_register(DS3231_STATUS, 0x02, 0),
+            (RtcChip::Pcf8563, RtcAlarm::Alarm1) => self.modify_register(PCF8563_CONTROL_STATUS_2, 0x08, 0x04),
+            _ => Err(RtcError::Unsupported)
+        }
+    }
+
+    pub fn set_square_wave(&self, square_wave: SquareWave) -> Result<(), RtcError> {
+        match self.chip {
+            RtcChip::Ds3231 => {
+                // RS2:RS1 bits 4:3 and INTCN bit 2 in the control register, EN32kHz bit 3 in the status register
+                let (rate, interrupt_control, output_32khz) = match square_wave {
+                    SquareWave::Disabled => (0b00, 1, 0),
+                    SquareWave::Hz1 => (0b00, 0, 0),
+                    SquareWave::Hz1024 => (0b01, 0, 0),
+                    SquareWave::Hz4096 => (0b10, 0, 0),
+                    SquareWave::Hz8192 => (0b11, 0, 0),
+                    // Only available on the dedicated 32kHz pin
+                    SquareWave::Hz32768 => (0b00, 1, 1),
+                    SquareWave::Hz32 => return Err(RtcError::Unsupported)
+                };
+                self.modify_register(DS3231_CONTROL, 0b11100, (rate << 3) | (interrupt_control << 2))?;
+                self.modify_register(DS3231_STATUS, 0x08, output_32khz << 3)
+            }
+            RtcChip::Ds1307 => {
+                // SQWE bit 4 and RS1:RS0 bits 1:0 in the control register
+                let control = match square_wave {
+                    SquareWave::Disabled => 0,
+                    SquareWave::Hz1 => 0x10,
+                    SquareWave::Hz4096 => 0x11,
+                    SquareWave::Hz8192 => 0x12,
+                    SquareWave::Hz32768 => 0x13,
+                    _ => return Err(RtcError::Unsupported)
+                };
+                self.write_registers(DS1307_CONTROL, &[control])
+            }
+            RtcChip::Pcf8563 => {
+                // FE bit 7 and FD1:FD0 bits 1:0 in the CLKOUT control register
+                let control = match square_wave {
+                    SquareWave::Disabled => 0,
+                    SquareWave::Hz32768 => 0x80,
+                    SquareWave::Hz1024 => 0x81,
+                    SquareWave::Hz32 => 0x82,
+                    SquareWave::Hz1 => 0x83,
+                    _ => return Err(RtcError::Unsupported)
+                };
+                self.write_registers(PCF8563_CLKOUT_CONTROL, &[control])
+            }
+        }
+    }
+
+    // Degrees Celsius with a 0.25 resolution, refreshed every 64 seconds
+    pub fn temperature(&self) -> Result<f32, RtcError> {
+        if let RtcChip::Ds3231 = self.chip {
+            // Integer part in the MSB, quarters in bits 7:6 of the LSB
+            let mut registers = [0u8; 2];
+            self.read_registers(DS3231_TEMPERATURE, &mut registers)?;
+            let raw = (((registers[0] as u16) << 8 | registers[1] as u16) as i16) >> 6;
+            Ok(raw as f32 * 0.25)
+        } else {
+            Err(RtcError::Unsupported)
+        }
+    }
+
+    /* Private methods */
+
+    fn max_year(&self) -> u16 {
+        match self.chip {
+            // Century bit in the month register
+            RtcChip::Ds3231 | RtcChip::Pcf8563 => 2199,
+            RtcChip::Ds1307 => 2099
+        }
+    }
+
+    fn read_register(&self, register: u8) -> Result<u8, RtcError> {
+        let mut value = [0u8; 1];
+        self.read_registers(register, &mut value)?;
+        Ok(value[0])
+    }
+
+    fn read_registers(&self, register: u8, buffer: &mut [u8]) -> Result<(), RtcError> {
+        self.bus.write_read(self.address(), &[register], buffer).map_err(RtcError::Bus)
+    }
+
+    fn write_registers(&self, register: u8, data: &[u8]) -> Result<(), RtcError> {
+        let mut frame = [0u8; 8];
+        frame[0] = register;
+        frame[1..=data.len()].copy_from_slice(data);
+        self.bus.write(self.address(), &frame[..=data.len()]).map_err(RtcError::Bus)
+    }
+
+    fn modify_register(&self, register: u8, clear_mask: u8, set_mask: u8) -> Result<(), RtcError> {
+        let value = self.read_register(register)?;
+        self.write_registers(register, &[(value & !clear_mask) | set_mask])
+    }
+}
+
+// DS3231 and DS1307 hours may have been left in the 12-hour mode
+fn decode_hours(register: u8) -> u8 {
+    // 12/24 bit 6, AM/PM bit 5 in the 12-hour mode
+    if (register & 0x40) > 0 {
+        let hours = bcd_to_binary(register & 0x1F) % 12;
+        if (register & 0x20) > 0 {
+            hours + 12
+        } else {
+            hours
+        }
+    } else {
+        bcd_to_binary(register & 0x3F)
+    }
+}
+
+fn alarm_field(value: Option<u8>, limit: u8) -> Result<u8, RtcError> {
+    match value {
+        None => Ok(ALARM_FIELD_DISABLED),
+        Some(value) if value < limit => Ok(binary_to_bcd(value)),
+        Some(_) => Err(RtcError::InvalidAlarm)
+    }
+}
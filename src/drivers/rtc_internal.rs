@@ -0,0 +1,186 @@
+#![allow(dead_code)]
+
+use stm32g4::stm32g431;
+use crate::core::datetime::{bcd_to_binary, binary_to_bcd, DateTime};
+use crate::core::delay::delay_us;
+
+// LSE start-up can take up to 2 s with a high drive crystal
+const LSE_TIMEOUT_US: u32 = 2000000;
+const RTC_TIMEOUT_US: u32 = 10000;
+// 32768 Hz / (127 + 1) / (255 + 1) = 1 Hz
+const RTC_ASYNC_PREDIV: u32 = 127;
+const RTC_SYNC_PREDIV: u32 = 255;
+
+pub enum InternalRtcError {
+    LseNotReady,
+    TimeOut,
+    // Only the years 2000 to 2099 fit in the calendar registers
+    InvalidDateTime
+}
+
+// Calendar clocked by the LSE, it keeps running on VBAT while the core is off
+pub struct InternalRtc;
+
+// Contents of RTC_TR and RTC_DR, every field is BCD in 24-hour format
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RtcTimeDate {
+    pub tr: u32,
+    pub dr: u32
+}
+
+impl InternalRtc {
+    pub fn new() -> InternalRtc {
+        InternalRtc
+    }
+
+    // The backup domain keeps its configuration after a reset, the calendar is not touched if the RTC already runs
+    pub fn begin(&self) -> Result<(), InternalRtcError> {
+        unsafe {
+            let rcc = &*stm32g431::RCC::ptr();
+            let pwr = &*stm32g431::PWR::ptr();
+            // PWREN bit 28 and RTCAPBEN bit 10 in RCC_APB1ENR1
+            rcc.apb1enr1.as_ptr().write(rcc.apb1enr1.as_ptr().read() | (1 << 28) | (1 << 10));
+            // DBP bit 8 in PWR_CR1 unlocks the backup domain
+            pwr.cr1.as_ptr().write(pwr.cr1.as_ptr().read() | (1 << 8));
+
+            // RTCEN bit 15 in RCC_BDCR
+            if (rcc.bdcr.as_ptr().read() & (1 << 15)) > 0 {
+                return Ok(())
+            }
+
+            // LSEON bit 0, wait for LSERDY bit 1 in RCC_BDCR
+            rcc.bdcr.as_ptr().write(rcc.bdcr.as_ptr().read() | (1 << 0));
+            let mut elapsed_us = 0u32;
+            while (rcc.bdcr.as_ptr().read() & (1 << 1)) == 0 {
+                if elapsed_us >= LSE_TIMEOUT_US {
+                    return Err(InternalRtcError::LseNotReady)
+                }
+                delay_us(1);
+                elapsed_us += 1;
+            }
+
+            // RTCSEL bits 9:8 = 0b01 selects the LSE, RTCEN bit 15
+            rcc.bdcr.as_ptr().write((rcc.bdcr.as_ptr().read() & !(0b11 << 8)) | (0b01 << 8) | (1 << 15));
+        }
+
+        self.enter_initialization_mode()?;
+        unsafe {
+            let rtc = &*stm32g431::RTC::ptr();
+            // PREDIV_A bits 22:16 and PREDIV_S bits 14:0 in RTC_PRER
+            rtc.prer.as_ptr().write((RTC_ASYNC_PREDIV << 16) | RTC_SYNC_PREDIV);
+            // FMT bit 6 in RTC_CR cleared selects the 24-hour format
+            rtc.cr.as_ptr().write(rtc.cr.as_ptr().read() & !(1 << 6));
+        }
+        self.exit_initialization_mode();
+        Ok(())
+    }
+
+    pub fn read_date_time(&self) -> Result<DateTime, InternalRtcError> {
+        Ok(self.read_registers()?.into())
+    }
+
+    pub fn set_date_time(&self, date_time: &DateTime) -> Result<(), InternalRtcError> {
+        if !date_time.is_valid() || date_time.year < 2000 || date_time.year > 2099 {
+            return Err(InternalRtcError::InvalidDateTime)
+        }
+        let registers = RtcTimeDate::from(*date_time);
+
+        self.enter_initialization_mode()?;
+        unsafe {
+            let rtc = &*stm32g431::RTC::ptr();
+            rtc.tr.as_ptr().write(registers.tr);
+            rtc.dr.as_ptr().write(registers.dr);
+        }
+        self.exit_initialization_mode();
+        Ok(())
+    }
+
+    pub fn read_registers(&self) -> Result<RtcTimeDate, InternalRtcError> {
+        unsafe {
+            let rtc = &*stm32g431::RTC::ptr();
+            // Clear RSF bit 5 in RTC_ICSR and wait for the shadow registers to be updated
+            rtc.icsr.as_ptr().write(rtc.icsr.as_ptr().read() & !(1 << 5));
+            self.wait_for_icsr_flag(5)?;
+            // Reading RTC_TR locks the shadow registers until RTC_DR is read
+            let tr = rtc.tr.as_ptr().read();
+            let dr = rtc.dr.as_ptr().read();
+            Ok(RtcTimeDate {
+                tr,
+                dr
+            })
+        }
+    }
+
+    /* Private methods */
+
+    fn enter_initialization_mode(&self) -> Result<(), InternalRtcError> {
+        unsafe {
+            let rtc = &*stm32g431::RTC::ptr();
+            // Unlock the write protection with the key sequence in RTC_WPR
+            rtc.wpr.as_ptr().write(0xCA);
+            rtc.wpr.as_ptr().write(0x53);
+            // INIT bit 7 in RTC_ICSR, wait for INITF bit 6
+            rtc.icsr.as_ptr().write(rtc.icsr.as_ptr().read() | (1 << 7));
+        }
+        let result = self.wait_for_icsr_flag(6);
+        if result.is_err() {
+            self.exit_initialization_mode();
+        }
+        result
+    }
+
+    fn exit_initialization_mode(&self){
+        unsafe {
+            let rtc = &*stm32g431::RTC::ptr();
+            // Clear INIT bit 7 in RTC_ICSR, the calendar restarts
+            rtc.icsr.as_ptr().write(rtc.icsr.as_ptr().read() & !(1 << 7));
+            // Any wrong key locks the write protection again
+            rtc.wpr.as_ptr().write(0xFF);
+        }
+    }
+
+    fn wait_for_icsr_flag(&self, bit: u32) -> Result<(), InternalRtcError> {
+        unsafe {
+            let rtc = &*stm32g431::RTC::ptr();
+            let mut elapsed_us = 0u32;
+            while (rtc.icsr.as_ptr().read() & (1 << bit)) == 0 {
+                if elapsed_us >= RTC_TIMEOUT_US {
+                    return Err(InternalRtcError::TimeOut)
+                }
+                delay_us(1);
+                elapsed_us += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The registers only hold the years of the century, DateTime years are taken from 2000
+impl From<DateTime> for RtcTimeDate {
+    fn from(date_time: DateTime) -> RtcTimeDate {
+        RtcTimeDate {
+            // HT/HU bits 21:16, MNT/MNU bits 14:8, ST/SU bits 6:0, PM bit 22 cleared
+            tr: ((binary_to_bcd(date_time.hours) as u32) << 16)
+                | ((binary_to_bcd(date_time.minutes) as u32) << 8)
+                | binary_to_bcd(date_time.seconds) as u32,
+            // YT/YU bits 23:16, WDU bits 15:13, MT/MU bits 12:8, DT/DU bits 5:0
+            dr: ((binary_to_bcd((date_time.year % 100) as u8) as u32) << 16)
+                | ((date_time.weekday() as u32) << 13)
+                | ((binary_to_bcd(date_time.month) as u32) << 8)
+                | binary_to_bcd(date_time.day) as u32
+        }
+    }
+}
+
+impl From<RtcTimeDate> for DateTime {
+    fn from(registers: RtcTimeDate) -> DateTime {
+        DateTime {
+            year: 2000 + bcd_to_binary(((registers.dr >> 16) & 0xFF) as u8) as u16,
+            month: bcd_to_binary(((registers.dr >> 8) & 0x1F) as u8),
+            day: bcd_to_binary((registers.dr & 0x3F) as u8),
+            hours: bcd_to_binary(((registers.tr >> 16) & 0x3F) as u8),
+            minutes: bcd_to_binary(((registers.tr >> 8) & 0x7F) as u8),
+            seconds: bcd_to_binary((registers.tr & 0x7F) as u8)
+        }
+    }
+}
@@ -1,27 +1,40 @@
 #![allow(dead_code)]
 
 use alloc::string::String;
-use core::fmt::{Debug, Formatter};
 use stm32g4::stm32g431;
-use crate::system::APB2_FREQ;
+use crate::system::{APB2_FREQ, HSI16_FREQ, LSE_FREQ, SYSCLK_FREQ};
 use crate::drivers::gpio::{Gpio, GpioConfig, GPIOPORT, MODER, OSPEEDR, OTYPER, PUPDR};
 use stm32g4::stm32g431::interrupt;
-use crate::core::delay::non_exact_time_delay;
+use crate::core::delay::{delay_us, non_exact_time_delay};
+use crate::core::Conversions;
 
-pub struct Serial {
+pub mod lin;
+pub mod usart2;
+
+// The polled protocols are part of the host-tested library
+#[allow(unused_imports)]
+pub use stm32g431xx::drivers::serial::{at, nmea, xmodem};
+pub use stm32g431xx::drivers::serial::{SerialError, SerialPort};
 
+pub struct Serial {
+    // RXNE interrupt state to restore once the polled port is dropped
+    restore_interrupts: bool
 }
 
-#[derive(PartialEq)]
-pub enum SerialError {
-    NoDataFound,
-    OverRun
+pub enum AutoBaudRateMode {
+    // Measurement of the start bit, the character must start with a bit at 1
+    StartBit,
+    // Measurement from falling edge to falling edge, the character must start with 10xx bit pattern
+    FallingEdge,
+    // 0x7F frame detection
+    Frame0x7F,
+    // 0x55 frame detection
+    Frame0x55
 }
 
-impl Debug for SerialError {
-    fn fmt(&self, _f: &mut Formatter<'_>) -> core::fmt::Result {
-        todo!()
-    }
+pub enum LinBreakLength {
+    Bits10,
+    Bits11
 }
 
 const TYPE_SERIAL_USART1: GpioConfig = GpioConfig {
@@ -32,6 +45,14 @@ const TYPE_SERIAL_USART1: GpioConfig = GpioConfig {
     alf_func_sel: Some(7),
 };
 
+const TYPE_SERIAL_USART1_HALF_DUPLEX: GpioConfig = GpioConfig {
+    moder: MODER::AlternateFunction,
+    otyper: OTYPER::OpenDrain,
+    ospeedr: OSPEEDR::VeryHigh,
+    pupdr: PUPDR::PullUp,
+    alf_func_sel: Some(7),
+};
+
 const USART1_TX: Gpio = Gpio {
     port: GPIOPORT::GPIOA,
     pin_number: 9
@@ -43,6 +64,15 @@ const USART1_RX: Gpio = Gpio {
 };
 
 impl Serial {
+    // Port handed to the polled protocols (XMODEM, AT commands), RXNEIE is cleared until it is dropped
+    pub fn port() -> Serial {
+        let restore_interrupts = Self::interrupts_enabled();
+        Self::disable_interrupts();
+        Serial {
+            restore_interrupts
+        }
+    }
+
     pub fn configure(){
         Self::enable_rcc_clock();
         Self::configure_gpio();
@@ -53,6 +83,90 @@ impl Serial {
         Self::enabled(true);
     }
 
+    pub fn configure_half_duplex(turnaround_us: u32){
+        Self::enable_rcc_clock();
+        // Only the TX pin is used, in open drain so that both ends can drive the line
+        USART1_TX.configure(TYPE_SERIAL_USART1_HALF_DUPLEX);
+        Self::enabled(false);
+        Self::configure_basic_registers();
+        Self::set_baudrate(Self::get_baudrate());
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            // Clear LINEN bit 14 and CLKEN bit 11 in USART_CR2
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() & !((1 << 14) | (1 << 11)));
+            // Clear SCEN bit 5 and IREN bit 1 in USART_CR3
+            port.cr3.as_ptr().write(port.cr3.as_ptr().read() & !((1 << 5) | (1 << 1)));
+            // Enable HDSEL bit 3 in USART_CR3
+            port.cr3.as_ptr().write(port.cr3.as_ptr().read() | (1 << 3));
+            HALF_DUPLEX_TURNAROUND_US = turnaround_us;
+        }
+        Self::enabled(true);
+    }
+
+    pub fn disable_half_duplex(){
+        Self::enabled(false);
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            // Disable HDSEL bit 3 in USART_CR3
+            port.cr3.as_ptr().write(port.cr3.as_ptr().read() & !(1 << 3));
+        }
+        Self::configure_gpio();
+        Self::enabled(true);
+    }
+
+    pub fn half_duplex_write(data: &[u8]){
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+
+            // Give the other end time to release the line
+            delay_us(HALF_DUPLEX_TURNAROUND_US);
+
+            // Disable RE bit 2 in USART_CR1, the transmitted bytes would be received back otherwise
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !(1 << 2));
+
+            for &b in data {
+                Self::write_byte(b);
+            }
+
+            // TC is set, the line is released, turn the receiver back on
+            Self::flush_receiver();
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() | (1 << 2));
+        }
+    }
+
+    pub fn half_duplex_read(buffer: &mut [u8], timeout_us: u32) -> Result<(), SerialError> {
+        let interrupts_enabled = Self::interrupts_enabled();
+        Self::disable_interrupts();
+        let mut result = Ok(());
+        for byte in buffer.iter_mut() {
+            match Self::read_byte_timeout(timeout_us) {
+                Ok(b) => *byte = b,
+                Err(e) => {
+                    if e == SerialError::OverRun {
+                        Self::clear_ore_flag();
+                    }
+                    result = Err(e);
+                    break
+                }
+            }
+        }
+        if interrupts_enabled {
+            Self::enable_interrupts();
+        }
+        result
+    }
+
+    pub fn half_duplex_transfer(tx: &[u8], rx: &mut [u8], timeout_us: u32) -> Result<(), SerialError> {
+        let interrupts_enabled = Self::interrupts_enabled();
+        Self::disable_interrupts();
+        Self::half_duplex_write(tx);
+        let result = Self::half_duplex_read(rx, timeout_us);
+        if interrupts_enabled {
+            Self::enable_interrupts();
+        }
+        result
+    }
+
     fn enable_rcc_clock(){
         unsafe {
             let rcc = &*stm32g431::RCC::ptr();
@@ -73,7 +187,7 @@ impl Serial {
         USART1_RX.configure(TYPE_SERIAL_USART1)
     }
 
-    fn enable_interrupts(){
+    pub fn enable_interrupts(){
         unsafe {
             let port = &*stm32g431::USART1::ptr();
             //RXNE Interrupt
@@ -81,7 +195,7 @@ impl Serial {
         }
     }
 
-    fn disable_interrupts(){
+    pub fn disable_interrupts(){
         unsafe {
             let port = &*stm32g431::USART1::ptr();
             //RXNE Interrupt
@@ -89,13 +203,136 @@ impl Serial {
         }
     }
 
+    pub fn interrupts_enabled() -> bool {
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            //RXNE Interrupt
+            (port.cr1.as_ptr().read() & (1 << 5)) > 0
+        }
+    }
+
     pub fn set_baudrate(baudrate: u32){
-        let usart_divider = APB2_FREQ / baudrate;
+        let usart_divider = Self::kernel_clock_freq() / baudrate;
         unsafe {
             let port = &*stm32g431::USART1::ptr();
+            // USART_BRR can only be written with UE bit 0 in USART_CR1 cleared
+            let enabled = (port.cr1.as_ptr().read() & (1 << 0)) > 0;
+            Self::enabled(false);
             //Program baudrate in USART_BRR
-            port.brr.as_ptr().write(usart_divider)
+            port.brr.as_ptr().write(usart_divider);
             //port.brr.as_ptr().write(1475)
+            SERIAL_BAUDRATE = baudrate;
+            if enabled {
+                Self::enabled(true);
+            }
+        }
+    }
+
+    pub fn get_baudrate() -> u32 {
+        unsafe {
+            SERIAL_BAUDRATE
+        }
+    }
+
+    // The host must send the detection character within timeout_us, ABREN is left cleared on every outcome
+    pub fn auto_baudrate(mode: AutoBaudRateMode, timeout_us: u32) -> Result<u32, SerialError> {
+        let interrupts_enabled = Self::interrupts_enabled();
+        Self::disable_interrupts();
+        Self::enabled(false);
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            // Set ABRMOD bits 22:21 in USART_CR2
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() & !(0x3 << 21));
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (mode.as_u32() << 21));
+            // Enable ABREN bit 20 in USART_CR2
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (1 << 20));
+        }
+        Self::enabled(true);
+
+        let result = Self::wait_for_auto_baudrate(timeout_us);
+
+        Self::enabled(false);
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            // Disable ABREN bit 20 in USART_CR2, BRR keeps the detected value
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() & !(1 << 20));
+        }
+        Self::enabled(true);
+        if interrupts_enabled {
+            Self::enable_interrupts();
+        }
+
+        let baudrate = result?;
+        unsafe {
+            SERIAL_BAUDRATE = baudrate;
+        }
+        Ok(baudrate)
+    }
+
+    fn wait_for_auto_baudrate(timeout_us: u32) -> Result<u32, SerialError> {
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            let mut elapsed_us = 0u32;
+
+            loop {
+                let port_isr = port.isr.read();
+
+                // Check ABRE bit 14 in USART_ISR, ABRF is also set on error
+                if (port_isr.bits() & (1 << 14)) > 0 {
+                    // Request a new auto baud rate detection in order to clear the flags
+                    port.rqr.as_ptr().write(1 << 0);
+                    return Err(SerialError::AutoBaudRateError)
+                }
+
+                // Check ABRF bit 15 in USART_ISR
+                if (port_isr.bits() & (1 << 15)) > 0 {
+                    break
+                }
+
+                if elapsed_us >= timeout_us {
+                    return Err(SerialError::TimeOut)
+                }
+                delay_us(1);
+                elapsed_us += 1;
+            }
+
+            // Discard the character used for the detection
+            while port.isr.read().rxne().bit_is_clear() {
+                if elapsed_us >= timeout_us {
+                    return Err(SerialError::TimeOut)
+                }
+                delay_us(1);
+                elapsed_us += 1;
+            }
+            port.rdr.as_ptr().read();
+
+            let brr = port.brr.as_ptr().read() & 0xFFFF;
+
+            if brr == 0 {
+                return Err(SerialError::AutoBaudRateError)
+            }
+
+            // Check OVER8 bit 15 in USART_CR1
+            if (port.cr1.as_ptr().read() & (1 << 15)) > 0 {
+                // BRR[2:0] holds USARTDIV[3:0] shifted 1 bit to the right
+                let usart_divider = (brr & 0xFFF0) | ((brr & 0x7) << 1);
+                Ok((2 * Self::kernel_clock_freq()) / usart_divider)
+            } else {
+                Ok(Self::kernel_clock_freq() / brr)
+            }
+        }
+    }
+
+    fn kernel_clock_freq() -> u32 {
+        unsafe {
+            let rcc = &*stm32g431::RCC::ptr();
+            // Read USART1SEL bits 1:0 in RCC_CCIPR
+            match rcc.ccipr.as_ptr().read() & 0x3 {
+                0 => APB2_FREQ,
+                1 => SYSCLK_FREQ,
+                2 => HSI16_FREQ,
+                _ => LSE_FREQ
+            }
         }
     }
 
@@ -159,6 +396,107 @@ impl Serial {
         }
     }
 
+    pub fn read_byte_timeout(timeout_us: u32) -> Result<u8, SerialError> {
+        let mut elapsed_us = 0u32;
+        loop {
+            match Self::read_byte() {
+                Err(SerialError::NoDataFound) => {
+                    if elapsed_us >= timeout_us {
+                        return Err(SerialError::TimeOut)
+                    }
+                    delay_us(1);
+                    elapsed_us += 1;
+                }
+                result => return result
+            }
+        }
+    }
+
+    pub fn flush_receiver() {
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            // Write ORECF bit 3 and FECF bit 1 in USART_ICR register
+            port.icr.as_ptr().write((1 << 3) | (1 << 1));
+            // Write RXFRQ bit 3 in USART_RQR register in order to discard the received data
+            port.rqr.as_ptr().write(1 << 3);
+        }
+    }
+
+    pub fn enable_lin_mode(break_length: LinBreakLength){
+        Self::enabled(false);
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            // Clear CLKEN bit 11 and STOP bits 13:12 in USART_CR2
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() & !((1 << 11) | (0x3 << 12)));
+            // Clear SCEN bit 5, HDSEL bit 3 and IREN bit 1 in USART_CR3
+            port.cr3.as_ptr().write(port.cr3.as_ptr().read() & !((1 << 5) | (1 << 3) | (1 << 1)));
+            // Set LBDL bit 5 in USART_CR2
+            match break_length {
+                LinBreakLength::Bits10 => port.cr2.as_ptr().write(port.cr2.as_ptr().read() & !(1 << 5)),
+                LinBreakLength::Bits11 => port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (1 << 5))
+            }
+            // Enable LINEN bit 14 in USART_CR2
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (1 << 14));
+        }
+        Self::enabled(true);
+    }
+
+    pub fn disable_lin_mode(){
+        Self::enabled(false);
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            // Disable LINEN bit 14 and LBDIE bit 6 in USART_CR2
+            port.cr2.as_ptr().write(port.cr2.as_ptr().read() & !((1 << 14) | (1 << 6)));
+        }
+        Self::enabled(true);
+    }
+
+    pub fn enable_lin_break_interrupt(enable: bool){
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            // LBDIE bit 6 in USART_CR2
+            if enable {
+                port.cr2.as_ptr().write(port.cr2.as_ptr().read() | (1 << 6))
+            } else {
+                port.cr2.as_ptr().write(port.cr2.as_ptr().read() & !(1 << 6))
+            }
+        }
+    }
+
+    pub fn send_break() -> Result<(), SerialError> {
+        // The break and its stop bit last less than two characters
+        let timeout_us = (20 * 1000000 / Self::get_baudrate()).max(1);
+        let mut elapsed_us = 0u32;
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            // Write SBKRQ bit 1 in USART_RQR
+            port.rqr.as_ptr().write(1 << 1);
+            // Wait for SBKF bit 18 in USART_ISR to be cleared at the end of the break
+            while (port.isr.as_ptr().read() & (1 << 18)) > 0 {
+                if elapsed_us >= timeout_us {
+                    return Err(SerialError::TimeOut)
+                }
+                delay_us(1);
+                elapsed_us += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn lin_break_detected() -> bool {
+        unsafe {
+            let port = &*stm32g431::USART1::ptr();
+            // Check LBDF bit 8 in USART_ISR
+            if (port.isr.as_ptr().read() & (1 << 8)) > 0 {
+                // Write LBDCF bit 8 in USART_ICR in order to clear LBDF flag
+                port.icr.as_ptr().write(1 << 8);
+                true
+            } else {
+                false
+            }
+        }
+    }
+
     pub fn clear_ore_flag() {
         unsafe {
             let port = &*stm32g431::USART1::ptr();
@@ -224,8 +562,36 @@ impl Serial {
 
 }
 
+impl SerialPort for Serial {
+    fn write_byte(&mut self, byte: u8) {
+        Serial::write_byte(byte)
+    }
+
+    // The USART1 handler does not read RDR, RXNEIE would make it fire again and again while the port is polled
+    fn read_byte_timeout(&mut self, timeout_us: u32) -> Result<u8, SerialError> {
+        Serial::disable_interrupts();
+        let result = Serial::read_byte_timeout(timeout_us);
+        if result == Err(SerialError::OverRun) {
+            Serial::clear_ore_flag();
+        }
+        result
+    }
+}
+
+impl Drop for Serial {
+    fn drop(&mut self) {
+        if self.restore_interrupts {
+            Serial::enable_interrupts();
+        }
+    }
+}
+
 static mut DEFAULT_HANDLER: fn() = default_handler;
 
+static mut SERIAL_BAUDRATE: u32 = 115200;
+
+static mut HALF_DUPLEX_TURNAROUND_US: u32 = 0;
+
 fn default_handler(){
     /* ******* */
 }
@@ -235,4 +601,15 @@ fn USART1() {
     unsafe {
         DEFAULT_HANDLER();
     }
+}
+
+impl Conversions for AutoBaudRateMode {
+    fn as_u32(&self) -> u32 {
+        match self {
+            AutoBaudRateMode::StartBit => 0,
+            AutoBaudRateMode::FallingEdge => 1,
+            AutoBaudRateMode::Frame0x7F => 2,
+            AutoBaudRateMode::Frame0x55 => 3,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,352 @@
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+use core::str;
+use crate::drivers::serial::{SerialError, SerialPort};
+
+const AT_MAX_LINE_LENGTH: usize = 256;
+const AT_POLL_INTERVAL_US: u32 = 1000;
+// Bounds poll_urc when the modem keeps sending
+const AT_URC_POLL_TIMEOUT_MS: u32 = 100;
+const AT_DATA_TERMINATOR: u8 = 0x1A;
+
+#[derive(PartialEq, Debug)]
+pub enum AtError {
+    TimeOut,
+    Error,
+    CmeError(u16),
+    CmsError(u16),
+    LineTooLong,
+    OverRun
+}
+
+#[derive(PartialEq, Debug)]
+pub enum AtFinal {
+    Ok,
+    // Index of the matching entry in the custom final responses
+    Custom(usize)
+}
+
+pub struct UrcHandler {
+    pub prefix: &'static str,
+    pub handler: fn(&str)
+}
+
+pub struct AtClient<P: SerialPort> {
+    pub port: P,
+    pub urc_handlers: Vec<UrcHandler>,
+    // Free running millisecond counter, delay::millis on the target, the timeouts do not depend on the received bytes
+    millis: fn() -> u32,
+    line: [u8; AT_MAX_LINE_LENGTH],
+    length: usize
+}
+
+enum Line {
+    Text(usize),
+    Prompt
+}
+
+impl<P: SerialPort> AtClient<P> {
+    pub fn new(port: P, millis: fn() -> u32) -> AtClient<P> {
+        AtClient {
+            port,
+            urc_handlers: Vec::new(),
+            millis,
+            line: [0; AT_MAX_LINE_LENGTH],
+            length: 0
+        }
+    }
+
+    pub fn on_urc(&mut self, prefix: &'static str, handler: fn(&str)) {
+        self.urc_handlers.push(UrcHandler { prefix, handler });
+    }
+
+    pub fn command(&mut self, command: &str, timeout_ms: u32, on_line: &mut dyn FnMut(&str)) -> Result<AtFinal, AtError> {
+        self.command_with_finals(command, &[], timeout_ms, on_line)
+    }
+
+    pub fn command_with_finals(&mut self, command: &str, custom_finals: &[&str], timeout_ms: u32, on_line: &mut dyn FnMut(&str)) -> Result<AtFinal, AtError> {
+        self.poll_urc();
+        self.send_command(command);
+        let start_ms = (self.millis)();
+        self.wait_for_final(command, custom_finals, start_ms, timeout_ms, on_line)
+    }
+
+    // Commands such as AT+CMGS or AT+CIPSEND answer with a "> " prompt before the data phase
+    pub fn command_with_data(&mut self, command: &str, data: &[u8], terminate: bool, custom_finals: &[&str], timeout_ms: u32, on_line: &mut dyn FnMut(&str)) -> Result<AtFinal, AtError> {
+        self.poll_urc();
+        self.send_command(command);
+
+        let start_ms = (self.millis)();
+        loop {
+            match self.read_line(start_ms, timeout_ms, true, false)? {
+                Line::Prompt => break,
+                Line::Text(length) => {
+                    // Some modems (ESP-AT) answer OK before the prompt, any other final response refuses the data phase
+                    match self.process_line(length, command, custom_finals, on_line) {
+                        Some(Ok(AtFinal::Ok)) | None => {}
+                        Some(result) => return result
+                    }
+                }
+            }
+        }
+
+        for &byte in data {
+            self.port.write_byte(byte);
+        }
+        if terminate {
+            self.port.write_byte(AT_DATA_TERMINATOR);
+        }
+
+        self.wait_for_final(command, custom_finals, start_ms, timeout_ms, on_line)
+    }
+
+    // Dispatches the unsolicited result codes received while no command is running, it returns at the first idle poll
+    pub fn poll_urc(&mut self) {
+        let start_ms = (self.millis)();
+        while let Ok(Line::Text(length)) = self.read_line(start_ms, AT_URC_POLL_TIMEOUT_MS, false, true) {
+            if let Some(text) = self.line_text(length) {
+                self.dispatch_urc(text);
+            }
+        }
+    }
+
+    fn send_command(&mut self, command: &str) {
+        for byte in command.bytes() {
+            self.port.write_byte(byte);
+        }
+        self.port.write_byte(b'\r');
+    }
+
+    fn wait_for_final(&mut self, command: &str, custom_finals: &[&str], start_ms: u32, timeout_ms: u32, on_line: &mut dyn FnMut(&str)) -> Result<AtFinal, AtError> {
+        loop {
+            if let Line::Text(length) = self.read_line(start_ms, timeout_ms, false, false)? {
+                if let Some(result) = self.process_line(length, command, custom_finals, on_line) {
+                    return result
+                }
+            }
+        }
+    }
+
+    fn process_line(&self, length: usize, command: &str, custom_finals: &[&str], on_line: &mut dyn FnMut(&str)) -> Option<Result<AtFinal, AtError>> {
+        let text = self.line_text(length)?;
+
+        // Command echo (ATE1)
+        if text == command {
+            return None
+        }
+
+        if text == "OK" {
+            return Some(Ok(AtFinal::Ok))
+        }
+        if text == "ERROR" {
+            return Some(Err(AtError::Error))
+        }
+        if let Some(code) = text.strip_prefix("+CME ERROR:") {
+            return Some(Err(AtError::CmeError(code.trim().parse().unwrap_or(0))))
+        }
+        if let Some(code) = text.strip_prefix("+CMS ERROR:") {
+            return Some(Err(AtError::CmsError(code.trim().parse().unwrap_or(0))))
+        }
+        if let Some(index) = custom_finals.iter().position(|&f| text == f) {
+            return Some(Ok(AtFinal::Custom(index)))
+        }
+
+        if !self.dispatch_urc(text) {
+            on_line(text);
+        }
+        None
+    }
+
+    fn line_text(&self, length: usize) -> Option<&str> {
+        // The space following a "> " prompt ends up in front of the next line
+        match str::from_utf8(&self.line[..length]) {
+            Ok(text) if !text.trim().is_empty() => Some(text.trim()),
+            _ => None
+        }
+    }
+
+    fn dispatch_urc(&self, text: &str) -> bool {
+        match self.urc_handlers.iter().find(|urc| text.starts_with(urc.prefix)) {
+            Some(urc) => {
+                (urc.handler)(text);
+                true
+            }
+            None => false
+        }
+    }
+
+    // The timeout counts from start_ms, a partial line stays in the buffer for the next call
+    fn read_line(&mut self, start_ms: u32, timeout_ms: u32, prompt_expected: bool, stop_when_idle: bool) -> Result<Line, AtError> {
+        loop {
+            match self.port.read_byte_timeout(AT_POLL_INTERVAL_US) {
+                Ok(byte) => match byte {
+                    b'\r' | b'\n' => {
+                        // Empty lines between responses are skipped
+                        if self.length > 0 {
+                            let length = self.length;
+                            self.length = 0;
+                            return Ok(Line::Text(length))
+                        }
+                    }
+                    b'>' if prompt_expected && self.length == 0 => return Ok(Line::Prompt),
+                    _ => {
+                        if self.length >= AT_MAX_LINE_LENGTH {
+                            self.length = 0;
+                            return Err(AtError::LineTooLong)
+                        }
+                        self.line[self.length] = byte;
+                        self.length += 1;
+                    }
+                },
+                Err(SerialError::OverRun) => {
+                    self.length = 0;
+                    return Err(AtError::OverRun)
+                }
+                Err(_) => {
+                    if stop_when_idle {
+                        return Err(AtError::TimeOut)
+                    }
+                }
+            }
+
+            if (self.millis)().wrapping_sub(start_ms) >= timeout_ms {
+                return Err(AtError::TimeOut)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::string::String;
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static NOW_MS: Cell<u32> = const { Cell::new(0) };
+    }
+
+    fn millis() -> u32 {
+        NOW_MS.with(|now| now.get())
+    }
+
+    // Scripted modem, the response comes once the command line is ended and every read takes one millisecond
+    struct MockModem {
+        response: Vec<u8>,
+        received: VecDeque<u8>,
+        sent: Vec<u8>,
+        // Sent over and over once the script is over
+        filler: Option<u8>
+    }
+
+    impl SerialPort for MockModem {
+        fn write_byte(&mut self, byte: u8) {
+            self.sent.push(byte);
+            if byte == b'\r' {
+                self.received.extend(self.response.drain(..));
+            }
+        }
+
+        fn read_byte_timeout(&mut self, _timeout_us: u32) -> Result<u8, SerialError> {
+            NOW_MS.with(|now| now.set(now.get().wrapping_add(1)));
+            match self.received.pop_front().or(self.filler) {
+                Some(byte) => Ok(byte),
+                None => Err(SerialError::TimeOut)
+            }
+        }
+    }
+
+    fn client(response: &str) -> AtClient<MockModem> {
+        NOW_MS.with(|now| now.set(0));
+        AtClient::new(MockModem {
+            response: response.bytes().collect(),
+            received: VecDeque::new(),
+            sent: Vec::new(),
+            filler: None
+        }, millis)
+    }
+
+    #[test]
+    fn ok_final_with_information_lines() {
+        let mut at = client("AT+CSQ\r\r\n+CSQ: 21,99\r\n\r\nOK\r\n");
+        let mut lines = Vec::new();
+        let result = at.command("AT+CSQ", 1000, &mut |line| lines.push(String::from(line)));
+        assert_eq!(result, Ok(AtFinal::Ok));
+        // The echo is skipped
+        assert_eq!(lines, ["+CSQ: 21,99"]);
+        assert_eq!(at.port.sent, b"AT+CSQ\r");
+    }
+
+    #[test]
+    fn error_final() {
+        let mut at = client("\r\nERROR\r\n");
+        assert_eq!(at.command("AT+XYZ", 1000, &mut |_| {}), Err(AtError::Error));
+    }
+
+    #[test]
+    fn cme_and_cms_errors_carry_the_code() {
+        let mut at = client("\r\n+CME ERROR: 10\r\n");
+        assert_eq!(at.command("AT+CPIN?", 1000, &mut |_| {}), Err(AtError::CmeError(10)));
+        let mut at = client("\r\n+CMS ERROR: 304\r\n");
+        assert_eq!(at.command("AT+CMGS=5", 1000, &mut |_| {}), Err(AtError::CmsError(304)));
+    }
+
+    #[test]
+    fn custom_final() {
+        let mut at = client("\r\nCONNECT\r\n");
+        assert_eq!(at.command_with_finals("ATD123", &["NO CARRIER", "CONNECT"], 1000, &mut |_| {}), Ok(AtFinal::Custom(1)));
+    }
+
+    #[test]
+    fn prompt_then_data_phase() {
+        let mut at = client("\r\n> \r\n+CMGS: 4\r\n\r\nOK\r\n");
+        let mut lines = Vec::new();
+        let result = at.command_with_data("AT+CMGS=5", b"hello", true, &[], 1000, &mut |line| lines.push(String::from(line)));
+        assert_eq!(result, Ok(AtFinal::Ok));
+        assert_eq!(lines, ["+CMGS: 4"]);
+        assert_eq!(at.port.sent, b"AT+CMGS=5\rhello\x1A");
+    }
+
+    #[test]
+    fn error_instead_of_prompt_skips_the_data() {
+        let mut at = client("\r\nERROR\r\n");
+        assert_eq!(at.command_with_data("AT+CIPSEND=5", b"hello", false, &[], 1000, &mut |_| {}), Err(AtError::Error));
+        assert_eq!(at.port.sent, b"AT+CIPSEND=5\r");
+    }
+
+    #[test]
+    fn timeout_without_answer() {
+        let mut at = client("");
+        assert_eq!(at.command("AT", 50, &mut |_| {}), Err(AtError::TimeOut));
+        assert!((50..60).contains(&millis()));
+    }
+
+    #[test]
+    fn timeout_while_the_modem_keeps_sending() {
+        let mut at = client("\r\n");
+        // Empty lines never end the command, only the clock does
+        at.port.filler = Some(b'\n');
+        assert_eq!(at.command("AT", 50, &mut |_| {}), Err(AtError::TimeOut));
+        assert!((50..200).contains(&millis()));
+    }
+
+    #[test]
+    fn urc_is_dispatched_before_the_command() {
+        std::thread_local! {
+            static URC_COUNT: Cell<u32> = const { Cell::new(0) };
+        }
+        fn on_ring(line: &str) {
+            assert_eq!(line, "RING");
+            URC_COUNT.with(|count| count.set(count.get() + 1));
+        }
+
+        let mut at = client("");
+        at.port.received.extend(b"\r\nRING\r\n");
+        at.on_urc("RING", on_ring);
+        at.poll_urc();
+        assert_eq!(URC_COUNT.with(|count| count.get()), 1);
+        assert!(at.port.sent.is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+use crate::drivers::serial::{LinBreakLength, Serial, SerialError};
+use crate::core::delay::delay_us;
+
+// The frame encoding is part of the host-tested library
+pub use stm32g431xx::drivers::serial::lin_frame::{classic_checksum, enhanced_checksum, identifier_from_protected, protected_identifier};
+
+const LIN_SYNC_FIELD: u8 = 0x55;
+const LIN_MAX_DATA_LENGTH: usize = 8;
+// Break (13 bits) + delimiter (1 bit) + sync field (10 bits) + identifier (10 bits)
+const LIN_HEADER_BITS: u32 = 34;
+// Diagnostic frames always use the classic checksum
+const LIN_MASTER_REQUEST_ID: u8 = 0x3C;
+const LIN_SLAVE_RESPONSE_ID: u8 = 0x3D;
+
+#[derive(PartialEq)]
+pub enum LinError {
+    TimeOut,
+    OverRun,
+    SyncFieldError,
+    ParityError,
+    ChecksumError,
+    ReadBackError,
+    InvalidLength,
+    EmptySchedule
+}
+
+pub enum LinChecksumModel {
+    Classic,
+    Enhanced
+}
+
+pub enum LinFrameType {
+    // The node sends the response of the frame
+    Publish(fn(&mut [u8])),
+    // The node receives the response of the frame
+    Subscribe(fn(&[u8]))
+}
+
+pub struct LinFrame {
+    pub id: u8,
+    pub length: usize,
+    pub checksum_model: LinChecksumModel,
+    pub frame_type: LinFrameType
+}
+
+pub struct LinScheduleEntry {
+    pub frame: LinFrame,
+    // Frame slot time in microseconds, measured from the start of the break
+    pub slot_time_us: u32
+}
+
+pub struct LinMaster {
+    pub schedule: Vec<LinScheduleEntry>,
+    current_entry: usize
+}
+
+pub struct LinSlave {
+    pub frames: Vec<LinFrame>
+}
+
+impl LinMaster {
+    pub fn new(schedule: Vec<LinScheduleEntry>) -> LinMaster {
+        LinMaster {
+            schedule,
+            current_entry: 0
+        }
+    }
+
+    pub fn begin(baudrate: u32) {
+        begin_lin_node(baudrate)
+    }
+
+    pub fn run_next_slot(&mut self) -> Result<u8, LinError> {
+        if self.schedule.is_empty() {
+            return Err(LinError::EmptySchedule)
+        }
+
+        let entry = &self.schedule[self.current_entry];
+        self.current_entry = (self.current_entry + 1) % self.schedule.len();
+
+        let result = Self::process_frame(&entry.frame);
+
+        // The driver has no timer, so the remaining slot time is estimated from the frame length
+        let frame_time_us = (LIN_HEADER_BITS + (entry.frame.length as u32 + 1) * 10) * bit_time_us();
+        if entry.slot_time_us > frame_time_us {
+            delay_us(entry.slot_time_us - frame_time_us);
+        }
+
+        result.map(|_| entry.frame.id)
+    }
+
+    pub fn run_schedule_cycle(&mut self) -> Result<(), LinError> {
+        for _ in 0..self.schedule.len() {
+            self.run_next_slot()?;
+        }
+        Ok(())
+    }
+
+    pub fn process_frame(frame: &LinFrame) -> Result<(), LinError> {
+        if frame.length == 0 || frame.length > LIN_MAX_DATA_LENGTH {
+            return Err(LinError::InvalidLength)
+        }
+
+        Self::send_header(frame.id)?;
+
+        let mut data = [0u8; LIN_MAX_DATA_LENGTH];
+        let data = &mut data[..frame.length];
+
+        match frame.frame_type {
+            LinFrameType::Publish(fill) => {
+                fill(data);
+                write_response(frame, data)
+            }
+            LinFrameType::Subscribe(on_response) => {
+                read_response(frame, data)?;
+                on_response(data);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn send_header(id: u8) -> Result<(), LinError> {
+        Serial::flush_receiver();
+        Serial::send_break().map_err(lin_error)?;
+
+        // The transceiver echoes the break back, wait until it is detected
+        let mut elapsed_us = 0u32;
+        while !Serial::lin_break_detected() {
+            if elapsed_us >= byte_time_us() * 2 {
+                return Err(LinError::ReadBackError)
+            }
+            delay_us(1);
+            elapsed_us += 1;
+        }
+
+        // The break is received as a 0x00 character with a framing error
+        Serial::flush_receiver();
+
+        write_with_read_back(&[LIN_SYNC_FIELD, protected_identifier(id)])
+    }
+}
+
+impl LinSlave {
+    pub fn new(frames: Vec<LinFrame>) -> LinSlave {
+        LinSlave {
+            frames
+        }
+    }
+
+    pub fn begin(baudrate: u32) {
+        begin_lin_node(baudrate)
+    }
+
+    pub fn poll(&self) -> Result<Option<u8>, LinError> {
+        if !Serial::lin_break_detected() {
+            return Ok(None)
+        }
+
+        // The break is received as a 0x00 character with a framing error
+        Serial::flush_receiver();
+
+        let header_timeout_us = LIN_HEADER_BITS * bit_time_us() * 14 / 10;
+
+        let sync = Serial::read_byte_timeout(header_timeout_us).map_err(lin_error)?;
+        if sync != LIN_SYNC_FIELD {
+            return Err(LinError::SyncFieldError)
+        }
+
+        let pid = Serial::read_byte_timeout(header_timeout_us).map_err(lin_error)?;
+        let id = identifier_from_protected(pid).ok_or(LinError::ParityError)?;
+
+        let frame = match self.frames.iter().find(|frame| frame.id == id) {
+            Some(frame) => frame,
+            // The frame is handled by another node
+            None => return Ok(None)
+        };
+
+        if frame.length == 0 || frame.length > LIN_MAX_DATA_LENGTH {
+            return Err(LinError::InvalidLength)
+        }
+
+        let mut data = [0u8; LIN_MAX_DATA_LENGTH];
+        let data = &mut data[..frame.length];
+
+        match frame.frame_type {
+            LinFrameType::Publish(fill) => {
+                fill(data);
+                write_response(frame, data)?;
+            }
+            LinFrameType::Subscribe(on_response) => {
+                read_response(frame, data)?;
+                on_response(data);
+            }
+        }
+
+        Ok(Some(id))
+    }
+}
+
+fn frame_checksum(frame: &LinFrame, data: &[u8]) -> u8 {
+    match frame.checksum_model {
+        LinChecksumModel::Enhanced if frame.id != LIN_MASTER_REQUEST_ID && frame.id != LIN_SLAVE_RESPONSE_ID => {
+            enhanced_checksum(protected_identifier(frame.id), data)
+        }
+        _ => classic_checksum(data)
+    }
+}
+
+fn write_response(frame: &LinFrame, data: &[u8]) -> Result<(), LinError> {
+    write_with_read_back(data)?;
+    write_with_read_back(&[frame_checksum(frame, data)])
+}
+
+fn read_response(frame: &LinFrame, data: &mut [u8]) -> Result<(), LinError> {
+    // The response may take up to 140% of its nominal time
+    let response_timeout_us = (data.len() as u32 + 1) * byte_time_us() * 14 / 10;
+
+    for byte in data.iter_mut() {
+        *byte = Serial::read_byte_timeout(response_timeout_us).map_err(lin_error)?;
+    }
+
+    let checksum = Serial::read_byte_timeout(response_timeout_us).map_err(lin_error)?;
+
+    if checksum != frame_checksum(frame, data) {
+        return Err(LinError::ChecksumError)
+    }
+    Ok(())
+}
+
+fn write_with_read_back(data: &[u8]) -> Result<(), LinError> {
+    // The bus is single wire, every transmitted byte is received back through the transceiver
+    for &byte in data {
+        Serial::write_byte(byte);
+        let echo = Serial::read_byte_timeout(byte_time_us() * 2).map_err(lin_error)?;
+        if echo != byte {
+            return Err(LinError::ReadBackError)
+        }
+    }
+    Ok(())
+}
+
+fn begin_lin_node(baudrate: u32) {
+    // Frames are handled by polling
+    Serial::disable_interrupts();
+    Serial::set_baudrate(baudrate);
+    Serial::enable_lin_mode(LinBreakLength::Bits11);
+    Serial::flush_receiver();
+}
+
+fn lin_error(error: SerialError) -> LinError {
+    match error {
+        SerialError::OverRun => {
+            Serial::clear_ore_flag();
+            LinError::OverRun
+        }
+        _ => LinError::TimeOut
+    }
+}
+
+fn bit_time_us() -> u32 {
+    (1000000 / Serial::get_baudrate()).max(1)
+}
+
+fn byte_time_us() -> u32 {
+    bit_time_us() * 10
+}
\ No newline at end of file
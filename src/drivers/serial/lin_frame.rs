@@ -0,0 +1,80 @@
+// Protected identifier and checksum of a LIN 2.x frame
+
+// The identifier is 6 bits, the two parity bits P0 and P1 fill the byte sent after the sync field
+pub fn protected_identifier(id: u8) -> u8 {
+    let id = id & 0x3F;
+    let bit = |n: u8| (id >> n) & 1;
+    // P0 = ID0 ^ ID1 ^ ID2 ^ ID4
+    let p0 = bit(0) ^ bit(1) ^ bit(2) ^ bit(4);
+    // P1 = !(ID1 ^ ID3 ^ ID4 ^ ID5)
+    let p1 = !(bit(1) ^ bit(3) ^ bit(4) ^ bit(5)) & 1;
+    id | (p0 << 6) | (p1 << 7)
+}
+
+// None when the parity bits do not match the identifier
+pub fn identifier_from_protected(pid: u8) -> Option<u8> {
+    let id = pid & 0x3F;
+    if protected_identifier(id) == pid {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+// LIN 1.x checksum over the data bytes only, also used by the diagnostic frames
+pub fn classic_checksum(data: &[u8]) -> u8 {
+    checksum(0, data)
+}
+
+// LIN 2.x checksum, the protected identifier is part of the sum
+pub fn enhanced_checksum(pid: u8, data: &[u8]) -> u8 {
+    checksum(pid as u16, data)
+}
+
+fn checksum(initial: u16, data: &[u8]) -> u8 {
+    // Sum with carry, the result is inverted
+    let mut sum = initial;
+    for &byte in data {
+        sum += byte as u16;
+        if sum > 0xFF {
+            sum -= 0xFF;
+        }
+    }
+    !(sum as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protected_identifiers_of_the_diagnostic_frames() {
+        assert_eq!(protected_identifier(0x3C), 0x3C);
+        assert_eq!(protected_identifier(0x3D), 0x7D);
+        assert_eq!(protected_identifier(0x00), 0x80);
+        assert_eq!(protected_identifier(0x10), 0x50);
+    }
+
+    #[test]
+    fn identifier_with_wrong_parity_is_rejected() {
+        assert_eq!(identifier_from_protected(0x7D), Some(0x3D));
+        assert_eq!(identifier_from_protected(0x3D), None);
+        for id in 0..0x40 {
+            assert_eq!(identifier_from_protected(protected_identifier(id)), Some(id));
+        }
+    }
+
+    #[test]
+    fn checksums_of_the_specification_example() {
+        // LIN 2.x example frame, PID 0x4A with the data 0x55 0x93 0xE5
+        let data = [0x55, 0x93, 0xE5];
+        assert_eq!(enhanced_checksum(0x4A, &data), 0xE6);
+        assert_eq!(classic_checksum(&data), 0x31);
+    }
+
+    #[test]
+    fn carry_is_added_back() {
+        assert_eq!(classic_checksum(&[0xFF, 0x01]), 0xFE);
+        assert_eq!(classic_checksum(&[]), 0xFF);
+    }
+}
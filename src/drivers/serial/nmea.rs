@@ -0,0 +1,645 @@
+#![allow(dead_code)]
+
+use core::str;
+use crate::drivers::serial::{SerialError, SerialPort};
+
+// Longest sentence allowed by NMEA 0183 is 82 characters including "$" and "\r\n"
+const NMEA_MAX_SENTENCE_LENGTH: usize = 82;
+const NMEA_MAX_SATELLITES: usize = 32;
+// 4 satellites of 4 fields and the NMEA 4.10 signal identifier
+const NMEA_GSV_MAX_FIELDS: usize = 17;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum NmeaError {
+    BufferOverflow,
+    MissingChecksum,
+    ChecksumMismatch,
+    InvalidField,
+    UnsupportedSentence,
+    // The port lost bytes, the sentence being received was dropped
+    DataLost
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum NmeaSentence {
+    Gga,
+    Rmc,
+    Gsa,
+    Gsv,
+    Vtg,
+    Zda
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum FixQuality {
+    Invalid,
+    Gps,
+    DifferentialGps,
+    Pps,
+    RealTimeKinematic,
+    FloatRealTimeKinematic,
+    Estimated,
+    Manual,
+    Simulation
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum FixMode {
+    NoFix,
+    Fix2D,
+    Fix3D
+}
+
+#[derive(Clone, Copy)]
+pub struct Position {
+    // Decimal degrees, negative to the south and to the west
+    pub latitude: f64,
+    pub longitude: f64,
+    // Meters above mean sea level
+    pub altitude: f32,
+    pub geoid_separation: f32
+}
+
+#[derive(Clone, Copy)]
+pub struct UtcTime {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16
+}
+
+#[derive(Clone, Copy)]
+pub struct UtcDate {
+    pub day: u8,
+    pub month: u8,
+    pub year: u16
+}
+
+#[derive(Clone, Copy)]
+pub struct SatelliteInfo {
+    pub prn: u8,
+    pub elevation: u8,
+    pub azimuth: u16,
+    pub snr: Option<u8>
+}
+
+#[derive(Clone, Copy)]
+pub struct GpsData {
+    pub position: Position,
+    pub time: UtcTime,
+    pub date: UtcDate,
+    pub fix_quality: FixQuality,
+    pub fix_mode: FixMode,
+    // RMC status, A = valid, V = warning
+    pub valid: bool,
+    pub satellites_used: u8,
+    pub used_prns: [u8; 12],
+    pub pdop: f32,
+    pub hdop: f32,
+    pub vdop: f32,
+    pub speed_knots: f32,
+    pub speed_kmh: f32,
+    // Degrees from true north
+    pub course: f32,
+    pub satellites_in_view: u8,
+    pub satellites: [SatelliteInfo; NMEA_MAX_SATELLITES],
+    // Local time zone offset from ZDA, in minutes
+    pub local_zone_offset: i16
+}
+
+pub struct NmeaParser {
+    buffer: [u8; NMEA_MAX_SENTENCE_LENGTH],
+    length: usize,
+    receiving: bool,
+    pub data: GpsData
+}
+
+impl NmeaParser {
+    pub const fn new() -> NmeaParser {
+        NmeaParser {
+            buffer: [0; NMEA_MAX_SENTENCE_LENGTH],
+            length: 0,
+            receiving: false,
+            data: GpsData::new()
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<Result<NmeaSentence, NmeaError>> {
+        match byte {
+            b'$' => {
+                self.length = 0;
+                self.receiving = true;
+                None
+            }
+            b'\r' | b'\n' => {
+                if !self.receiving {
+                    return None
+                }
+                self.receiving = false;
+                let length = self.length;
+                // Copy the sentence out of the receive buffer, the parser updates self.data
+                let buffer = self.buffer;
+                Some(self.parse_sentence(&buffer[..length]))
+            }
+            _ => {
+                if !self.receiving {
+                    return None
+                }
+                if self.length >= NMEA_MAX_SENTENCE_LENGTH {
+                    self.receiving = false;
+                    return Some(Err(NmeaError::BufferOverflow))
+                }
+                self.buffer[self.length] = byte;
+                self.length += 1;
+                None
+            }
+        }
+    }
+
+    // Drains the bytes already received by the port, use an interrupt-fed port such as Usart2 at GPS rates
+    pub fn poll_serial(&mut self, port: &mut impl SerialPort) -> Option<Result<NmeaSentence, NmeaError>> {
+        let mut last = None;
+        loop {
+            match port.read_byte_timeout(0) {
+                Ok(byte) => {
+                    if let Some(result) = self.feed(byte) {
+                        last = Some(result);
+                    }
+                }
+                Err(SerialError::OverRun) => {
+                    self.receiving = false;
+                    last = Some(Err(NmeaError::DataLost));
+                }
+                Err(_) => return last
+            }
+        }
+    }
+
+    // Parses a sentence without the leading "$" and the trailing "\r\n"
+    pub fn parse_sentence(&mut self, sentence: &[u8]) -> Result<NmeaSentence, NmeaError> {
+        let payload = verify_checksum(sentence)?;
+
+        let mut fields = payload.split(|&b| b == b',');
+        let address = fields.next().ok_or(NmeaError::InvalidField)?;
+
+        // Two characters talker identifier (GP, GN, GL, GA, GB) followed by the sentence formatter
+        if address.len() != 5 {
+            return Err(NmeaError::UnsupportedSentence)
+        }
+
+        match &address[2..] {
+            b"GGA" => self.parse_gga(&mut fields).map(|_| NmeaSentence::Gga),
+            b"RMC" => self.parse_rmc(&mut fields).map(|_| NmeaSentence::Rmc),
+            b"GSA" => self.parse_gsa(&mut fields).map(|_| NmeaSentence::Gsa),
+            b"GSV" => self.parse_gsv(&mut fields).map(|_| NmeaSentence::Gsv),
+            b"VTG" => self.parse_vtg(&mut fields).map(|_| NmeaSentence::Vtg),
+            b"ZDA" => self.parse_zda(&mut fields).map(|_| NmeaSentence::Zda),
+            _ => Err(NmeaError::UnsupportedSentence)
+        }
+    }
+
+    fn parse_gga<'a>(&mut self, fields: &mut impl Iterator<Item = &'a [u8]>) -> Result<(), NmeaError> {
+        let time = parse_time(next_field(fields)?)?;
+        let latitude = parse_coordinate(next_field(fields)?, next_field(fields)?)?;
+        let longitude = parse_coordinate(next_field(fields)?, next_field(fields)?)?;
+        let fix_quality = parse_fix_quality(next_field(fields)?)?;
+        let satellites_used = parse_number::<u8>(next_field(fields)?)?;
+        let hdop = parse_number::<f32>(next_field(fields)?)?;
+        let altitude = parse_number::<f32>(next_field(fields)?)?;
+        // Altitude units
+        next_field(fields)?;
+        let geoid_separation = parse_number::<f32>(next_field(fields)?)?;
+
+        if let Some(time) = time {
+            self.data.time = time;
+        }
+        if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+            self.data.position.latitude = latitude;
+            self.data.position.longitude = longitude;
+        }
+        self.data.fix_quality = fix_quality;
+        self.data.satellites_used = satellites_used.unwrap_or(0);
+        if let Some(hdop) = hdop {
+            self.data.hdop = hdop;
+        }
+        if let Some(altitude) = altitude {
+            self.data.position.altitude = altitude;
+        }
+        if let Some(geoid_separation) = geoid_separation {
+            self.data.position.geoid_separation = geoid_separation;
+        }
+        Ok(())
+    }
+
+    fn parse_rmc<'a>(&mut self, fields: &mut impl Iterator<Item = &'a [u8]>) -> Result<(), NmeaError> {
+        let time = parse_time(next_field(fields)?)?;
+        let valid = next_field(fields)? == b"A";
+        let latitude = parse_coordinate(next_field(fields)?, next_field(fields)?)?;
+        let longitude = parse_coordinate(next_field(fields)?, next_field(fields)?)?;
+        let speed_knots = parse_number::<f32>(next_field(fields)?)?;
+        let course = parse_number::<f32>(next_field(fields)?)?;
+        let date = parse_date(next_field(fields)?)?;
+
+        if let Some(time) = time {
+            self.data.time = time;
+        }
+        self.data.valid = valid;
+        if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+            self.data.position.latitude = latitude;
+            self.data.position.longitude = longitude;
+        }
+        if let Some(speed_knots) = speed_knots {
+            self.data.speed_knots = speed_knots;
+        }
+        if let Some(course) = course {
+            self.data.course = course;
+        }
+        if let Some(date) = date {
+            self.data.date = date;
+        }
+        Ok(())
+    }
+
+    fn parse_gsa<'a>(&mut self, fields: &mut impl Iterator<Item = &'a [u8]>) -> Result<(), NmeaError> {
+        // Selection mode, M = manual, A = automatic
+        next_field(fields)?;
+        let fix_mode = match next_field(fields)? {
+            b"2" => FixMode::Fix2D,
+            b"3" => FixMode::Fix3D,
+            _ => FixMode::NoFix
+        };
+
+        let mut used_prns = [0u8; 12];
+        for prn in used_prns.iter_mut() {
+            *prn = parse_number::<u8>(next_field(fields)?)?.unwrap_or(0);
+        }
+
+        let pdop = parse_number::<f32>(next_field(fields)?)?;
+        let hdop = parse_number::<f32>(next_field(fields)?)?;
+        let vdop = parse_number::<f32>(next_field(fields)?)?;
+
+        self.data.fix_mode = fix_mode;
+        self.data.used_prns = used_prns;
+        if let Some(pdop) = pdop {
+            self.data.pdop = pdop;
+        }
+        if let Some(hdop) = hdop {
+            self.data.hdop = hdop;
+        }
+        if let Some(vdop) = vdop {
+            self.data.vdop = vdop;
+        }
+        Ok(())
+    }
+
+    fn parse_gsv<'a>(&mut self, fields: &mut impl Iterator<Item = &'a [u8]>) -> Result<(), NmeaError> {
+        // Number of messages
+        next_field(fields)?;
+        let message_number = parse_number::<usize>(next_field(fields)?)?.ok_or(NmeaError::InvalidField)?;
+        let satellites_in_view = parse_number::<u8>(next_field(fields)?)?.unwrap_or(0);
+
+        if message_number == 0 {
+            return Err(NmeaError::InvalidField)
+        }
+
+        self.data.satellites_in_view = satellites_in_view;
+
+        // Every message carries up to 4 satellites of 4 fields, NMEA 4.10 adds a signal identifier after them
+        let mut satellite_fields: [&[u8]; NMEA_GSV_MAX_FIELDS] = [&[]; NMEA_GSV_MAX_FIELDS];
+        let mut count = 0;
+        for field in fields {
+            if count >= NMEA_GSV_MAX_FIELDS {
+                return Err(NmeaError::InvalidField)
+            }
+            satellite_fields[count] = field;
+            count += 1;
+        }
+        if count % 4 > 1 {
+            return Err(NmeaError::InvalidField)
+        }
+
+        let first = (message_number - 1) * 4;
+        for (index, satellite) in (first..).zip(satellite_fields[..count - count % 4].chunks(4)) {
+            let prn = parse_number::<u8>(satellite[0])?;
+            let elevation = parse_number::<u8>(satellite[1])?;
+            let azimuth = parse_number::<u16>(satellite[2])?;
+            let snr = parse_number::<u8>(satellite[3])?;
+
+            if let (Some(prn), true) = (prn, index < NMEA_MAX_SATELLITES) {
+                self.data.satellites[index] = SatelliteInfo {
+                    prn,
+                    elevation: elevation.unwrap_or(0),
+                    azimuth: azimuth.unwrap_or(0),
+                    snr
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_vtg<'a>(&mut self, fields: &mut impl Iterator<Item = &'a [u8]>) -> Result<(), NmeaError> {
+        let course = parse_number::<f32>(next_field(fields)?)?;
+        // T, magnetic course, M
+        next_field(fields)?;
+        next_field(fields)?;
+        next_field(fields)?;
+        let speed_knots = parse_number::<f32>(next_field(fields)?)?;
+        // N
+        next_field(fields)?;
+        let speed_kmh = parse_number::<f32>(next_field(fields)?)?;
+
+        if let Some(course) = course {
+            self.data.course = course;
+        }
+        if let Some(speed_knots) = speed_knots {
+            self.data.speed_knots = speed_knots;
+        }
+        if let Some(speed_kmh) = speed_kmh {
+            self.data.speed_kmh = speed_kmh;
+        }
+        Ok(())
+    }
+
+    fn parse_zda<'a>(&mut self, fields: &mut impl Iterator<Item = &'a [u8]>) -> Result<(), NmeaError> {
+        let time = parse_time(next_field(fields)?)?;
+        let day = parse_number::<u8>(next_field(fields)?)?;
+        let month = parse_number::<u8>(next_field(fields)?)?;
+        let year = parse_number::<u16>(next_field(fields)?)?;
+        let zone_hours = parse_number::<i16>(next_field(fields)?)?.unwrap_or(0);
+        let zone_minutes = parse_number::<i16>(next_field(fields)?)?.unwrap_or(0);
+
+        if let Some(time) = time {
+            self.data.time = time;
+        }
+        if let (Some(day), Some(month), Some(year)) = (day, month, year) {
+            self.data.date = UtcDate { day, month, year };
+        }
+        self.data.local_zone_offset = if zone_hours < 0 {
+            zone_hours * 60 - zone_minutes
+        } else {
+            zone_hours * 60 + zone_minutes
+        };
+        Ok(())
+    }
+}
+
+impl Default for NmeaParser {
+    fn default() -> NmeaParser {
+        NmeaParser::new()
+    }
+}
+
+impl GpsData {
+    pub const fn new() -> GpsData {
+        GpsData {
+            position: Position {
+                latitude: 0.0,
+                longitude: 0.0,
+                altitude: 0.0,
+                geoid_separation: 0.0
+            },
+            time: UtcTime {
+                hour: 0,
+                minute: 0,
+                second: 0,
+                millisecond: 0
+            },
+            date: UtcDate {
+                day: 0,
+                month: 0,
+                year: 0
+            },
+            fix_quality: FixQuality::Invalid,
+            fix_mode: FixMode::NoFix,
+            valid: false,
+            satellites_used: 0,
+            used_prns: [0; 12],
+            pdop: 0.0,
+            hdop: 0.0,
+            vdop: 0.0,
+            speed_knots: 0.0,
+            speed_kmh: 0.0,
+            course: 0.0,
+            satellites_in_view: 0,
+            satellites: [SatelliteInfo { prn: 0, elevation: 0, azimuth: 0, snr: None }; NMEA_MAX_SATELLITES],
+            local_zone_offset: 0
+        }
+    }
+
+    pub fn has_fix(&self) -> bool {
+        self.fix_quality != FixQuality::Invalid
+    }
+}
+
+impl Default for GpsData {
+    fn default() -> GpsData {
+        GpsData::new()
+    }
+}
+
+fn verify_checksum(sentence: &[u8]) -> Result<&[u8], NmeaError> {
+    let separator = sentence.iter().position(|&b| b == b'*').ok_or(NmeaError::MissingChecksum)?;
+
+    let (payload, checksum) = (&sentence[..separator], &sentence[(separator + 1)..]);
+
+    if checksum.len() != 2 {
+        return Err(NmeaError::MissingChecksum)
+    }
+
+    let expected = (hex_digit(checksum[0])? << 4) | hex_digit(checksum[1])?;
+    // XOR of every character between "$" and "*"
+    let calculated = payload.iter().fold(0u8, |sum, &b| sum ^ b);
+
+    if expected != calculated {
+        return Err(NmeaError::ChecksumMismatch)
+    }
+    Ok(payload)
+}
+
+fn hex_digit(c: u8) -> Result<u8, NmeaError> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        _ => Err(NmeaError::InvalidField)
+    }
+}
+
+fn next_field<'a>(fields: &mut impl Iterator<Item = &'a [u8]>) -> Result<&'a [u8], NmeaError> {
+    fields.next().ok_or(NmeaError::InvalidField)
+}
+
+// Empty fields are valid in NMEA and mean that the value is not available
+fn parse_number<T: str::FromStr>(field: &[u8]) -> Result<Option<T>, NmeaError> {
+    if field.is_empty() {
+        return Ok(None)
+    }
+    str::from_utf8(field)
+        .ok()
+        .and_then(|s| s.parse::<T>().ok())
+        .map(Some)
+        .ok_or(NmeaError::InvalidField)
+}
+
+fn parse_two_digits(field: &[u8]) -> Result<u8, NmeaError> {
+    if field.len() < 2 || !field[0].is_ascii_digit() || !field[1].is_ascii_digit() {
+        return Err(NmeaError::InvalidField)
+    }
+    Ok((field[0] - b'0') * 10 + (field[1] - b'0'))
+}
+
+fn parse_time(field: &[u8]) -> Result<Option<UtcTime>, NmeaError> {
+    // hhmmss.sss
+    if field.is_empty() {
+        return Ok(None)
+    }
+    if field.len() < 6 {
+        return Err(NmeaError::InvalidField)
+    }
+
+    let fraction = parse_number::<f32>(&field[6..])?.unwrap_or(0.0);
+
+    Ok(Some(UtcTime {
+        hour: parse_two_digits(&field[0..2])?,
+        minute: parse_two_digits(&field[2..4])?,
+        second: parse_two_digits(&field[4..6])?,
+        millisecond: (fraction * 1000.0) as u16
+    }))
+}
+
+fn parse_date(field: &[u8]) -> Result<Option<UtcDate>, NmeaError> {
+    // ddmmyy
+    if field.is_empty() {
+        return Ok(None)
+    }
+    if field.len() != 6 {
+        return Err(NmeaError::InvalidField)
+    }
+
+    Ok(Some(UtcDate {
+        day: parse_two_digits(&field[0..2])?,
+        month: parse_two_digits(&field[2..4])?,
+        year: 2000 + parse_two_digits(&field[4..6])? as u16
+    }))
+}
+
+fn parse_coordinate(field: &[u8], hemisphere: &[u8]) -> Result<Option<f64>, NmeaError> {
+    // ddmm.mmmm for latitude, dddmm.mmmm for longitude
+    let value = match parse_number::<f64>(field)? {
+        Some(value) => value,
+        None => return Ok(None)
+    };
+
+    let degrees = ((value / 100.0) as u32) as f64;
+    let minutes = value - degrees * 100.0;
+    let coordinate = degrees + minutes / 60.0;
+
+    match hemisphere {
+        b"N" | b"E" => Ok(Some(coordinate)),
+        b"S" | b"W" => Ok(Some(-coordinate)),
+        _ => Err(NmeaError::InvalidField)
+    }
+}
+
+fn parse_fix_quality(field: &[u8]) -> Result<FixQuality, NmeaError> {
+    match field {
+        b"" | b"0" => Ok(FixQuality::Invalid),
+        b"1" => Ok(FixQuality::Gps),
+        b"2" => Ok(FixQuality::DifferentialGps),
+        b"3" => Ok(FixQuality::Pps),
+        b"4" => Ok(FixQuality::RealTimeKinematic),
+        b"5" => Ok(FixQuality::FloatRealTimeKinematic),
+        b"6" => Ok(FixQuality::Estimated),
+        b"7" => Ok(FixQuality::Manual),
+        b"8" => Ok(FixQuality::Simulation),
+        _ => Err(NmeaError::InvalidField)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::vec::Vec;
+
+    fn parse(parser: &mut NmeaParser, payload: &str) -> Result<NmeaSentence, NmeaError> {
+        let checksum = payload.bytes().fold(0u8, |sum, b| sum ^ b);
+        let sentence = format!("{}*{:02X}", payload, checksum);
+        parser.parse_sentence(sentence.as_bytes())
+    }
+
+    #[test]
+    fn feed_parses_a_complete_sentence() {
+        let mut parser = NmeaParser::new();
+        let line = b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+        let results: Vec<_> = line.iter().filter_map(|&b| parser.feed(b)).collect();
+        assert_eq!(results, [Ok(NmeaSentence::Gga)]);
+        assert_eq!(parser.data.fix_quality, FixQuality::Gps);
+        assert_eq!(parser.data.satellites_used, 8);
+        assert!((parser.data.position.latitude - 48.1173).abs() < 1e-6);
+        assert!((parser.data.position.longitude - 11.516667).abs() < 1e-6);
+    }
+
+    #[test]
+    fn gsv_with_signal_id_and_less_than_four_satellites() {
+        let mut parser = NmeaParser::new();
+        assert_eq!(parse(&mut parser, "GPGSV,3,3,10,07,79,048,42,02,51,062,43,1"), Ok(NmeaSentence::Gsv));
+        assert_eq!(parser.data.satellites_in_view, 10);
+        assert_eq!(parser.data.satellites[8].prn, 7);
+        assert_eq!(parser.data.satellites[9].prn, 2);
+        assert_eq!(parser.data.satellites[9].snr, Some(43));
+    }
+
+    #[test]
+    fn gsv_with_signal_id_and_four_satellites() {
+        let mut parser = NmeaParser::new();
+        assert_eq!(parse(&mut parser, "GLGSV,2,1,06,65,32,301,30,66,71,017,,72,29,093,25,81,18,250,22,1"), Ok(NmeaSentence::Gsv));
+        assert_eq!(parser.data.satellites[3].prn, 81);
+        assert_eq!(parser.data.satellites[1].snr, None);
+    }
+
+    #[test]
+    fn gsv_with_a_truncated_satellite_is_rejected() {
+        let mut parser = NmeaParser::new();
+        assert_eq!(parse(&mut parser, "GPGSV,1,1,02,07,79,048,42,02,51"), Err(NmeaError::InvalidField));
+    }
+
+    #[test]
+    fn gsa_keeps_the_previous_dop_when_empty() {
+        let mut parser = NmeaParser::new();
+        assert_eq!(parse(&mut parser, "GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1"), Ok(NmeaSentence::Gsa));
+        assert_eq!(parser.data.hdop, 1.3);
+        assert_eq!(parse(&mut parser, "GPGSA,A,3,04,05,,09,12,,,24,,,,,,,"), Ok(NmeaSentence::Gsa));
+        assert_eq!(parser.data.pdop, 2.5);
+        assert_eq!(parser.data.hdop, 1.3);
+        assert_eq!(parser.data.vdop, 2.1);
+    }
+
+    struct MockPort {
+        rx: Vec<Result<u8, SerialError>>
+    }
+
+    impl SerialPort for MockPort {
+        fn write_byte(&mut self, _byte: u8) {}
+
+        fn read_byte_timeout(&mut self, _timeout_us: u32) -> Result<u8, SerialError> {
+            if self.rx.is_empty() {
+                return Err(SerialError::TimeOut)
+            }
+            self.rx.remove(0)
+        }
+    }
+
+    #[test]
+    fn poll_serial_drops_the_sentence_cut_by_an_overrun() {
+        let mut parser = NmeaParser::new();
+        let line = b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+        let mut rx: Vec<_> = line[..20].iter().map(|&b| Ok(b)).collect();
+        rx.push(Err(SerialError::OverRun));
+        rx.extend(line[30..].iter().map(|&b| Ok(b)));
+        assert_eq!(parser.poll_serial(&mut MockPort { rx }), Some(Err(NmeaError::DataLost)));
+
+        let rx = line.iter().map(|&b| Ok(b)).collect();
+        assert_eq!(parser.poll_serial(&mut MockPort { rx }), Some(Ok(NmeaSentence::Gga)));
+    }
+}
@@ -0,0 +1,12 @@
+pub trait SerialPort {
+    fn write_byte(&mut self, byte: u8);
+    fn read_byte_timeout(&mut self, timeout_us: u32) -> Result<u8, SerialError>;
+}
+
+#[derive(PartialEq, Debug)]
+pub enum SerialError {
+    NoDataFound,
+    OverRun,
+    AutoBaudRateError,
+    TimeOut
+}
@@ -0,0 +1,145 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Single producer single consumer byte queue, the receive interrupt pushes and the main loop pops.
+// One slot stays free to tell a full buffer from an empty one, N - 1 bytes fit.
+pub struct RingBuffer<const N: usize> {
+    buffer: UnsafeCell<[u8; N]>,
+    // Next slot written, only moved by the producer
+    head: AtomicUsize,
+    // Next slot read, only moved by the consumer
+    tail: AtomicUsize,
+    overflowed: AtomicBool
+}
+
+// head and tail are each owned by one side, a slot is only read once the producer published it
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> RingBuffer<N> {
+        RingBuffer {
+            buffer: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overflowed: AtomicBool::new(false)
+        }
+    }
+
+    // Producer side, a byte that does not fit is dropped and the overflow is reported to the consumer
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % N;
+        if next == self.tail.load(Ordering::Acquire) {
+            self.overflowed.store(true, Ordering::Relaxed);
+            return false
+        }
+        unsafe {
+            (*self.buffer.get())[head] = byte;
+        }
+        self.head.store(next, Ordering::Release);
+        true
+    }
+
+    // Consumer side
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None
+        }
+        let byte = unsafe {
+            (*self.buffer.get())[tail]
+        };
+        self.tail.store((tail + 1) % N, Ordering::Release);
+        Some(byte)
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (head + N - tail) % N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // True once after bytes were lost, by the buffer or by the receiver itself
+    pub fn take_overflow(&self) -> bool {
+        self.overflowed.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn set_overflow(&self) {
+        self.overflowed.store(true, Ordering::Relaxed);
+    }
+
+    // Consumer side, drops everything received so far
+    pub fn clear(&self) {
+        self.tail.store(self.head.load(Ordering::Acquire), Ordering::Release);
+        self.overflowed.store(false, Ordering::Relaxed);
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> RingBuffer<N> {
+        RingBuffer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_come_out_in_order() {
+        let ring: RingBuffer<8> = RingBuffer::new();
+        assert!(ring.is_empty());
+        for byte in b"$GP" {
+            assert!(ring.push(*byte));
+        }
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.pop(), Some(b'$'));
+        assert_eq!(ring.pop(), Some(b'G'));
+        assert_eq!(ring.pop(), Some(b'P'));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn indices_wrap_around() {
+        let ring: RingBuffer<4> = RingBuffer::new();
+        for round in 0..10u8 {
+            assert!(ring.push(round));
+            assert!(ring.push(round + 100));
+            assert_eq!(ring.pop(), Some(round));
+            assert_eq!(ring.pop(), Some(round + 100));
+        }
+        assert!(ring.is_empty());
+        assert!(!ring.take_overflow());
+    }
+
+    #[test]
+    fn full_buffer_drops_the_byte_and_reports_it_once() {
+        let ring: RingBuffer<4> = RingBuffer::new();
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert!(ring.push(3));
+        assert!(!ring.push(4));
+        assert_eq!(ring.len(), 3);
+        assert!(ring.take_overflow());
+        assert!(!ring.take_overflow());
+        assert_eq!(ring.pop(), Some(1));
+        assert!(ring.push(5));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), Some(3));
+        assert_eq!(ring.pop(), Some(5));
+    }
+
+    #[test]
+    fn clear_drops_pending_bytes() {
+        let ring: RingBuffer<4> = RingBuffer::new();
+        ring.push(1);
+        ring.set_overflow();
+        ring.clear();
+        assert!(ring.is_empty());
+        assert!(!ring.take_overflow());
+    }
+}
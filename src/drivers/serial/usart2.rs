@@ -0,0 +1,131 @@
+#![allow(dead_code)]
+
+use cortex_m::peripheral::NVIC;
+use stm32g4::stm32g431;
+use stm32g4::stm32g431::interrupt;
+use crate::core::delay::delay_us;
+use crate::drivers::gpio::{Gpio, GpioConfig, GPIOPORT, MODER, OSPEEDR, OTYPER, PUPDR};
+use crate::drivers::serial::{SerialError, SerialPort};
+use crate::system::{APB1_FREQ, HSI16_FREQ, LSE_FREQ, SYSCLK_FREQ};
+use stm32g431xx::drivers::serial::ring_buffer::RingBuffer;
+
+// About 50 ms of NMEA at 38400 bauds, the receiver can be polled that late without losing a sentence
+const USART2_RX_BUFFER_SIZE: usize = 256;
+
+const TYPE_SERIAL_USART2: GpioConfig = GpioConfig {
+    moder: MODER::AlternateFunction,
+    otyper: OTYPER::PushPull,
+    ospeedr: OSPEEDR::VeryHigh,
+    pupdr: PUPDR::None,
+    alf_func_sel: Some(7),
+};
+
+const USART2_TX: Gpio = Gpio {
+    port: GPIOPORT::GPIOA,
+    pin_number: 2
+};
+
+const USART2_RX: Gpio = Gpio {
+    port: GPIOPORT::GPIOA,
+    pin_number: 3
+};
+
+// Filled by the USART2 interrupt, read through the SerialPort implementation
+static USART2_RX_BUFFER: RingBuffer<USART2_RX_BUFFER_SIZE> = RingBuffer::new();
+
+// USART2 on PA2/PA3, every received byte is moved into a ring buffer by the RXNE interrupt
+pub struct Usart2;
+
+impl Usart2 {
+    pub fn configure(baudrate: u32) -> Usart2 {
+        unsafe {
+            let rcc = &*stm32g431::RCC::ptr();
+            // USART2EN bit 17 in RCC_APB1ENR1
+            rcc.apb1enr1.as_ptr().write(rcc.apb1enr1.as_ptr().read() | (1 << 17));
+        }
+        USART2_TX.configure(TYPE_SERIAL_USART2);
+        USART2_RX.configure(TYPE_SERIAL_USART2);
+
+        unsafe {
+            let port = &*stm32g431::USART2::ptr();
+            // USART_BRR can only be written with UE bit 0 in USART_CR1 cleared
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() & !(1 << 0));
+            port.brr.as_ptr().write(Self::kernel_clock_freq() / baudrate);
+            USART2_RX_BUFFER.clear();
+            // TE bit 3, RE bit 2, RXNEIE bit 5 and UE bit 0 in USART_CR1
+            port.cr1.as_ptr().write(port.cr1.as_ptr().read() | (1 << 3) | (1 << 2) | (1 << 5) | (1 << 0));
+            NVIC::unmask(stm32g431::Interrupt::USART2);
+        }
+        Usart2
+    }
+
+    pub fn available() -> usize {
+        USART2_RX_BUFFER.len()
+    }
+
+    pub fn flush_receiver() {
+        USART2_RX_BUFFER.clear();
+    }
+
+    fn kernel_clock_freq() -> u32 {
+        unsafe {
+            let rcc = &*stm32g431::RCC::ptr();
+            // Read USART2SEL bits 3:2 in RCC_CCIPR
+            match (rcc.ccipr.as_ptr().read() >> 2) & 0x3 {
+                0 => APB1_FREQ,
+                1 => SYSCLK_FREQ,
+                2 => HSI16_FREQ,
+                _ => LSE_FREQ
+            }
+        }
+    }
+}
+
+impl SerialPort for Usart2 {
+    fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            let port = &*stm32g431::USART2::ptr();
+            // Wait for TXE bit 7 in USART_ISR, then write USART_TDR
+            while (port.isr.as_ptr().read() & (1 << 7)) == 0 { }
+            port.tdr.as_ptr().write(byte as u32);
+        }
+    }
+
+    // Bytes lost by the receiver or the full buffer are reported once as an overrun
+    fn read_byte_timeout(&mut self, timeout_us: u32) -> Result<u8, SerialError> {
+        if USART2_RX_BUFFER.take_overflow() {
+            return Err(SerialError::OverRun)
+        }
+        let mut elapsed_us = 0u32;
+        loop {
+            if let Some(byte) = USART2_RX_BUFFER.pop() {
+                return Ok(byte)
+            }
+            if elapsed_us >= timeout_us {
+                return Err(SerialError::TimeOut)
+            }
+            delay_us(1);
+            elapsed_us += 1;
+        }
+    }
+}
+
+#[interrupt]
+fn USART2() {
+    unsafe {
+        let port = &*stm32g431::USART2::ptr();
+        let isr = port.isr.as_ptr().read();
+
+        // RXNE bit 5 in USART_ISR, reading USART_RDR clears it
+        if (isr & (1 << 5)) > 0 {
+            USART2_RX_BUFFER.push(port.rdr.as_ptr().read() as u8);
+        }
+
+        // ORE bit 3 in USART_ISR, a byte arrived before the previous one was read
+        if (isr & (1 << 3)) > 0 {
+            USART2_RX_BUFFER.set_overflow();
+            // Write ORECF bit 3 in USART_ICR
+            port.icr.as_ptr().write(1 << 3);
+        }
+    }
+}
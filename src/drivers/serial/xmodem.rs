@@ -0,0 +1,676 @@
+#![allow(dead_code)]
+
+use core::str;
+use crate::drivers::serial::{SerialError, SerialPort};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC_REQUEST: u8 = b'C';
+const PADDING: u8 = 0x1A;
+
+const BLOCK_SIZE: usize = 128;
+const BLOCK_SIZE_1K: usize = 1024;
+const MAX_RETRIES: u32 = 10;
+const CHARACTER_TIMEOUT_US: u32 = 1000000;
+const START_TIMEOUT_US: u32 = 3000000;
+const PURGE_TIMEOUT_US: u32 = 100000;
+
+#[derive(PartialEq, Debug)]
+pub enum TransferError {
+    TimeOut,
+    Cancelled,
+    TooManyRetries,
+    BadPacket,
+    UnexpectedBlock,
+    InvalidHeader,
+    SinkError
+}
+
+pub trait TransferSink {
+    // YMODEM provides the file name and size, XMODEM an empty name and no size
+    fn begin_file(&mut self, name: &str, size: Option<u32>) -> Result<(), TransferError>;
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), TransferError>;
+    fn end_file(&mut self) -> Result<(), TransferError>;
+}
+
+pub trait TransferSource {
+    fn name(&self) -> &str;
+    fn size(&self) -> u32;
+    fn read(&mut self, offset: u32, buffer: &mut [u8]) -> usize;
+}
+
+pub struct RamBuffer<'a> {
+    pub name: &'a str,
+    pub buffer: &'a mut [u8],
+    pub length: usize
+}
+
+pub struct Xmodem<P: SerialPort> {
+    pub port: P
+}
+
+enum Packet {
+    Data {
+        number: u8,
+        length: usize
+    },
+    EndOfTransmission,
+    Cancelled
+}
+
+impl<P: SerialPort> Xmodem<P> {
+    pub fn new(port: P) -> Xmodem<P> {
+        Xmodem {
+            port
+        }
+    }
+
+    /* Receive */
+
+    pub fn receive(&mut self, sink: &mut dyn TransferSink) -> Result<u32, TransferError> {
+        sink.begin_file("", None)?;
+        let received = self.receive_data(sink, None, false)?;
+        sink.end_file()?;
+        Ok(received)
+    }
+
+    pub fn receive_batch(&mut self, sink: &mut dyn TransferSink) -> Result<u32, TransferError> {
+        let mut files = 0;
+        let mut buffer = [0u8; BLOCK_SIZE_1K];
+
+        loop {
+            let length = self.receive_header(&mut buffer)?;
+            let header = &buffer[..length];
+
+            let name_length = header.iter().position(|&b| b == 0).unwrap_or(length);
+
+            // An empty file name ends the batch
+            if name_length == 0 {
+                self.port.write_byte(ACK);
+                return Ok(files)
+            }
+
+            let name = match str::from_utf8(&header[..name_length]) {
+                Ok(name) => name,
+                Err(_) => {
+                    self.cancel();
+                    return Err(TransferError::InvalidHeader)
+                }
+            };
+            let size = parse_decimal(&header[(name_length + 1).min(length)..]);
+
+            if let Err(e) = sink.begin_file(name, size) {
+                self.cancel();
+                return Err(e)
+            }
+
+            self.port.write_byte(ACK);
+
+            self.receive_data(sink, size, true)?;
+            sink.end_file()?;
+            files += 1;
+        }
+    }
+
+    fn receive_header(&mut self, buffer: &mut [u8; BLOCK_SIZE_1K]) -> Result<usize, TransferError> {
+        for _ in 0..MAX_RETRIES {
+            self.port.write_byte(CRC_REQUEST);
+
+            match self.read_packet(buffer, START_TIMEOUT_US, true) {
+                Ok(Packet::Data { number: 0, length }) => return Ok(length),
+                Ok(Packet::Cancelled) => return Err(TransferError::Cancelled),
+                Ok(_) => {
+                    self.cancel();
+                    return Err(TransferError::UnexpectedBlock)
+                }
+                Err(_) => self.purge()
+            }
+        }
+        self.cancel();
+        Err(TransferError::TooManyRetries)
+    }
+
+    fn receive_data(&mut self, sink: &mut dyn TransferSink, size: Option<u32>, ymodem: bool) -> Result<u32, TransferError> {
+        let mut buffer = [0u8; BLOCK_SIZE_1K];
+        let mut expected_block: u8 = 1;
+        let mut offset: u32 = 0;
+        let mut retries = 0;
+        let mut started = false;
+        let mut end_of_transmission_received = false;
+
+        self.port.write_byte(CRC_REQUEST);
+
+        loop {
+            let timeout_us = if started { CHARACTER_TIMEOUT_US } else { START_TIMEOUT_US };
+
+            match self.read_packet(&mut buffer, timeout_us, true) {
+                Ok(Packet::Data { number, length }) => {
+                    retries = 0;
+                    if number == expected_block {
+                        started = true;
+
+                        // The last block is padded, YMODEM gives the exact file size
+                        let length = match size {
+                            Some(size) => (length as u32).min(size.saturating_sub(offset)),
+                            None => length as u32
+                        };
+
+                        if let Err(e) = sink.write(offset, &buffer[..length as usize]) {
+                            self.cancel();
+                            return Err(e)
+                        }
+
+                        offset += length;
+                        expected_block = expected_block.wrapping_add(1);
+                        self.port.write_byte(ACK);
+                    } else if number == expected_block.wrapping_sub(1) {
+                        // The ACK was lost, the sender repeats the previous block
+                        self.port.write_byte(ACK);
+                    } else {
+                        self.cancel();
+                        return Err(TransferError::UnexpectedBlock)
+                    }
+                }
+                Ok(Packet::EndOfTransmission) => {
+                    // YMODEM senders expect the first EOT to be answered with a NAK
+                    if ymodem && !end_of_transmission_received {
+                        end_of_transmission_received = true;
+                        self.port.write_byte(NAK);
+                    } else {
+                        self.port.write_byte(ACK);
+                        return Ok(offset)
+                    }
+                }
+                Ok(Packet::Cancelled) => return Err(TransferError::Cancelled),
+                Err(_) => {
+                    retries += 1;
+                    if retries > MAX_RETRIES {
+                        self.cancel();
+                        return Err(TransferError::TooManyRetries)
+                    }
+                    self.purge();
+                    self.port.write_byte(if started { NAK } else { CRC_REQUEST });
+                }
+            }
+        }
+    }
+
+    fn read_packet(&mut self, buffer: &mut [u8; BLOCK_SIZE_1K], timeout_us: u32, crc_mode: bool) -> Result<Packet, TransferError> {
+        let length = match self.read(timeout_us)? {
+            SOH => BLOCK_SIZE,
+            STX => BLOCK_SIZE_1K,
+            EOT => return Ok(Packet::EndOfTransmission),
+            CAN => {
+                // Two consecutive CAN cancel the transfer
+                return match self.read(CHARACTER_TIMEOUT_US) {
+                    Ok(CAN) => Ok(Packet::Cancelled),
+                    _ => Err(TransferError::BadPacket)
+                }
+            }
+            _ => return Err(TransferError::BadPacket)
+        };
+
+        let number = self.read_in_packet()?;
+        let complement = self.read_in_packet()?;
+
+        for byte in buffer[..length].iter_mut() {
+            *byte = self.read_in_packet()?;
+        }
+
+        let valid = if crc_mode {
+            let crc = ((self.read_in_packet()? as u16) << 8) | (self.read_in_packet()? as u16);
+            crc == crc16(&buffer[..length])
+        } else {
+            self.read_in_packet()? == checksum(&buffer[..length])
+        };
+
+        if (number != !complement) || !valid {
+            return Err(TransferError::BadPacket)
+        }
+
+        Ok(Packet::Data { number, length })
+    }
+
+    /* Send */
+
+    pub fn send(&mut self, source: &mut dyn TransferSource, use_1k: bool) -> Result<u32, TransferError> {
+        let crc_mode = self.wait_for_receiver()?;
+        // 1K blocks are only allowed with CRC
+        let block_size = if use_1k && crc_mode { BLOCK_SIZE_1K } else { BLOCK_SIZE };
+        let sent = self.send_data(source, crc_mode, block_size)?;
+        self.send_end_of_transmission()?;
+        Ok(sent)
+    }
+
+    pub fn send_batch(&mut self, sources: &mut [&mut dyn TransferSource]) -> Result<u32, TransferError> {
+        let mut header = [0u8; BLOCK_SIZE];
+        let mut files = 0;
+
+        for source in sources.iter_mut() {
+            self.wait_for_crc_request()?;
+
+            // Header block: file name, NUL and the file size in decimal
+            header.fill(0);
+            let name = source.name().as_bytes();
+            let name_length = name.len().min(BLOCK_SIZE - 12);
+            header[..name_length].copy_from_slice(&name[..name_length]);
+            write_decimal(&mut header[(name_length + 1)..], source.size());
+
+            self.send_packet(0, &header, true)?;
+
+            // The receiver requests the data with a new 'C'
+            self.wait_for_crc_request()?;
+            self.send_data(*source, true, BLOCK_SIZE_1K)?;
+            self.send_end_of_transmission()?;
+            files += 1;
+        }
+
+        // An empty header block ends the batch
+        self.wait_for_crc_request()?;
+        header.fill(0);
+        self.send_packet(0, &header, true)?;
+
+        Ok(files)
+    }
+
+    fn wait_for_receiver(&mut self) -> Result<bool, TransferError> {
+        for _ in 0..MAX_RETRIES {
+            match self.read(START_TIMEOUT_US) {
+                Ok(CRC_REQUEST) => return Ok(true),
+                Ok(NAK) => return Ok(false),
+                Ok(CAN) => return Err(TransferError::Cancelled),
+                _ => {}
+            }
+        }
+        Err(TransferError::TimeOut)
+    }
+
+    fn wait_for_crc_request(&mut self) -> Result<(), TransferError> {
+        match self.wait_for_receiver()? {
+            true => Ok(()),
+            false => {
+                self.cancel();
+                Err(TransferError::UnexpectedBlock)
+            }
+        }
+    }
+
+    fn send_data(&mut self, source: &mut dyn TransferSource, crc_mode: bool, block_size: usize) -> Result<u32, TransferError> {
+        let mut buffer = [0u8; BLOCK_SIZE_1K];
+        let mut block: u8 = 1;
+        let mut offset: u32 = 0;
+        let size = source.size();
+
+        while offset < size {
+            let length = source.read(offset, &mut buffer[..block_size]);
+            if length == 0 {
+                break
+            }
+
+            // Short tails go in a 128 bytes block to save padding
+            let packet_size = if length <= BLOCK_SIZE { BLOCK_SIZE } else { block_size };
+            buffer[length..packet_size].fill(PADDING);
+
+            self.send_packet(block, &buffer[..packet_size], crc_mode)?;
+
+            offset += length as u32;
+            block = block.wrapping_add(1);
+        }
+
+        Ok(offset)
+    }
+
+    fn send_packet(&mut self, number: u8, data: &[u8], crc_mode: bool) -> Result<(), TransferError> {
+        for _ in 0..MAX_RETRIES {
+            self.port.write_byte(if data.len() == BLOCK_SIZE_1K { STX } else { SOH });
+            self.port.write_byte(number);
+            self.port.write_byte(!number);
+            for &byte in data {
+                self.port.write_byte(byte);
+            }
+            if crc_mode {
+                let crc = crc16(data);
+                self.port.write_byte((crc >> 8) as u8);
+                self.port.write_byte(crc as u8);
+            } else {
+                self.port.write_byte(checksum(data));
+            }
+
+            match self.read(CHARACTER_TIMEOUT_US * 10) {
+                Ok(ACK) => return Ok(()),
+                Ok(CAN) if self.read(CHARACTER_TIMEOUT_US) == Ok(CAN) => return Err(TransferError::Cancelled),
+                _ => {}
+            }
+        }
+        self.cancel();
+        Err(TransferError::TooManyRetries)
+    }
+
+    fn send_end_of_transmission(&mut self) -> Result<(), TransferError> {
+        // YMODEM receivers answer the first EOT with a NAK
+        for _ in 0..MAX_RETRIES {
+            self.port.write_byte(EOT);
+            if self.read(CHARACTER_TIMEOUT_US * 10) == Ok(ACK) {
+                return Ok(())
+            }
+        }
+        self.cancel();
+        Err(TransferError::TooManyRetries)
+    }
+
+    /* Common */
+
+    pub fn cancel(&mut self) {
+        for _ in 0..3 {
+            self.port.write_byte(CAN);
+        }
+    }
+
+    fn purge(&mut self) {
+        while self.port.read_byte_timeout(PURGE_TIMEOUT_US).is_ok() { }
+    }
+
+    fn read(&mut self, timeout_us: u32) -> Result<u8, TransferError> {
+        self.port.read_byte_timeout(timeout_us).map_err(|e| match e {
+            SerialError::TimeOut => TransferError::TimeOut,
+            _ => TransferError::BadPacket
+        })
+    }
+
+    fn read_in_packet(&mut self) -> Result<u8, TransferError> {
+        // A timeout in the middle of a packet is a bad packet
+        self.read(CHARACTER_TIMEOUT_US).map_err(|_| TransferError::BadPacket)
+    }
+}
+
+impl<'a> TransferSink for RamBuffer<'a> {
+    fn begin_file(&mut self, _name: &str, size: Option<u32>) -> Result<(), TransferError> {
+        if let Some(size) = size {
+            if size as usize > self.buffer.len() {
+                return Err(TransferError::SinkError)
+            }
+        }
+        self.length = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), TransferError> {
+        let start = offset as usize;
+        let end = start + data.len();
+        if end > self.buffer.len() {
+            return Err(TransferError::SinkError)
+        }
+        self.buffer[start..end].copy_from_slice(data);
+        self.length = self.length.max(end);
+        Ok(())
+    }
+
+    fn end_file(&mut self) -> Result<(), TransferError> {
+        Ok(())
+    }
+}
+
+impl<'a> TransferSource for RamBuffer<'a> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn size(&self) -> u32 {
+        self.length as u32
+    }
+
+    fn read(&mut self, offset: u32, buffer: &mut [u8]) -> usize {
+        let start = (offset as usize).min(self.length);
+        let length = buffer.len().min(self.length - start);
+        buffer[..length].copy_from_slice(&self.buffer[start..(start + length)]);
+        length
+    }
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+    // CRC-16/XMODEM, polynomial 0x1021, initial value 0
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if (crc & 0x8000) > 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn parse_decimal(data: &[u8]) -> Option<u32> {
+    let mut value: u32 = 0;
+    let mut digits = 0;
+    for &byte in data {
+        if !byte.is_ascii_digit() {
+            break
+        }
+        value = value.checked_mul(10)?.checked_add((byte - b'0') as u32)?;
+        digits += 1;
+    }
+    if digits > 0 { Some(value) } else { None }
+}
+
+fn write_decimal(buffer: &mut [u8], value: u32) {
+    let mut digits = [0u8; 10];
+    let mut count = 0;
+    let mut value = value;
+    loop {
+        digits[count] = b'0' + (value % 10) as u8;
+        count += 1;
+        value /= 10;
+        if value == 0 {
+            break
+        }
+    }
+    for (i, digit) in digits[..count].iter().rev().enumerate() {
+        buffer[i] = *digit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::vec::Vec;
+
+    // Scripted remote end, None is a read that times out
+    struct MockPort {
+        received: VecDeque<Option<u8>>,
+        sent: Vec<u8>
+    }
+
+    impl MockPort {
+        fn new() -> MockPort {
+            MockPort {
+                received: VecDeque::new(),
+                sent: Vec::new()
+            }
+        }
+
+        fn push(&mut self, bytes: &[u8]) {
+            self.received.extend(bytes.iter().map(|&b| Some(b)));
+        }
+
+        fn push_timeout(&mut self) {
+            self.received.push_back(None);
+        }
+
+        fn push_packet(&mut self, number: u8, data: &[u8; BLOCK_SIZE], corrupted: bool) {
+            let crc = crc16(data) ^ if corrupted { 1 } else { 0 };
+            self.push(&[SOH, number, !number]);
+            self.push(data);
+            self.push(&[(crc >> 8) as u8, crc as u8]);
+        }
+    }
+
+    impl SerialPort for MockPort {
+        fn write_byte(&mut self, byte: u8) {
+            self.sent.push(byte);
+        }
+
+        fn read_byte_timeout(&mut self, _timeout_us: u32) -> Result<u8, SerialError> {
+            match self.received.pop_front() {
+                Some(Some(byte)) => Ok(byte),
+                _ => Err(SerialError::TimeOut)
+            }
+        }
+    }
+
+    fn block(value: u8) -> [u8; BLOCK_SIZE] {
+        let mut data = [0u8; BLOCK_SIZE];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = value.wrapping_add(i as u8);
+        }
+        data
+    }
+
+    #[test]
+    fn crc16_matches_xmodem_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn receive_crc_blocks() {
+        let mut port = MockPort::new();
+        port.push_packet(1, &block(0), false);
+        port.push_packet(2, &block(0x80), false);
+        port.push(&[EOT]);
+
+        let mut memory = [0u8; 512];
+        let mut sink = RamBuffer { name: "", buffer: &mut memory, length: 0 };
+        let mut xmodem = Xmodem::new(port);
+
+        assert_eq!(xmodem.receive(&mut sink), Ok(256));
+        assert_eq!(sink.length, 256);
+        assert_eq!(&sink.buffer[..128], &block(0));
+        assert_eq!(&sink.buffer[128..256], &block(0x80));
+        assert_eq!(xmodem.port.sent, [CRC_REQUEST, ACK, ACK, ACK]);
+    }
+
+    #[test]
+    fn receive_naks_a_bad_block_and_takes_the_retry() {
+        let mut port = MockPort::new();
+        port.push_packet(1, &block(0), false);
+        port.push_packet(2, &block(0x80), true);
+        port.push_timeout();
+        port.push_packet(2, &block(0x80), false);
+        port.push(&[EOT]);
+
+        let mut memory = [0u8; 512];
+        let mut sink = RamBuffer { name: "", buffer: &mut memory, length: 0 };
+        let mut xmodem = Xmodem::new(port);
+
+        assert_eq!(xmodem.receive(&mut sink), Ok(256));
+        assert_eq!(&sink.buffer[128..256], &block(0x80));
+        assert_eq!(xmodem.port.sent, [CRC_REQUEST, ACK, NAK, ACK, ACK]);
+    }
+
+    #[test]
+    fn receive_acks_a_repeated_block_once() {
+        let mut port = MockPort::new();
+        port.push_packet(1, &block(0), false);
+        // The ACK was lost, the sender repeats block 1
+        port.push_packet(1, &block(0), false);
+        port.push(&[EOT]);
+
+        let mut memory = [0u8; 512];
+        let mut sink = RamBuffer { name: "", buffer: &mut memory, length: 0 };
+        let mut xmodem = Xmodem::new(port);
+
+        assert_eq!(xmodem.receive(&mut sink), Ok(128));
+        assert_eq!(xmodem.port.sent, [CRC_REQUEST, ACK, ACK, ACK]);
+    }
+
+    #[test]
+    fn receive_gives_up_after_too_many_retries() {
+        let mut port = MockPort::new();
+        port.push_packet(1, &block(0), false);
+        for _ in 0..=MAX_RETRIES {
+            port.push_packet(2, &block(0x80), true);
+            port.push_timeout();
+        }
+
+        let mut memory = [0u8; 512];
+        let mut sink = RamBuffer { name: "", buffer: &mut memory, length: 0 };
+        let mut xmodem = Xmodem::new(port);
+
+        assert_eq!(xmodem.receive(&mut sink), Err(TransferError::TooManyRetries));
+        assert!(xmodem.port.sent.ends_with(&[CAN, CAN, CAN]));
+    }
+
+    #[test]
+    fn receive_stops_on_cancel() {
+        let mut port = MockPort::new();
+        port.push(&[CAN, CAN]);
+
+        let mut memory = [0u8; 128];
+        let mut sink = RamBuffer { name: "", buffer: &mut memory, length: 0 };
+        let mut xmodem = Xmodem::new(port);
+
+        assert_eq!(xmodem.receive(&mut sink), Err(TransferError::Cancelled));
+    }
+
+    #[test]
+    fn send_resends_a_block_after_nak_and_ends_with_eot() {
+        let mut port = MockPort::new();
+        // CRC request, NAK of the first attempt, ACK of the retry, then ACK of the EOT
+        port.push(&[CRC_REQUEST, NAK, ACK, ACK]);
+
+        let mut memory = block(0);
+        let mut source = RamBuffer { name: "", buffer: &mut memory, length: 100 };
+        let mut xmodem = Xmodem::new(port);
+
+        assert_eq!(xmodem.send(&mut source, false), Ok(100));
+
+        let mut expected = block(0);
+        expected[100..].fill(PADDING);
+        let crc = crc16(&expected);
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&[SOH, 1, 0xFE]);
+        packet.extend_from_slice(&expected);
+        packet.extend_from_slice(&[(crc >> 8) as u8, crc as u8]);
+
+        let sent = &xmodem.port.sent;
+        assert_eq!(sent.len(), 2 * packet.len() + 1);
+        assert_eq!(&sent[..packet.len()], &packet[..]);
+        assert_eq!(&sent[packet.len()..2 * packet.len()], &packet[..]);
+        assert_eq!(sent[2 * packet.len()], EOT);
+    }
+
+    #[test]
+    fn send_repeats_eot_until_acknowledged() {
+        let mut port = MockPort::new();
+        // YMODEM receivers answer the first EOT with a NAK
+        port.push(&[NAK, ACK]);
+        let mut xmodem = Xmodem::new(port);
+
+        assert_eq!(xmodem.send_end_of_transmission(), Ok(()));
+        assert_eq!(xmodem.port.sent, [EOT, EOT]);
+    }
+
+    #[test]
+    fn send_cancels_when_eot_is_never_acknowledged() {
+        let port = MockPort::new();
+        let mut xmodem = Xmodem::new(port);
+
+        assert_eq!(xmodem.send_end_of_transmission(), Err(TransferError::TooManyRetries));
+        let sent = &xmodem.port.sent;
+        assert_eq!(sent.len(), MAX_RETRIES as usize + 3);
+        assert!(sent[..MAX_RETRIES as usize].iter().all(|&b| b == EOT));
+        assert!(sent.ends_with(&[CAN, CAN, CAN]));
+    }
+}
@@ -0,0 +1,27 @@
+#![cfg_attr(not(test), no_std)]
+
+// Driver logic that does not touch the peripherals, the firmware uses it and cargo test runs it on the host
+
+extern crate alloc;
+
+pub mod drivers {
+    pub mod bme280 {
+        pub mod compensation;
+    }
+
+    #[allow(non_snake_case)]
+    pub mod I2C {
+        pub mod ten_bit;
+    }
+
+    pub mod serial {
+        mod port;
+        pub mod at;
+        pub mod lin_frame;
+        pub mod nmea;
+        pub mod ring_buffer;
+        pub mod xmodem;
+
+        pub use self::port::{SerialError, SerialPort};
+    }
+}
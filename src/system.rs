@@ -6,17 +6,22 @@ use core::panic::PanicInfo;
 use core::sync::atomic;
 use core::sync::atomic::Ordering;
 use cortex_m::peripheral::NVIC;
-use stm32g4::stm32g431::Interrupt::{SPI3, USART1};
+use stm32g4::stm32g431::Interrupt::{EXTI0, EXTI1, EXTI15_10, EXTI2, EXTI3, EXTI4, EXTI9_5, I2C1_ER, I2C1_EV, I2C2_ER, I2C2_EV, I2C3_ER, I2C3_EV, SPI3, USART1};
 use crate::drivers::serial::Serial;
+use crate::core::delay;
 
 pub const SYSCLK_FREQ: u32 = 170000000;
 pub const APB1_FREQ: u32 = 170000000;
 pub const APB2_FREQ: u32 = 170000000;
+pub const HSI16_FREQ: u32 = 16000000;
+pub const LSE_FREQ: u32 = 32768;
 
 
 pub fn system_init(){
     //Configure system clock
     system_clock_config();
+    //Start the SysTick behind delay::millis
+    delay::enable_millis_tick();
     //Configure Interrupts
     enable_interrupts();
     //Configure Serial
@@ -74,7 +79,9 @@ fn enable_interrupts(){
 
 fn disable_interrupts_in_case_of_fault(){
     disable_usart1_interrupt();
-    disable_spi3_interrupt()
+    disable_spi3_interrupt();
+    disable_i2c_interrupts();
+    disable_exti_interrupts()
 }
 
 unsafe fn enable_hsi(){
@@ -153,4 +160,23 @@ fn disable_usart1_interrupt(){
 
 fn disable_spi3_interrupt(){
     NVIC::mask(SPI3);
+}
+
+fn disable_i2c_interrupts(){
+    NVIC::mask(I2C1_EV);
+    NVIC::mask(I2C1_ER);
+    NVIC::mask(I2C2_EV);
+    NVIC::mask(I2C2_ER);
+    NVIC::mask(I2C3_EV);
+    NVIC::mask(I2C3_ER);
+}
+
+fn disable_exti_interrupts(){
+    NVIC::mask(EXTI0);
+    NVIC::mask(EXTI1);
+    NVIC::mask(EXTI2);
+    NVIC::mask(EXTI3);
+    NVIC::mask(EXTI4);
+    NVIC::mask(EXTI9_5);
+    NVIC::mask(EXTI15_10);
 }
\ No newline at end of file